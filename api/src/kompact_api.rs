@@ -2,72 +2,111 @@ extern crate bytes;
 extern crate kompact;
 
 pub use crate::messages::messages::MetricReport;
-use crate::messages::messages::Subscribe;
+pub use crate::messages::messages::ProcessEvent;
+pub use crate::messages::messages::PushMetrics;
+use crate::messages::messages::{Credit, Subscribe, Unsubscribe};
+pub use crate::messages::messages::{
+    MemoryReleased, RegisterReleaser, ReleaseMemory,
+};
 
 use kompact::prelude::BufMut;
 use kompact::*;
 use protobuf::Message;
+use std::sync::Arc;
 
 pub struct ProtoSer;
 
-impl Deserialiser<Subscribe> for ProtoSer {
-    fn deserialise(buf: &mut Buf) -> Result<Subscribe, SerError> {
-        let parsed = protobuf::parse_from_bytes(buf.bytes())
-            .map_err(|err| SerError::InvalidData(err.to_string()))?;
-        Ok(parsed)
-    }
+/// Serialisation ids of the enya messages. All of them are protobuf
+/// encoded, the id tells the receiver which message to parse.
+pub mod serids {
+    pub const SUBSCRIBE: u64 = 100;
+    pub const METRIC_REPORT: u64 = 101;
+    pub const PROCESS_EVENT: u64 = 102;
+    pub const PUSH_METRICS: u64 = 103;
+    pub const REGISTER_RELEASER: u64 = 104;
+    pub const RELEASE_MEMORY: u64 = 105;
+    pub const MEMORY_RELEASED: u64 = 106;
+    pub const CREDIT: u64 = 107;
+    pub const UNSUBSCRIBE: u64 = 108;
 }
 
-impl Serialisable for Subscribe {
-    fn serid(&self) -> u64 {
-        serialisation_ids::PBUF
-    }
-    fn size_hint(&self) -> Option<usize> {
-        if let Ok(bytes) = self.write_to_bytes() {
-            Some(bytes.len())
-        } else {
-            None
-        }
-    }
-    fn serialise(&self, buf: &mut BufMut) -> Result<(), SerError> {
-        let bytes = self
+/// A message that is serialised once and shared by every copy, for
+/// sending the same message to many actors. It is always delivered
+/// serialised, also to local actors.
+#[derive(Clone)]
+pub struct Encoded {
+    serid: u64,
+    bytes: Arc<Vec<u8>>,
+}
+
+impl Encoded {
+    pub fn new<M: Message>(serid: u64, msg: &M) -> Result<Encoded, SerError> {
+        let bytes = msg
             .write_to_bytes()
             .map_err(|err| SerError::InvalidData(err.to_string()))?;
-        buf.put_slice(&bytes);
-        Ok(())
-    }
-    fn local(self: Box<Self>) -> Result<Box<Any + Send>, Box<Serialisable>> {
-        Ok(self)
+        Ok(Encoded {
+            serid,
+            bytes: Arc::new(bytes),
+        })
     }
 }
 
-impl Serialisable for MetricReport {
+impl Serialisable for Encoded {
     fn serid(&self) -> u64 {
-        serialisation_ids::PBUF
+        self.serid
     }
     fn size_hint(&self) -> Option<usize> {
-        if let Ok(bytes) = self.write_to_bytes() {
-            Some(bytes.len())
-        } else {
-            None
-        }
+        Some(self.bytes.len())
     }
     fn serialise(&self, buf: &mut BufMut) -> Result<(), SerError> {
-        let bytes = self
-            .write_to_bytes()
-            .map_err(|err| SerError::InvalidData(err.to_string()))?;
-        buf.put_slice(&bytes);
+        buf.put_slice(&self.bytes);
         Ok(())
     }
     fn local(self: Box<Self>) -> Result<Box<Any + Send>, Box<Serialisable>> {
-        Ok(self)
+        Err(self)
     }
 }
 
-impl Deserialiser<MetricReport> for ProtoSer {
-    fn deserialise(buf: &mut Buf) -> Result<MetricReport, SerError> {
-        let parsed = protobuf::parse_from_bytes(buf.bytes())
-            .map_err(|err| SerError::InvalidData(err.to_string()))?;
-        Ok(parsed)
-    }
+macro_rules! proto_message {
+    ($msg:ty, $serid:expr) => {
+        impl Serialisable for $msg {
+            fn serid(&self) -> u64 {
+                $serid
+            }
+            fn size_hint(&self) -> Option<usize> {
+                // computes the size without encoding the message
+                Some(self.compute_size() as usize)
+            }
+            fn serialise(&self, buf: &mut BufMut) -> Result<(), SerError> {
+                let bytes = self
+                    .write_to_bytes()
+                    .map_err(|err| SerError::InvalidData(err.to_string()))?;
+                buf.put_slice(&bytes);
+                Ok(())
+            }
+            fn local(
+                self: Box<Self>,
+            ) -> Result<Box<Any + Send>, Box<Serialisable>> {
+                Ok(self)
+            }
+        }
+
+        impl Deserialiser<$msg> for ProtoSer {
+            fn deserialise(buf: &mut Buf) -> Result<$msg, SerError> {
+                let parsed = protobuf::parse_from_bytes(buf.bytes())
+                    .map_err(|err| SerError::InvalidData(err.to_string()))?;
+                Ok(parsed)
+            }
+        }
+    };
 }
+
+proto_message!(Subscribe, serids::SUBSCRIBE);
+proto_message!(Credit, serids::CREDIT);
+proto_message!(Unsubscribe, serids::UNSUBSCRIBE);
+proto_message!(MetricReport, serids::METRIC_REPORT);
+proto_message!(ProcessEvent, serids::PROCESS_EVENT);
+proto_message!(PushMetrics, serids::PUSH_METRICS);
+proto_message!(RegisterReleaser, serids::REGISTER_RELEASER);
+proto_message!(ReleaseMemory, serids::RELEASE_MEMORY);
+proto_message!(MemoryReleased, serids::MEMORY_RELEASED);
@@ -1,9 +1,22 @@
 pub extern crate protobuf;
 
 pub mod messages;
+pub mod shm;
 
 #[cfg(feature = "kompact_api")]
 pub mod kompact_api;
 
+/// Environment variables enya sets for the Process, so that it can find
+/// the monitor of its System without any configuration
+pub mod env {
+    /// Actor path of the monitor, e.g. `tcp://127.0.0.1:2000/monitor`
+    pub const MONITOR_PATH: &str = "ENYA_MONITOR_PATH";
+    /// Socket address of the monitor, e.g. `127.0.0.1:2000`
+    pub const MONITOR_SOCKET: &str = "ENYA_MONITOR_SOCKET";
+    pub const CONTAINER_ID: &str = "ENYA_CONTAINER_ID";
+    /// Inherited fd of the metrics page, see `shm`
+    pub const METRICS_FD: &str = "ENYA_METRICS_FD";
+}
+
 pub use crate::messages::messages::*;
 pub use protobuf::*;
@@ -0,0 +1,431 @@
+//! Shared memory page with the latest metrics of the Process.
+//!
+//! The System publishes the metrics of every collection into a memfd,
+//! which the Process inherits read-only. Its number is found in the
+//! `ENYA_METRICS_FD` environment variable. Reading the page does not
+//! involve any syscalls.
+//!
+//! The page is a sequence of native endian words:
+//!
+//! | Offset | Type  | Field                                            |
+//! |--------|-------|--------------------------------------------------|
+//! | 0      | `u32` | Magic, `0x454e5941` (`ENYA`)                     |
+//! | 4      | `u32` | Layout version, currently `1`                    |
+//! | 8      | `u64` | Sequence number, odd while the System writes     |
+//! | 16     | `u64` | Time of the collection, ns since the Unix epoch  |
+//! | 24     | `u64` | Memory usage in bytes                            |
+//! | 32     | `u64` | Memory limit in bytes                            |
+//! | 40     | `u64` | Total cpu usage in ns                            |
+//! | 48     | `u64` | System cpu usage in ns                           |
+//! | 56     | `u64` | Bytes read from block devices                    |
+//! | 64     | `u64` | Bytes written to block devices                   |
+//! | 72     | `u64` | Bytes sent                                       |
+//! | 80     | `u64` | Packets sent                                     |
+//! | 88     | `u64` | Bytes received                                   |
+//! | 96     | `u64` | Packets received                                 |
+//! | 104    | `u64` | Health status, a `Health_Status` value           |
+//! | 112    | `u64` | Consecutive failed health checks                 |
+//!
+//! A reader loads the sequence number, the fields and the sequence number
+//! again, and retries if the two differ or the first one is odd. Fields
+//! are only appended, older readers keep working with newer versions.
+//!
+//! Once the System mapped the page, the memfd is sealed against further
+//! writes (`F_SEAL_FUTURE_WRITE`, Linux 5.1), so the Process can't forge
+//! metrics by reopening its fd for writing. On older kernels the System
+//! doesn't publish into the page, and `Reader::new` fails as the magic is
+//! never written.
+
+use crate::messages::messages::MetricReport;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const MAGIC: u32 = 0x454e_5941;
+pub const VERSION: u32 = 1;
+/// Size of the memfd
+pub const PAGE_SIZE: usize = 4096;
+
+const FIELDS: usize = 13;
+// not in every libc version yet
+const F_SEAL_FUTURE_WRITE: libc::c_int = 0x0010;
+
+#[repr(C)]
+struct Page {
+    magic: AtomicU32,
+    version: AtomicU32,
+    seq: AtomicU64,
+    values: [AtomicU64; FIELDS],
+}
+
+/// Metrics of the Process as of the last collection
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Metrics {
+    pub collected_at_ns: u64,
+    pub memory_usage: u64,
+    pub memory_limit: u64,
+    pub cpu_total: u64,
+    pub cpu_system: u64,
+    pub io_read: u64,
+    pub io_write: u64,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub health_status: u64,
+    pub health_failures: u64,
+}
+
+impl Metrics {
+    fn to_values(&self) -> [u64; FIELDS] {
+        [
+            self.collected_at_ns,
+            self.memory_usage,
+            self.memory_limit,
+            self.cpu_total,
+            self.cpu_system,
+            self.io_read,
+            self.io_write,
+            self.tx_bytes,
+            self.tx_packets,
+            self.rx_bytes,
+            self.rx_packets,
+            self.health_status,
+            self.health_failures,
+        ]
+    }
+
+    fn from_values(v: &[u64; FIELDS]) -> Metrics {
+        Metrics {
+            collected_at_ns: v[0],
+            memory_usage: v[1],
+            memory_limit: v[2],
+            cpu_total: v[3],
+            cpu_system: v[4],
+            io_read: v[5],
+            io_write: v[6],
+            tx_bytes: v[7],
+            tx_packets: v[8],
+            rx_bytes: v[9],
+            rx_packets: v[10],
+            health_status: v[11],
+            health_failures: v[12],
+        }
+    }
+}
+
+impl<'a> From<&'a MetricReport> for Metrics {
+    fn from(report: &MetricReport) -> Metrics {
+        let collected_at_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos()))
+            .unwrap_or(0);
+        let net = report.get_network();
+        Metrics {
+            collected_at_ns,
+            memory_usage: report.get_memory().get_usage(),
+            memory_limit: report.get_memory().get_limit(),
+            cpu_total: report.get_cpu().get_total(),
+            cpu_system: report.get_cpu().get_system(),
+            io_read: report.get_io().get_read(),
+            io_write: report.get_io().get_write(),
+            tx_bytes: net.get_tx_bytes(),
+            tx_packets: net.get_tx_packets(),
+            rx_bytes: net.get_rx_bytes(),
+            rx_packets: net.get_rx_packets(),
+            health_status: report.get_health().get_status() as u64,
+            health_failures: u64::from(report.get_health().get_failures()),
+        }
+    }
+}
+
+/// Creates the memfd of the page. Returns the fd of the System, which is
+/// closed on exec, and a read-only fd that is inherited by the Process.
+pub fn create() -> io::Result<(RawFd, RawFd)> {
+    let name = b"enya-metrics\0";
+    let fd = unsafe {
+        libc::memfd_create(
+            name.as_ptr() as *const libc::c_char,
+            libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let result = unsafe {
+        if libc::ftruncate(fd, PAGE_SIZE as libc::off_t) < 0 {
+            Err(io::Error::last_os_error())
+        } else if libc::fcntl(
+            fd,
+            libc::F_ADD_SEALS,
+            libc::F_SEAL_SHRINK | libc::F_SEAL_GROW,
+        ) < 0
+        {
+            Err(io::Error::last_os_error())
+        } else {
+            // a new, read-only open file description. Writes through a
+            // reopened one are prevented by the seal the Writer adds.
+            let path = format!("/proc/self/fd/{}\0", fd);
+            let rfd = libc::open(
+                path.as_ptr() as *const libc::c_char,
+                libc::O_RDONLY,
+            );
+            if rfd < 0 {
+                Err(io::Error::last_os_error())
+            } else {
+                Ok((fd, rfd))
+            }
+        }
+    };
+    if result.is_err() {
+        unsafe {
+            libc::close(fd);
+        }
+    }
+    result
+}
+
+fn map(fd: RawFd, prot: libc::c_int) -> io::Result<*mut Page> {
+    let addr = unsafe {
+        libc::mmap(ptr::null_mut(), PAGE_SIZE, prot, libc::MAP_SHARED, fd, 0)
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(addr as *mut Page)
+}
+
+/// Publishes metrics into the page, used by the System
+pub struct Writer {
+    page: *mut Page,
+}
+
+// the page is only written through &mut self
+unsafe impl Send for Writer {}
+
+impl Writer {
+    /// Maps the page and seals it, so that only this mapping can write.
+    /// Fails on kernels without `F_SEAL_FUTURE_WRITE`, the page is then
+    /// never marked as valid.
+    pub fn new(fd: RawFd) -> io::Result<Writer> {
+        let page = map(fd, libc::PROT_READ | libc::PROT_WRITE)?;
+        let sealed = unsafe {
+            libc::fcntl(
+                fd,
+                libc::F_ADD_SEALS,
+                F_SEAL_FUTURE_WRITE | libc::F_SEAL_SEAL,
+            )
+        };
+        if sealed < 0 {
+            let err = io::Error::last_os_error();
+            unsafe {
+                libc::munmap(page as *mut libc::c_void, PAGE_SIZE);
+            }
+            return Err(err);
+        }
+        let p = unsafe { &*page };
+        p.version.store(VERSION, Ordering::Relaxed);
+        p.magic.store(MAGIC, Ordering::Release);
+        Ok(Writer { page })
+    }
+
+    pub fn publish(&mut self, metrics: &Metrics) {
+        let p = unsafe { &*self.page };
+        let seq = p.seq.load(Ordering::Relaxed);
+        p.seq.store(seq.wrapping_add(1), Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (slot, value) in p.values.iter().zip(metrics.to_values().iter()) {
+            slot.store(*value, Ordering::Relaxed);
+        }
+        p.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.page as *mut libc::c_void, PAGE_SIZE);
+        }
+    }
+}
+
+/// Reads the metrics of the Process from the page
+pub struct Reader {
+    page: *const Page,
+}
+
+unsafe impl Send for Reader {}
+unsafe impl Sync for Reader {}
+
+impl Reader {
+    /// Maps the page passed by enya through the environment
+    pub fn from_env() -> io::Result<Reader> {
+        let fd = std::env::var(crate::env::METRICS_FD)
+            .ok()
+            .and_then(|fd| fd.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no metrics page in the environment",
+                )
+            })?;
+        Reader::new(fd)
+    }
+
+    pub fn new(fd: RawFd) -> io::Result<Reader> {
+        let page = map(fd, libc::PROT_READ)?;
+        let reader = Reader { page };
+        let p = unsafe { &*reader.page };
+        if p.magic.load(Ordering::Acquire) != MAGIC
+            || p.version.load(Ordering::Relaxed) < VERSION
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not an enya metrics page",
+            ));
+        }
+        Ok(reader)
+    }
+
+    /// Returns the latest metrics, or `None` if the System is writing them
+    /// right now
+    pub fn try_read(&self) -> Option<Metrics> {
+        let p = unsafe { &*self.page };
+        let before = p.seq.load(Ordering::Acquire);
+        if before & 1 == 1 {
+            return None;
+        }
+        let mut values = [0; FIELDS];
+        for (value, slot) in values.iter_mut().zip(p.values.iter()) {
+            *value = slot.load(Ordering::Relaxed);
+        }
+        fence(Ordering::Acquire);
+        if p.seq.load(Ordering::Relaxed) != before {
+            return None;
+        }
+        Some(Metrics::from_values(&values))
+    }
+
+    /// Returns the latest metrics, spinning while the System writes them
+    pub fn read(&self) -> Metrics {
+        loop {
+            if let Some(metrics) = self.try_read() {
+                return metrics;
+            }
+            std::sync::atomic::spin_loop_hint();
+        }
+    }
+
+    /// Number of times the System published metrics
+    pub fn collections(&self) -> u64 {
+        unsafe { &*self.page }.seq.load(Ordering::Acquire) / 2
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.page as *mut libc::c_void, PAGE_SIZE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> Metrics {
+        let v: Vec<u64> = (1..=FIELDS as u64).map(|i| i * 1000 + i).collect();
+        let mut values = [0; FIELDS];
+        values.copy_from_slice(&v);
+        Metrics::from_values(&values)
+    }
+
+    fn close(fds: &[RawFd]) {
+        for &fd in fds {
+            unsafe {
+                libc::close(fd);
+            }
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let (fd, rfd) = create().unwrap();
+        // not published yet
+        assert!(Reader::new(rfd).is_err());
+
+        let mut writer = Writer::new(fd).unwrap();
+        let reader = Reader::new(rfd).unwrap();
+        assert_eq!(reader.collections(), 0);
+        assert_eq!(reader.read(), Metrics::default());
+
+        writer.publish(&metrics());
+        assert_eq!(reader.read(), metrics());
+        let later = Metrics {
+            memory_usage: 42,
+            ..metrics()
+        };
+        writer.publish(&later);
+        assert_eq!(reader.try_read(), Some(later));
+        assert_eq!(reader.collections(), 2);
+        close(&[fd, rfd]);
+    }
+
+    #[test]
+    fn layout() {
+        let (fd, rfd) = create().unwrap();
+        let mut writer = Writer::new(fd).unwrap();
+        writer.publish(&metrics());
+
+        let mut page = [0u8; 120];
+        let n = unsafe {
+            libc::pread(rfd, page.as_mut_ptr() as *mut _, page.len(), 0)
+        };
+        assert_eq!(n, page.len() as isize);
+        let u32_at = |at: usize| {
+            let mut b = [0; 4];
+            b.copy_from_slice(&page[at..at + 4]);
+            u32::from_ne_bytes(b)
+        };
+        let u64_at = |at: usize| {
+            let mut b = [0; 8];
+            b.copy_from_slice(&page[at..at + 8]);
+            u64::from_ne_bytes(b)
+        };
+        assert_eq!(u32_at(0), MAGIC);
+        assert_eq!(u32_at(4), 1);
+        assert_eq!(u64_at(8), 2);
+        // the documented offsets, from the collection time to the health
+        // check failures
+        let expected = metrics().to_values();
+        for (i, offset) in (16..=112).step_by(8).enumerate() {
+            assert_eq!(u64_at(offset), expected[i], "offset {}", offset);
+        }
+        assert_eq!(u64_at(24), metrics().memory_usage);
+        close(&[fd, rfd]);
+    }
+
+    #[test]
+    fn sealed() {
+        let (fd, rfd) = create().unwrap();
+        let _writer = Writer::new(fd).unwrap();
+
+        // reopening the read-only fd for writing gets nowhere
+        let path = format!("/proc/self/fd/{}\0", rfd);
+        let wfd = unsafe {
+            libc::open(path.as_ptr() as *const libc::c_char, libc::O_RDWR)
+        };
+        assert!(wfd >= 0);
+        let n =
+            unsafe { libc::pwrite(wfd, b"forged".as_ptr() as *const _, 6, 0) };
+        assert_eq!(n, -1);
+        assert!(map(wfd, libc::PROT_READ | libc::PROT_WRITE).is_err());
+        // nor can the seal be lifted or the page resized
+        assert!(Writer::new(fd).is_err());
+        assert!(unsafe { libc::ftruncate(wfd, 0) } < 0);
+        close(&[fd, rfd, wfd]);
+    }
+}
@@ -12,8 +12,9 @@ use std::net::{IpAddr, Ipv4Addr};
 use std::str::FromStr;
 
 fn main() {
-    // Default Enya setup
-    let monitor_path_str: &str = "tcp://127.0.0.1:2000/monitor";
+    // Set by enya, falls back to the default Enya setup
+    let monitor_path_str = std::env::var(api::env::MONITOR_PATH)
+        .unwrap_or_else(|_| String::from("tcp://127.0.0.1:2000/monitor"));
 
     let system_addr = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
     let sub_addr_socket = SocketAddr::new(system_addr, 1500);
@@ -25,7 +26,7 @@ fn main() {
     });
 
     let system = KompicsSystem::new(cfg);
-    let enya_actor_path = ActorPath::from_str(monitor_path_str).unwrap();
+    let enya_actor_path = ActorPath::from_str(&monitor_path_str).unwrap();
 
     let (subscriber, _s) = system.create_and_register(move || {
         Subscriber::new(enya_actor_path)
@@ -55,10 +56,21 @@ impl Subscriber {
 impl Actor for Subscriber {
     fn receive_local(&mut self, _sender: ActorRef, msg: Box<Any>) {
     }
-    fn receive_message(&mut self, sender: ActorPath, _ser_id: u64, buf: &mut Buf) {
+    fn receive_message(&mut self, sender: ActorPath, ser_id: u64, buf: &mut Buf) {
+        if ser_id == serids::PROCESS_EVENT {
+            let result: Result<api::ProcessEvent, SerError> = ProtoSer::deserialise(buf);
+            if let Ok(event) = result {
+                info!(self.ctx.log(), "ProcessEvent: {:?}", event);
+                return;
+            }
+        }
         let result: Result<api::MetricReport, SerError> = ProtoSer::deserialise(buf);
         if let Ok(report) = result {
             info!(self.ctx.log(), "MetricReport from {:?}: {:?}", self.enya_monitor, report);
+            // done with the report, ready for the next one
+            let mut credit = api::Credit::new();
+            credit.set_credits(1);
+            self.enya_monitor.tell(credit, self);
         } else {
             error!(self.ctx.log(), "Got unexpected message from {}", sender);
         }
@@ -68,7 +80,10 @@ impl Actor for Subscriber {
 impl Provide<ControlPort> for Subscriber {
     fn handle(&mut self, event: ControlEvent) {
         if let ControlEvent::Start = event {
-            let msg = api::Subscribe::new();
+            let mut msg = api::Subscribe::new();
+            // at most a few reports in flight, a slow subscriber only
+            // misses reports in between
+            msg.set_credits(4);
             self.enya_monitor.tell(msg, self);
         }
     }
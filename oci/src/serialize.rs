@@ -76,6 +76,10 @@ pub fn deserialize<T: serde::Deserialize>(
     Ok(serde_json::from_reader(&file)?)
 }
 
+pub fn from_str<T: serde::Deserialize>(s: &str) -> Result<T, SerializeError> {
+    Ok(serde_json::from_str(s)?)
+}
+
 pub fn to_string<T: serde::Serialize>(
     obj: &T,
 ) -> Result<String, SerializeError> {
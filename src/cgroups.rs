@@ -1,12 +1,17 @@
 use errors::*;
 use lazy_static::initialize;
-use nix::unistd::Pid;
+use nix::errno::Errno;
+use nix::fcntl::{open, OFlag};
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, Pid};
 use num_traits::identities::Zero;
 use oci::LinuxDeviceType;
 use oci::{LinuxDeviceCgroup, LinuxResources, LinuxThrottleDevice};
 use std::collections::HashMap;
 use std::fs::{create_dir_all, remove_dir, File};
 use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::io::RawFd;
 use std::string::ToString;
 
 pub fn init() {
@@ -31,6 +36,38 @@ pub fn move_enya(
     Ok(())
 }
 
+/// Opens `cgroup_name` of every enya subsystem through a detached bind
+/// mount. The fds stay writable after the cgroup mount is remounted
+/// read-only, which lets the System move restarted processes.
+pub fn open_enya_detached(
+    cgroups_path: &str,
+    cgroup_name: &str,
+) -> Result<Vec<RawFd>> {
+    let mut fds = Vec::new();
+    for key in ENYA_SUBSYSTEMS.keys() {
+        fds.push(open_detached(cgroups_path, key, cgroup_name)?);
+    }
+    Ok(fds)
+}
+
+/// Opens `cgroup_name` of the `key` subsystem like `open_enya_detached`
+pub fn open_detached(
+    cgroups_path: &str,
+    key: &str,
+    cgroup_name: &str,
+) -> Result<RawFd> {
+    let dir = format!("{}/{}/{}", cgroups_path, key, cgroup_name);
+    debug! {"opening detached cgroup dir {}", &dir};
+    let flags = MsFlags::MS_BIND;
+    mount(Some(&*dir), &*dir, None::<&str>, flags, None::<&str>)
+        .chain_err(|| format!("failed to bind {}", &dir))?;
+    let oflags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    let fd = open(&*dir, oflags, Mode::empty());
+    umount2(&*dir, MntFlags::MNT_DETACH)
+        .chain_err(|| format!("failed to detach {}", &dir))?;
+    fd.chain_err(|| format!("failed to open {}", &dir))
+}
+
 pub fn enya_setup(cgroups_path: &str, cgroup_name: &str) -> Result<()> {
     for key in ENYA_SUBSYSTEMS.keys() {
         let dir = if let Some(s) = path(key, cgroups_path) {
@@ -209,6 +246,93 @@ pub fn get_procs(key: &str, cgroups_path: &str) -> Vec<Pid> {
     result
 }
 
+/// Pids of `cgroups_path` and all of its descendant cgroups
+pub fn get_all_procs(key: &str, cgroups_path: &str) -> Vec<Pid> {
+    let mut result = get_procs(key, cgroups_path);
+    let dir = match path(key, cgroups_path) {
+        Some(dir) => dir,
+        None => return result,
+    };
+    let entries = match ::std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("could not list {}: {}", dir, e);
+            return result;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        if entry.file_type().map(|t| t.is_dir()).unwrap_or(false) {
+            let child = format!(
+                "{}/{}",
+                cgroups_path,
+                entry.file_name().to_string_lossy()
+            );
+            result.extend(get_all_procs(key, &child));
+        }
+    }
+    result
+}
+
+const FREEZE_RETRIES: u32 = 100;
+
+/// Freezes all tasks of the container, so that they can be signalled
+/// without racing against new forks.
+pub fn freeze(cgroups_path: &str) -> Result<()> {
+    let dir = match path("freezer", cgroups_path) {
+        Some(d) => d,
+        None => bail!("freezer cgroup is not available"),
+    };
+    write_file(&dir, "freezer.state", "FROZEN")?;
+    for _ in 0..FREEZE_RETRIES {
+        if read_file(&dir, "freezer.state")?.trim() == "FROZEN" {
+            return Ok(());
+        }
+        ::std::thread::sleep(::std::time::Duration::from_millis(10));
+    }
+    // don't leave the container half frozen
+    write_file(&dir, "freezer.state", "THAWED")?;
+    bail!("timed out freezing {}", dir);
+}
+
+pub fn thaw(cgroups_path: &str) -> Result<()> {
+    if let Some(dir) = path("freezer", cgroups_path) {
+        write_file(&dir, "freezer.state", "THAWED")?;
+    }
+    Ok(())
+}
+
+// Number of tasks the kernel OOM killer has killed in the memory cgroup.
+// Older kernels do not report oom_kill, so None is returned.
+pub fn oom_kills(cgroups_path: &str) -> Option<u64> {
+    let dir = path("memory", cgroups_path)?;
+    let data = read_file(&dir, "memory.oom_control").ok()?;
+    for line in data.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() == 2 && fields[0] == "oom_kill" {
+            return fields[1].parse::<u64>().ok();
+        }
+    }
+    None
+}
+
+/// Disables the OOM killer for `cgroup_name` and returns an eventfd that is
+/// notified whenever the cgroup is out of memory instead
+pub fn oom_eventfd(cgroups_path: &str, cgroup_name: &str) -> Result<RawFd> {
+    let dir = format!("{}/memory/{}", cgroups_path, cgroup_name);
+    write_file(&dir, "memory.oom_control", "1")?;
+    let flags = libc::EFD_CLOEXEC | libc::EFD_NONBLOCK;
+    let efd = Errno::result(unsafe { libc::eventfd(0, flags) })
+        .chain_err(|| "failed to create eventfd")?;
+    let path = format!("{}/memory.oom_control", dir);
+    let ofd = open(&*path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+        .chain_err(|| format!("failed to open {}", path))?;
+    let res =
+        write_file(&dir, "cgroup.event_control", &format!("{} {}", efd, ofd));
+    close(ofd)?;
+    res?;
+    Ok(efd)
+}
+
 lazy_static! {
     pub static ref PATHS: HashMap<String, String> = {
         let mut result = HashMap::new();
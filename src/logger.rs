@@ -1,10 +1,120 @@
-use log::{Level, Log, Metadata, Record};
+use chrono::Utc;
+use errors::*;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json;
 
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
 use std::io::{stderr, Write};
+use std::sync::Mutex;
 
-pub struct SimpleLogger;
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+struct Sink {
+    file: Option<File>,
+    format: Format,
+    // level the logger was installed with
+    level: LevelFilter,
+    // extra fields added to every record, e.g. the container id
+    fields: BTreeMap<String, String>,
+}
+
+pub struct SimpleLogger {
+    sink: Mutex<Sink>,
+}
+
+lazy_static! {
+    pub static ref SIMPLE_LOGGER: SimpleLogger = SimpleLogger {
+        sink: Mutex::new(Sink {
+            file: None,
+            format: Format::Text,
+            level: LevelFilter::Info,
+            fields: BTreeMap::new(),
+        }),
+    };
+}
+
+/// Installs the logger. Records go to `path` if given, otherwise to stderr.
+pub fn init(
+    level: LevelFilter,
+    path: Option<&str>,
+    format: Format,
+) -> Result<()> {
+    // install first, so failing to open the file is still reported
+    let _ =
+        log::set_logger(&*SIMPLE_LOGGER).map(|()| log::set_max_level(level));
+    let mut sink = SIMPLE_LOGGER.sink.lock().unwrap();
+    sink.format = format;
+    sink.level = level;
+    if let Some(p) = path {
+        let f = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(p)
+            .chain_err(|| format!("failed to open log file {}", p))?;
+        sink.file = Some(f);
+    }
+    Ok(())
+}
+
+/// Adds a field that is included in every following record
+pub fn set_field(key: &str, value: &str) {
+    let mut sink = SIMPLE_LOGGER.sink.lock().unwrap();
+    sink.fields.insert(key.to_string(), value.to_string());
+}
+
+/// Switches between debug logging and the level the logger was installed
+/// with, returning the new level.
+pub fn toggle_debug() -> LevelFilter {
+    let sink = SIMPLE_LOGGER.sink.lock().unwrap();
+    let level = if log::max_level() >= LevelFilter::Debug {
+        ::std::cmp::min(sink.level, LevelFilter::Info)
+    } else {
+        LevelFilter::Debug
+    };
+    log::set_max_level(level);
+    level
+}
 
-pub static SIMPLE_LOGGER: SimpleLogger = SimpleLogger;
+// level names as expected by containerd and docker
+fn level_name(level: Level) -> &'static str {
+    match level {
+        Level::Error => "error",
+        Level::Warn => "warning",
+        Level::Info => "info",
+        Level::Debug => "debug",
+        Level::Trace => "trace",
+    }
+}
+
+fn format_record(record: &Record, sink: &Sink) -> String {
+    let time = Utc::now().to_rfc3339();
+    match sink.format {
+        Format::Json => {
+            let mut entry = sink.fields.clone();
+            entry
+                .insert("level".to_string(), level_name(record.level()).into());
+            entry.insert("msg".to_string(), record.args().to_string());
+            entry.insert("time".to_string(), time);
+            serde_json::to_string(&entry).unwrap_or_default()
+        }
+        Format::Text => {
+            let mut line = if sink.file.is_some() {
+                format!("{} {} - {}", time, record.level(), record.args())
+            } else {
+                format!("{} - {}", record.level(), record.args())
+            };
+            for (k, v) in &sink.fields {
+                line.push_str(&format!(" {}={}", k, v));
+            }
+            line
+        }
+    }
+}
 
 impl Log for SimpleLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
@@ -13,16 +123,26 @@ impl Log for SimpleLogger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            let _ = writeln!(
-                &mut stderr(),
-                "{} - {}",
-                record.level(),
-                record.args()
-            );
+            if let Ok(mut sink) = self.sink.lock() {
+                let line = format_record(record, &sink);
+                match sink.file {
+                    Some(ref mut f) => {
+                        let _ = writeln!(f, "{}", line);
+                    }
+                    None => {
+                        let _ = writeln!(&mut stderr(), "{}", line);
+                    }
+                }
+            }
         }
     }
 
     fn flush(&self) {
+        if let Ok(mut sink) = self.sink.lock() {
+            if let Some(ref mut f) = sink.file {
+                let _ = f.flush();
+            }
+        }
         stderr().flush().expect("Failed to flush");
     }
 }
@@ -5,7 +5,9 @@
 #[cfg(feature = "nightly")]
 extern crate alloc_system;
 
+extern crate api;
 extern crate caps;
+extern crate chrono;
 #[macro_use]
 extern crate clap;
 #[macro_use]
@@ -22,6 +24,9 @@ extern crate prctl;
 extern crate scopeguard;
 extern crate oci;
 extern crate seccomp_sys;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 
 extern crate system;
 
@@ -31,9 +36,13 @@ mod errors;
 mod logger;
 mod mounts;
 mod nix_ext;
+mod procfs;
+mod report;
 mod seccomp;
 mod selinux;
 mod signals;
+mod spec;
+mod state;
 mod sync;
 
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
@@ -56,14 +65,19 @@ use nix::unistd::{Gid, Pid, Uid};
 use nix_ext::{clearenv, putenv, setgroups, setrlimit};
 use oci::{Linux, LinuxIDMapping, LinuxRlimit, Spec};
 use oci::{LinuxDevice, LinuxDeviceType};
+use state::{Lifecycle, Status};
 use std::collections::HashMap;
 use std::ffi::CString;
-use std::fs::{canonicalize, create_dir, create_dir_all, remove_dir_all, File};
+use std::fs::{canonicalize, create_dir, create_dir_all, read_dir};
+use std::fs::{remove_dir_all, File};
 use std::io::{Read, Write};
 use std::os::unix::fs::symlink;
 use std::os::unix::io::{FromRawFd, RawFd};
 use std::result::Result as StdResult;
+use std::time::{Duration, Instant};
 use sync::Cond;
+use system::config::{Config, OomPolicy, RestartPolicy, SignalForward};
+use system::events::{EventStream, Update};
 use system::*;
 
 lazy_static! {
@@ -146,9 +160,7 @@ const INIT_PID: &'static str = "init.pid";
 const PROCESS_PID: &'static str = "process.pid";
 const TSOCKETFD: RawFd = 9;
 const ENYA_PROCESS_CGROUP: &'static str = "process";
-const ENYA_PROCESS_SHARE: f64 = 0.85;
 const ENYA_SYSTEM_CGROUP: &'static str = "system";
-const ENYA_SYSTEM_SHARE: f64 = 0.15;
 
 #[cfg(feature = "nightly")]
 static mut ARGC: isize = 0 as isize;
@@ -227,6 +239,23 @@ fn id_validator(val: String) -> StdResult<(), String> {
     Ok(())
 }
 
+#[allow(needless_pass_by_value)]
+fn timeout_validator(val: String) -> StdResult<(), String> {
+    match val.parse::<u64>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("timeout {} is not a number of seconds", val)),
+    }
+}
+
+#[allow(needless_pass_by_value)]
+fn interval_validator(val: String) -> StdResult<(), String> {
+    match val.parse::<u64>() {
+        Ok(0) => Err("interval must be at least a second".to_string()),
+        Ok(_) => Ok(()),
+        Err(_) => Err(format!("interval {} is not a number of seconds", val)),
+    }
+}
+
 fn run() -> Result<()> {
     let id_arg = Arg::with_name("id")
         .required(true)
@@ -269,14 +298,16 @@ fn run() -> Result<()> {
         )
         .arg(
             Arg::with_name("log")
-                .help("Compatibility (ignored)")
+                .help("Log file, defaults to stderr")
                 .long("log")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("log-format")
-                .help("Compatibility (ignored)")
+                .help("Format of log records")
                 .long("log-format")
+                .possible_values(&["text", "json"])
+                .default_value("text")
                 .takes_value(true),
         )
         .arg(
@@ -329,6 +360,17 @@ fn run() -> Result<()> {
                 )
                 .about("Create a container (to be started later)"),
         )
+        .subcommand(
+            SubCommand::with_name("spec")
+                .setting(AppSettings::ColoredHelp)
+                .arg(&bundle_arg)
+                .arg(
+                    Arg::with_name("rootless")
+                        .help("Generate a config for a rootless container")
+                        .long("rootless"),
+                )
+                .about("Create a new enya-aware config.json"),
+        )
         .subcommand(
             SubCommand::with_name("start")
                 .setting(AppSettings::ColoredHelp)
@@ -349,10 +391,32 @@ fn run() -> Result<()> {
                 .arg(&id_arg)
                 .arg(
                     Arg::with_name("a")
-                        .help("Compatibility (ignored)")
+                        .help("Signal all processes in the container")
                         .long("all")
                         .short("a"),
                 )
+                .arg(
+                    Arg::with_name("only")
+                        .help(
+                            "Only signal the process or the system, which \
+                             can't be stopped on its own",
+                        )
+                        .long("only")
+                        .takes_value(true)
+                        .possible_values(&["process", "system"])
+                        .conflicts_with("a"),
+                )
+                .arg(
+                    Arg::with_name("timeout")
+                        .help(
+                            "Seconds the process gets to stop before it is \
+                             killed, overriding io.enya.stop.timeout-ms",
+                        )
+                        .long("timeout")
+                        .takes_value(true)
+                        .validator(timeout_validator)
+                        .conflicts_with_all(&["a", "only"]),
+                )
                 .arg(
                     Arg::with_name("signal")
                         .default_value("TERM")
@@ -375,16 +439,57 @@ fn run() -> Result<()> {
                 .about("Delete a (previously created) container"),
         )
         .subcommand(
-            SubCommand::with_name("ps")
+            SubCommand::with_name("events")
                 .setting(AppSettings::ColoredHelp)
                 .arg(&id_arg)
+                .arg(
+                    Arg::with_name("interval")
+                        .help("Seconds between printed stats")
+                        .long("interval")
+                        .default_value("5")
+                        .takes_value(true)
+                        .validator(interval_validator),
+                )
+                .arg(
+                    Arg::with_name("stats")
+                        .help("Display the container's stats then exit")
+                        .long("stats"),
+                )
+                .about("Stream stats and events of a (previously created) container"),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .setting(AppSettings::ColoredHelp)
                 .arg(
                     Arg::with_name("f")
-                        .help("Compatibility (ignored)")
+                        .help("Output format")
                         .long("format")
                         .short("f")
+                        .possible_values(&["table", "json"])
+                        .default_value("table")
                         .takes_value(true),
                 )
+                .arg(
+                    Arg::with_name("q")
+                        .help("Display only container ids")
+                        .long("quiet")
+                        .short("q"),
+                )
+                .about("List containers in the state dir"),
+        )
+        .subcommand(
+            SubCommand::with_name("ps")
+                .setting(AppSettings::ColoredHelp)
+                .arg(&id_arg)
+                .arg(
+                    Arg::with_name("f")
+                        .help("Output format")
+                        .long("format")
+                        .short("f")
+                        .takes_value(true)
+                        .possible_values(&["table", "json"])
+                        .default_value("json"),
+                )
                 .about("List processes in a (previously created) container"),
         )
         .get_matches_from(get_args());
@@ -394,16 +499,21 @@ fn run() -> Result<()> {
         _ => log::LevelFilter::Trace,
     };
 
-    let _ = log::set_logger(&logger::SIMPLE_LOGGER)
-        .map(|()| log::set_max_level(level));
+    let format = match matches.value_of("log-format").unwrap_or_default() {
+        "json" => logger::Format::Json,
+        _ => logger::Format::Text,
+    };
+    logger::init(level, matches.value_of("log"), format)?;
+    if let (name, Some(sub_matches)) = matches.subcommand() {
+        logger::set_field("subcommand", name);
+        if let Some(id) = sub_matches.value_of("id") {
+            logger::set_field("id", id);
+        }
+    }
 
-    // create empty log file to avoid warning
-    let lpath = matches.value_of("log").unwrap_or_default();
-    if lpath != "" {
-        std::fs::OpenOptions::new()
-            .create(true)
-            .write(true)
-            .open(lpath)?;
+    // spec does not touch the state dir, so it works for any user
+    if let ("spec", Some(spec_matches)) = matches.subcommand() {
+        return cmd_spec(spec_matches);
     }
 
     let state_dir = matches.value_of("r").unwrap().to_string();
@@ -422,13 +532,19 @@ fn run() -> Result<()> {
             &state_dir,
             delete_matches,
         ),
+        ("events", Some(events_matches)) => cmd_events(
+            events_matches.value_of("id").unwrap(),
+            &state_dir,
+            events_matches,
+        ),
+        ("list", Some(list_matches)) => cmd_list(&state_dir, list_matches),
         ("kill", Some(kill_matches)) => cmd_kill(
             kill_matches.value_of("id").unwrap(),
             &state_dir,
             kill_matches,
         ),
         ("ps", Some(ps_matches)) => {
-            cmd_ps(ps_matches.value_of("id").unwrap(), &state_dir)
+            cmd_ps(ps_matches.value_of("id").unwrap(), &state_dir, ps_matches)
         }
         ("run", Some(run_matches)) => {
             cmd_run(run_matches.value_of("id").unwrap(), run_matches)
@@ -449,14 +565,48 @@ fn instance_dir(id: &str, state_dir: &str) -> String {
     format!("{}/{}", state_dir, id)
 }
 
-fn state(id: &str, status: &str, pid: Pid, bundle: &str) -> oci::State {
+// must be in instance_dir
+fn state(
+    id: &str,
+    status: Status,
+    pid: Pid,
+    spec: Option<&Spec>,
+) -> oci::State {
+    let mut annotations = HashMap::new();
+    if let Some(spec) = spec {
+        annotations = spec.annotations.clone();
+        // include the defaults, so the monitor endpoint is always known
+        if let Ok(config) = Config::from_spec(spec) {
+            annotations.extend(config.annotations());
+        }
+    }
+    let mut bundle = String::new();
+    if let Ok(lifecycle) = Lifecycle::load() {
+        let created = state::CREATED_ANNOTATION.to_string();
+        annotations.insert(created, lifecycle.created);
+        bundle = lifecycle.bundle;
+    }
+    if status == Status::Stopped {
+        if let Some(code) = state::exit_status() {
+            let exit = state::EXIT_STATUS_ANNOTATION.to_string();
+            annotations.insert(exit, code.to_string());
+        }
+        if let Some(path) = state::final_metrics() {
+            let metrics = state::FINAL_METRICS_ANNOTATION.to_string();
+            annotations.insert(metrics, path);
+        }
+    }
+    if let Some(path) = state::oom_record() {
+        let oom = state::OOM_RECORD_ANNOTATION.to_string();
+        annotations.insert(oom, path);
+    }
     oci::State {
-        version: "0.2.0".to_string(),
+        version: state::OCI_VERSION.to_string(),
         id: id.to_string(),
-        status: status.to_string(),
+        status: status.as_str().to_string(),
         pid: pid.into(), // TODO implement serde ser/de for Pid/Gid/..
-        bundle: bundle.to_string(),
-        annotations: HashMap::new(),
+        bundle,
+        annotations,
     }
 }
 
@@ -473,38 +623,58 @@ fn get_init_pid() -> Result<(Pid)> {
     Ok(pid)
 }
 
+// must be in instance_dir
+fn check_stale(id: &str, init_pid: Pid) -> Result<()> {
+    let start_time = Lifecycle::load().map(|l| l.init_start_time).unwrap_or(0);
+    if state::liveness(init_pid, start_time) == state::Liveness::Recycled {
+        bail!(
+            "container {} is stale, its init pid {} belongs to another process",
+            id,
+            init_pid
+        );
+    }
+    Ok(())
+}
+
 fn state_from_dir(id: &str, state_dir: &str) -> Result<(oci::State)> {
     let dir = instance_dir(id, state_dir);
     chdir(&*dir).chain_err(|| format!("instance {} doesn't exist", id))?;
-    let mut status = "creating";
-    let mut root = String::new();
     let pid = get_init_pid()?;
-    if let Ok(spec) = Spec::load(CONFIG) {
-        root = spec.root.path.to_owned();
-        status = "created";
-        if let Ok(mut f) = File::open(PROCESS_PID) {
-            status = "running";
+    // the config is only written once create is done
+    let spec = match Spec::load(CONFIG) {
+        Ok(spec) => spec,
+        Err(_) => return Ok(state(id, Status::Creating, pid, None)),
+    };
+    let mut status = Lifecycle::load()
+        .map(|l| l.current_status(pid))
+        .unwrap_or(Status::Creating);
+    if status == Status::Created || status == Status::Running {
+        // a restarted Process gets a new pid, so only the init pid counts
+        let restarts = Config::from_spec(&spec)
+            .map(|c| c.restart_policy != RestartPolicy::Never)
+            .unwrap_or(false);
+        let process_file = if restarts {
+            None
+        } else {
+            File::open(PROCESS_PID).ok()
+        };
+        if let Some(mut f) = process_file {
             let mut result = String::new();
             f.read_to_string(&mut result)?;
             if let Ok(process_pid) = result.parse::<i32>() {
                 if signals::signal_process(Pid::from_raw(process_pid), None)
                     .is_err()
                 {
-                    status = "stopped";
+                    status = Status::Stopped;
                 }
             } else {
                 // not safe to log during state because shim combines
                 // stdout and stderr
                 // warn!("invalid process pid: {}", result);
             }
-        } else {
-            // not safe to log during state because shim combines
-            // stdout and stderr
-            // warn!("could not open process pid");
         }
     }
-    let st = state(id, status, pid, &root);
-    Ok(st)
+    Ok(state(id, status, pid, Some(&spec)))
 }
 
 fn cmd_state(id: &str, state_dir: &str) -> Result<()> {
@@ -514,6 +684,240 @@ fn cmd_state(id: &str, state_dir: &str) -> Result<()> {
     Ok(())
 }
 
+fn cmd_spec(matches: &ArgMatches) -> Result<()> {
+    debug!("Performing spec");
+    let bundle = matches.value_of("bundle").unwrap();
+    let path = format!("{}/{}", bundle, CONFIG);
+    if std::path::Path::new(&path).exists() {
+        bail!("{} already exists, remove it first", path);
+    }
+    let spec = spec::default_spec(matches.is_present("rootless"));
+    let mut f = File::create(&path)
+        .chain_err(|| format!("failed to create {}", path))?;
+    serde_json::to_writer_pretty(&mut f, &spec)
+        .chain_err(|| format!("failed to write {}", path))?;
+    Ok(())
+}
+
+fn cmd_events(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
+    debug!("Performing events");
+    let interval =
+        value_t!(matches, "interval", u64).chain_err(|| "invalid interval")?;
+    let interval = std::time::Duration::from_secs(interval);
+    let stats_only = matches.is_present("stats");
+    // state_from_dir changes the working directory, so make sure
+    // we can find our way back to the state dir
+    let state_dir = canonicalize(state_dir)
+        .chain_err(|| format!("failed to find state dir {}", state_dir))?
+        .to_string_lossy()
+        .into_owned();
+    let st = state_from_dir(id, &state_dir)?;
+    if st.status != "created" && st.status != "running" {
+        bail!("container {} is not running", id);
+    }
+    let spec = Spec::load(CONFIG)
+        .chain_err(|| format!("failed to load {}", CONFIG))?;
+    let linux = spec.linux.as_ref().unwrap();
+    let cpath = if linux.cgroups_path == "" {
+        format! {"/{}", id}
+    } else {
+        linux.cgroups_path.clone()
+    };
+    let process_cgroup = format!("{}/{}", &cpath, ENYA_PROCESS_CGROUP);
+    let config =
+        Config::from_spec(&spec).chain_err(|| "invalid enya annotations")?;
+
+    // the monitor only listens inside of the container
+    let ns = format!("/proc/{}/ns/net", st.pid);
+    let fd = open(&*ns, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())
+        .chain_err(|| format!("failed to open {}", ns))?;
+    setns(fd, CloneFlags::CLONE_NEWNET)
+        .chain_err(|| "failed to enter network namespace")?;
+    close(fd)?;
+
+    let stream = EventStream::connect(&config.monitor_path())
+        .chain_err(|| "failed to connect to monitor")?;
+    let mut status = st.status;
+    let mut oom_kills = cgroups::oom_kills(&process_cgroup).unwrap_or(0);
+    let mut last_stats: Option<std::time::Instant> = None;
+    let mut watch = || -> Result<()> {
+        loop {
+            let next = stream
+                .next(interval)
+                .chain_err(|| "lost connection to monitor")?;
+            match next {
+                Some(Update::Metrics(ref r)) if stats_only => {
+                    return print_event(&report::Event::stats(id, r));
+                }
+                Some(Update::Metrics(ref r)) => {
+                    if last_stats.map_or(true, |t| t.elapsed() >= interval) {
+                        print_event(&report::Event::stats(id, r))?;
+                        last_stats = Some(std::time::Instant::now());
+                    }
+                }
+                Some(Update::Process(ref e)) => {
+                    if !stats_only {
+                        print_event(&report::Event::process(id, e))?;
+                    }
+                }
+                None if stats_only => bail!(
+                    "no stats received from monitor within {:?}",
+                    interval
+                ),
+                None => {}
+            }
+
+            if let Some(kills) = cgroups::oom_kills(&process_cgroup) {
+                if kills > oom_kills {
+                    print_event(&report::Event::new("oom", id))?;
+                    oom_kills = kills;
+                }
+            }
+
+            let current = match state_from_dir(id, &state_dir) {
+                Ok(st) => st.status,
+                Err(_) => "deleted".to_string(),
+            };
+            if current != status {
+                let event =
+                    report::Event::new("state", id).with("status", &current);
+                print_event(&event)?;
+                status = current;
+            }
+            if status == "stopped" || status == "deleted" {
+                return Ok(());
+            }
+        }
+    };
+    let result = watch();
+    stream.shutdown();
+    result
+}
+
+fn print_event(event: &report::Event) -> Result<()> {
+    let line = serde_json::to_string(event)
+        .chain_err(|| "could not serialize event")?;
+    println!("{}", line);
+    Ok(())
+}
+
+#[derive(Serialize, Debug)]
+struct ContainerInfo {
+    id: String,
+    pid: i32,
+    status: String,
+    bundle: String,
+    created: String,
+    monitor: String,
+}
+
+fn cmd_list(state_dir: &str, matches: &ArgMatches) -> Result<()> {
+    debug!("Performing list");
+    // state_from_dir changes the working directory, so make sure
+    // we can find our way back to the state dir
+    let state_dir = canonicalize(state_dir)
+        .chain_err(|| format!("failed to find state dir {}", state_dir))?
+        .to_string_lossy()
+        .into_owned();
+    let mut ids = Vec::new();
+    for entry in read_dir(&state_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            ids.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    ids.sort();
+
+    let mut containers = Vec::new();
+    for id in ids {
+        let st = match state_from_dir(&id, &state_dir) {
+            Ok(st) => st,
+            Err(e) => {
+                // the instance may have been deleted while we were listing
+                debug!("skipping {}: {}", id, e);
+                continue;
+            }
+        };
+        // NOTE: state_from_dir leaves us in the instance dir
+        let created = st
+            .annotations
+            .get(state::CREATED_ANNOTATION)
+            .cloned()
+            .unwrap_or_default();
+        let config = Spec::load(CONFIG)
+            .ok()
+            .and_then(|spec| Config::from_spec(&spec).ok())
+            .unwrap_or_default();
+        containers.push(ContainerInfo {
+            id: st.id,
+            pid: st.pid,
+            status: st.status,
+            bundle: st.bundle,
+            created,
+            monitor: config.monitor_path(),
+        });
+    }
+
+    if matches.is_present("q") {
+        for c in &containers {
+            println!("{}", c.id);
+        }
+        return Ok(());
+    }
+
+    match matches.value_of("f").unwrap_or_default() {
+        "json" => println!(
+            "{}",
+            serde_json::to_string(&containers)
+                .chain_err(|| "could not serialize containers")?
+        ),
+        _ => {
+            let mut rows = vec![[
+                "ID".to_string(),
+                "PID".to_string(),
+                "STATUS".to_string(),
+                "BUNDLE".to_string(),
+                "CREATED".to_string(),
+                "MONITOR".to_string(),
+            ]];
+            for c in containers {
+                rows.push([
+                    c.id,
+                    c.pid.to_string(),
+                    c.status,
+                    c.bundle,
+                    c.created,
+                    c.monitor,
+                ]);
+            }
+            print_table(&rows);
+        }
+    }
+    Ok(())
+}
+
+fn print_table<R: AsRef<[String]>>(rows: &[R]) {
+    let mut widths = Vec::new();
+    for row in rows {
+        for (i, col) in row.as_ref().iter().enumerate() {
+            if widths.len() <= i {
+                widths.push(0);
+            }
+            widths[i] = std::cmp::max(widths[i], col.len());
+        }
+    }
+    for row in rows {
+        let line = row
+            .as_ref()
+            .iter()
+            .zip(&widths)
+            .map(|(col, width)| format!("{:1$}", col, width))
+            .collect::<Vec<String>>()
+            .join("   ");
+        println!("{}", line.trim_right());
+    }
+}
+
 fn cmd_create(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
     debug!("Performing create");
     let bundle = matches.value_of("bundle").unwrap();
@@ -527,7 +931,9 @@ fn cmd_create(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
         }
         bail!("Container with id {} already exists", id);
     }
-    if let Err(e) = finish_create(id, &dir, matches) {
+    if let Err(e) =
+        state::lock(&dir).and_then(|()| finish_create(id, &dir, matches))
+    {
         let _ = remove_dir_all(&dir);
         Err(e)
     } else {
@@ -572,6 +978,10 @@ fn load_console_sockets() -> Result<(RawFd, RawFd)> {
 fn finish_create(id: &str, dir: &str, matches: &ArgMatches) -> Result<()> {
     let spec = Spec::load(CONFIG)
         .chain_err(|| format!("failed to load {}", CONFIG))?;
+    let bundle = canonicalize(".")
+        .chain_err(|| "failed to find bundle")?
+        .to_string_lossy()
+        .into_owned();
 
     let rootfs = canonicalize(&spec.root.path)
         .chain_err(|| format! {"failed to find root path {}", &spec.root.path})?
@@ -579,6 +989,10 @@ fn finish_create(id: &str, dir: &str, matches: &ArgMatches) -> Result<()> {
         .into_owned();
 
     chdir(&*dir).chain_err(|| format!("failed to chdir to {}", &dir))?;
+    Lifecycle::new(&bundle).save()?;
+    let exitfd = state::open_exit_file()?;
+    let metricsfd = state::open_metrics_file()?;
+    let oomfd = state::open_oom_file()?;
     // NOTE: There are certain configs where we will not be able to create a
     //       console during start, so this could potentially create the
     //       console during init and pass to the process via sendmsg. This
@@ -631,15 +1045,20 @@ fn finish_create(id: &str, dir: &str, matches: &ArgMatches) -> Result<()> {
         csocketfd,
         consolefd,
         tsocketfd,
+        exitfd,
+        metricsfd,
+        oomfd,
     )?;
+    close(exitfd).chain_err(|| "could not close exitfd")?;
+    close(metricsfd).chain_err(|| "could not close metricsfd")?;
+    close(oomfd).chain_err(|| "could not close oomfd")?;
     if child_pid != Pid::from_raw(-1) {
         debug!("writing init pid file {}", child_pid);
-        let mut f = File::create(INIT_PID)?;
-        f.write_all(child_pid.to_string().as_bytes())?;
+        let pid = child_pid.to_string();
+        state::write_atomic(INIT_PID, pid.as_bytes())?;
         if pidfile != "" {
             debug!("writing process {} pid to file {}", child_pid, pidfile);
-            let mut f = File::create(pidfile)?;
-            f.write_all(child_pid.to_string().as_bytes())?;
+            state::write_atomic(pidfile, pid.as_bytes())?;
         }
         let linux = spec.linux.as_ref().unwrap();
         // update namespaces to enter only
@@ -686,9 +1105,15 @@ fn finish_create(id: &str, dir: &str, matches: &ArgMatches) -> Result<()> {
             windows: spec.windows,
         };
         debug!("writing updated config");
-        updated
-            .save(CONFIG)
-            .chain_err(|| format!("failed to save {}", CONFIG))?;
+        let config = oci::serialize::to_string(&updated)
+            .chain_err(|| format!("failed to serialize {}", CONFIG))?;
+        state::write_atomic(CONFIG, config.as_bytes())?;
+        let mut lifecycle = Lifecycle::load()?;
+        lifecycle.status = Status::Created;
+        lifecycle.init_start_time = procfs::stat(child_pid)
+            .chain_err(|| format!("failed to stat init {}", child_pid))?
+            .start_time;
+        lifecycle.save()?;
     }
     Ok(())
 }
@@ -699,11 +1124,13 @@ fn cmd_start(id: &str, state_dir: &str) -> Result<()> {
     // we use instance dir for config written out by create
     let dir = instance_dir(id, state_dir);
     chdir(&*dir).chain_err(|| format!("instance {} doesn't exist", id))?;
+    state::lock(&dir)?;
 
     let spec = Spec::load(CONFIG)
         .chain_err(|| format!("failed to load {}", CONFIG))?;
 
     let init_pid = get_init_pid()?;
+    check_stale(id, init_pid)?;
 
     let tsocket = "trigger-socket";
     let mut tsocketfd = socket(
@@ -728,7 +1155,7 @@ fn cmd_start(id: &str, state_dir: &str) -> Result<()> {
     if tsocketfd != -1 {
         debug!("running prestart hooks");
         if let Some(ref hooks) = spec.hooks {
-            let st = state(id, "running", init_pid, &spec.root.path);
+            let st = state(id, Status::Created, init_pid, Some(&spec));
             for h in &hooks.prestart {
                 execute_hook(h, &st)
                     .chain_err(|| "failed to execute prestart hooks")?;
@@ -750,11 +1177,11 @@ fn cmd_start(id: &str, state_dir: &str) -> Result<()> {
                 break;
             }
         }
-        let mut f = File::create(PROCESS_PID)?;
-        f.write_all(child_pid.to_string().as_bytes())?;
+        let pid = child_pid.to_string();
+        state::write_atomic(PROCESS_PID, pid.as_bytes())?;
         debug!("running poststart hooks");
         if let Some(ref hooks) = spec.hooks {
-            let st = state(id, "running", init_pid, &spec.root.path);
+            let st = state(id, Status::Running, init_pid, Some(&spec));
             for h in &hooks.poststart {
                 if let Err(e) = execute_hook(h, &st) {
                     warn!("failed to execute poststart hook: {}", e);
@@ -764,6 +1191,7 @@ fn cmd_start(id: &str, state_dir: &str) -> Result<()> {
         debug!("writing zero to trigger socket to start exec");
         let data: &[u8] = &[0];
         write(tsocketfd, data).chain_err(|| "failed to write zero")?;
+        Lifecycle::set_status(Status::Running)?;
         return Ok(());
     }
 
@@ -780,11 +1208,15 @@ fn cmd_start(id: &str, state_dir: &str) -> Result<()> {
         csocketfd,
         consolefd,
         -1,
+        -1,
+        -1,
+        -1,
     )?;
     if child_pid != Pid::from_raw(-1) {
         debug!("writing process {} pid file", child_pid);
-        let mut f = File::create(PROCESS_PID)?;
-        f.write_all(child_pid.to_string().as_bytes())?;
+        let pid = child_pid.to_string();
+        state::write_atomic(PROCESS_PID, pid.as_bytes())?;
+        Lifecycle::set_status(Status::Running)?;
     }
     Ok(())
 }
@@ -795,11 +1227,58 @@ fn cmd_kill(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
         .unwrap_or(Signal::SIGTERM);
     let dir = instance_dir(id, state_dir);
     chdir(&*dir).chain_err(|| format!("instance {} doesn't exist", id))?;
+    state::lock(&dir)?;
+    check_stale(id, get_init_pid()?)?;
+    let only = matches.value_of("only");
+    if only == Some("system") {
+        match signal {
+            // the System is pid 1, it can't exit without taking the
+            // Process with it
+            Signal::SIGTERM | Signal::SIGINT | Signal::SIGQUIT => {
+                bail!("{:?} can't be sent to the system only", signal)
+            }
+            _ => {}
+        }
+    }
+    if matches.is_present("a") || only == Some("process") {
+        let spec = Spec::load(CONFIG)
+            .chain_err(|| format!("failed to load {}", CONFIG))?;
+        let linux = spec.linux.as_ref().unwrap();
+        let cpath = if linux.cgroups_path == "" {
+            format! {"/{}", id}
+        } else {
+            linux.cgroups_path.clone()
+        };
+        // the process cgroup only exists for the enya subsystems, memory
+        // is one of them
+        let cgroup = if only.is_some() {
+            format!("{}/{}", &cpath, ENYA_PROCESS_CGROUP)
+        } else {
+            cpath.clone()
+        };
+        let init_pid = if only.is_some() {
+            None
+        } else {
+            Some(get_init_pid()?)
+        };
+        return signal_frozen(&cpath, &cgroup, signal, init_pid);
+    }
     let mut f = File::open(INIT_PID).chain_err(|| "failed to find pid")?;
     let mut result = String::new();
     f.read_to_string(&mut result)?;
     if let Ok(init_pid) = result.parse::<i32>() {
-        if signals::signal_process(Pid::from_raw(init_pid), signal).is_err() {
+        let init_pid = Pid::from_raw(init_pid);
+        let res = if only == Some("system") {
+            signals::queue_signal(init_pid, signal, signals::SYSTEM_ONLY)
+        } else if let Some(t) = matches.value_of("timeout") {
+            // validated by clap
+            let timeout = Duration::from_secs(t.parse::<u64>().unwrap());
+            let flags = signals::timeout_flags(timeout);
+            signals::queue_signal(init_pid, signal, flags)
+        } else {
+            signals::signal_process(init_pid, signal)
+        };
+        if res.is_err() {
             warn!("failed signal init process {}, may have exited", init_pid);
         }
     } else {
@@ -808,23 +1287,108 @@ fn cmd_kill(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
     Ok(())
 }
 
-fn cmd_ps(id: &str, state_dir: &str) -> Result<()> {
+// Signals every process in `cgroup` while the container is frozen, so
+// that no new processes can slip by. `init` gets the signal for the
+// System only, as it would pass it on to everyone a second time.
+fn signal_frozen(
+    cpath: &str,
+    cgroup: &str,
+    signal: Signal,
+    init: Option<Pid>,
+) -> Result<()> {
+    let frozen = match cgroups::freeze(cpath) {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("could not freeze container: {}", e);
+            false
+        }
+    };
+    if let Some(init) = init {
+        debug!("sending {:?} to init {}", signal, init);
+        if let Err(e) =
+            signals::queue_signal(init, signal, signals::SYSTEM_ONLY)
+        {
+            warn!("failed to signal init {}: {}", init, e);
+        }
+    }
+    for pid in cgroups::get_all_procs("memory", cgroup) {
+        if Some(pid) == init {
+            continue;
+        }
+        debug!("sending {:?} to {}", signal, pid);
+        if let Err(e) = signals::signal_process(pid, signal) {
+            warn!("failed to signal {}: {}", pid, e);
+        }
+    }
+    if frozen {
+        cgroups::thaw(cpath)?;
+    }
+    Ok(())
+}
+
+fn cmd_ps(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
     debug!("Performing ps");
     let dir = instance_dir(id, state_dir);
     chdir(&*dir).chain_err(|| format!("instance {} doesn't exist", id))?;
-    let mut f = File::open(PROCESS_PID).chain_err(|| "failed to find pid")?;
-    let mut result = String::new();
-    f.read_to_string(&mut result)?;
-    // TODO: return any other execed processes
-    let mut pids = Vec::new();
-    if let Ok(process_pid) = result.parse::<i32>() {
-        pids.push(Pid::from_raw(process_pid));
+    let spec = Spec::load(CONFIG)
+        .chain_err(|| format!("failed to load {}", CONFIG))?;
+    let linux = spec.linux.as_ref().unwrap();
+    let cpath = if linux.cgroups_path == "" {
+        format! {"/{}", id}
     } else {
-        warn!("invalid process pid: {}", result);
+        linux.cgroups_path.clone()
+    };
+    // the enya cgroups only exist for the enya subsystems, so anything
+    // else in the container, e.g. execed processes, is found via cpuset
+    let process_cgroup = format!("{}/{}", &cpath, ENYA_PROCESS_CGROUP);
+    let system_cgroup = format!("{}/{}", &cpath, ENYA_SYSTEM_CGROUP);
+    let mut procs = Vec::new();
+    for pid in cgroups::get_procs("memory", &system_cgroup) {
+        procs.push((pid, ENYA_SYSTEM_CGROUP));
+    }
+    for pid in cgroups::get_procs("memory", &process_cgroup) {
+        procs.push((pid, ENYA_PROCESS_CGROUP));
+    }
+    let config =
+        Config::from_spec(&spec).chain_err(|| "invalid enya annotations")?;
+    for group in &config.processes {
+        let group_cgroup = format!("{}/{}", &cpath, group.name);
+        for pid in cgroups::get_procs("memory", &group_cgroup) {
+            procs.push((pid, group.name.as_str()));
+        }
+    }
+    for pid in cgroups::get_procs("cpuset", &cpath) {
+        if !procs.iter().any(|&(p, _)| p == pid) {
+            procs.push((pid, "other"));
+        }
+    }
+
+    if matches.value_of("f") == Some("table") {
+        let mut rows = vec![vec![
+            "USER".to_string(),
+            "PID".to_string(),
+            "ROLE".to_string(),
+            "%CPU".to_string(),
+            "RSS".to_string(),
+            "START".to_string(),
+            "COMMAND".to_string(),
+        ]];
+        let boot_time = procfs::boot_time()?;
+        let uptime = procfs::uptime()?;
+        for (pid, role) in procs {
+            match ps_row(pid, role, boot_time, uptime) {
+                Ok(row) => rows.push(row),
+                // the process may have exited in the meantime
+                Err(e) => debug!("skipping {}: {}", pid, e),
+            }
+        }
+        print_table(&rows);
+        return Ok(());
     }
-    let pids = pids
+
+    let pids = procs
         .into_iter()
-        .map(|pid: Pid| -> i32 { pid.into() })
+        .map(|(pid, _)| -> i32 { pid.into() })
         .collect::<Vec<i32>>();
     println!(
         "{}",
@@ -834,6 +1398,37 @@ fn cmd_ps(id: &str, state_dir: &str) -> Result<()> {
     Ok(())
 }
 
+fn ps_row(
+    pid: Pid,
+    role: &str,
+    boot_time: u64,
+    uptime: f64,
+) -> Result<Vec<String>> {
+    let stat = procfs::stat(pid)?;
+    let uid = procfs::uid(pid)?;
+    let ticks = procfs::clock_ticks() as f64;
+    let started = stat.start_time as f64 / ticks;
+    let cpu_time = (stat.utime + stat.stime) as f64 / ticks;
+    let cpu = if uptime > started {
+        100.0 * cpu_time / (uptime - started)
+    } else {
+        0.0
+    };
+    let start = chrono::DateTime::<chrono::Local>::from(
+        std::time::UNIX_EPOCH
+            + std::time::Duration::from_secs(boot_time + started as u64),
+    );
+    Ok(vec![
+        procfs::user_name(pid, uid),
+        pid.to_string(),
+        role.to_string(),
+        format!("{:.1}", cpu),
+        format!("{}", stat.rss * procfs::page_size() / 1024),
+        start.format("%H:%M").to_string(),
+        procfs::cmdline(pid)?,
+    ])
+}
+
 fn cmd_delete(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
     debug!("Performing delete");
     let dir = instance_dir(id, state_dir);
@@ -842,7 +1437,14 @@ fn cmd_delete(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
         warn!("returning zero to work around docker bug");
         return Ok(());
     }
-    if let Ok(mut f) = File::open(PROCESS_PID) {
+    state::lock(&dir)?;
+    // never signal processes that merely reuse the pids of the container
+    let start_time = Lifecycle::load().map(|l| l.init_start_time).unwrap_or(0);
+    let stale = state::liveness(get_init_pid()?, start_time)
+        == state::Liveness::Recycled;
+    if stale {
+        warn!("init pid of {} was recycled, only cleaning up", id);
+    } else if let Ok(mut f) = File::open(PROCESS_PID) {
         let mut result = String::new();
         f.read_to_string(&mut result)?;
         if let Ok(process_pid) = result.parse::<i32>() {
@@ -876,7 +1478,9 @@ fn cmd_delete(id: &str, state_dir: &str, matches: &ArgMatches) -> Result<()> {
     } else {
         debug!("process doesn't exist");
     }
-    if let Ok(mut f) = File::open(INIT_PID) {
+    if stale {
+        debug!("not killing recycled init pid");
+    } else if let Ok(mut f) = File::open(INIT_PID) {
         debug!("killing init process");
         let mut result = String::new();
         f.read_to_string(&mut result)?;
@@ -957,6 +1561,7 @@ fn cmd_run(id: &str, matches: &ArgMatches) -> Result<()> {
         -1,
         -1,
         -1,
+        -1,
     )?;
     info!("Container running with pid {}", child_pid);
     Ok(())
@@ -1033,11 +1638,14 @@ fn safe_run_container(
     csocketfd: RawFd,
     consolefd: RawFd,
     tsocketfd: RawFd,
+    exitfd: RawFd,
+    metricsfd: RawFd,
+    oomfd: RawFd,
 ) -> Result<Pid> {
     let pid = getpid();
     match run_container(
         id, rootfs, spec, init_pid, init, init_only, daemonize, csocketfd,
-        consolefd, tsocketfd,
+        consolefd, tsocketfd, exitfd, metricsfd, oomfd,
     ) {
         Err(e) => {
             // if we are the top level thread, kill all children
@@ -1061,6 +1669,9 @@ fn run_container(
     csocketfd: RawFd,
     mut consolefd: RawFd,
     tsocketfd: RawFd,
+    exitfd: RawFd,
+    metricsfd: RawFd,
+    oomfd: RawFd,
 ) -> Result<Pid> {
     if let Err(e) = prctl::set_dumpable(false) {
         bail!(format!("set dumpable returned {}", e));
@@ -1083,6 +1694,10 @@ fn run_container(
 
     let linux = spec.linux.as_ref().unwrap();
 
+    // fail early instead of in the System
+    let config =
+        Config::from_spec(spec).chain_err(|| "invalid enya annotations")?;
+
     // initialize static variables before forking
     initialize(&DEFAULT_DEVICES);
     initialize(&NAMESPACES);
@@ -1110,6 +1725,15 @@ fn run_container(
         init_only = false;
     }
 
+    // the System publishes the metrics of the Process into a shared page,
+    // which the Process maps from the inherited read-only fd
+    let (pagefd, process_pagefd) = if init {
+        api::shm::create().chain_err(|| "failed to create metrics page")?
+    } else {
+        (-1, -1)
+    };
+    let spec = &with_enya_env(id, spec, &config, process_pagefd);
+
     let cpath = if linux.cgroups_path == "" {
         format! {"/{}", id}
     } else {
@@ -1137,8 +1761,14 @@ fn run_container(
 
     // parent returns child pid and exits
     if child_pid != Pid::from_raw(-1) {
+        if pagefd != -1 {
+            close(pagefd).chain_err(|| "could not close pagefd")?;
+            close(process_pagefd).chain_err(|| "could not close pagefd")?;
+        }
         return Ok(child_pid);
     }
+    // the instance lock belongs to the runtime, not the container
+    state::close_lock();
 
     let mut mount_fd = -1;
     // enter path namespaces
@@ -1187,6 +1817,9 @@ fn run_container(
     //       2. No resources are specified
     cgroups::enya_setup(&cpath, ENYA_PROCESS_CGROUP)?;
     cgroups::enya_setup(&cpath, ENYA_SYSTEM_CGROUP)?;
+    for group in &config.processes {
+        cgroups::enya_setup(&cpath, &group.name)?;
+    }
 
     if !init_only {
         // notify first parent that it can continue
@@ -1273,17 +1906,31 @@ fn run_container(
         setgroups(&spec.process.user.additional_gids)?;
     }
 
-    // notify first parent that it can continue
-    debug!("writing zero to pipe to trigger poststart");
-    let data: &[u8] = &[0];
-    write(wfd, data).chain_err(|| "failed to write zero")?;
-
+    let mut readyfd = -1;
     if init {
+        // the System notifies the first parent once it is up
+        let fds = SystemFds {
+            wfd,
+            exitfd,
+            metricsfd,
+            readyfd,
+            pagefd,
+            oomfd,
+            process_memfd: -1,
+            system_memfd: -1,
+            oom_eventfd: -1,
+        };
         if init_only && tsocketfd == -1 {
-            system(&cpath, spec, wfd, daemonize)?;
+            system(&cpath, spec, fds, daemonize, None, &HashMap::new())?;
         } else {
-            fork_final_child(&cpath, spec, wfd, tsocketfd, daemonize)?;
+            readyfd =
+                fork_final_child(&cpath, spec, fds, tsocketfd, daemonize)?;
         }
+    } else {
+        // notify first parent that it can continue
+        debug!("writing zero to pipe to trigger poststart");
+        let data: &[u8] = &[0];
+        write(wfd, data).chain_err(|| "failed to write zero")?;
     }
 
     secure_container(spec, linux)?;
@@ -1300,10 +1947,42 @@ fn run_container(
         close(tsocketfd).chain_err(|| "could not close trigger fd")?;
     }
 
+    // never exec before the monitor of the System is reachable
+    if readyfd != -1 {
+        wait_for_pipe_zero(readyfd, -1)
+            .chain_err(|| "the System failed to start")?;
+    }
+
     do_exec(&spec.process.args[0], &spec.process.args, &spec.process.env)?;
     Ok(Pid::from_raw(-1))
 }
 
+// Adds the variables the Process uses to find its monitor and metrics page
+// to the environment, unless they are set already
+fn with_enya_env(
+    id: &str,
+    spec: &Spec,
+    config: &Config,
+    pagefd: RawFd,
+) -> Spec {
+    let mut vars = vec![
+        (api::env::MONITOR_PATH, config.monitor_path()),
+        (api::env::MONITOR_SOCKET, config.monitor_socket()),
+        (api::env::CONTAINER_ID, id.to_string()),
+    ];
+    if pagefd != -1 {
+        vars.push((api::env::METRICS_FD, pagefd.to_string()));
+    }
+    let mut spec = spec.clone();
+    for &(key, ref value) in vars.iter() {
+        let prefix = format!("{}=", key);
+        if !spec.process.env.iter().any(|e| e.starts_with(&prefix)) {
+            spec.process.env.push(format!("{}{}", prefix, value));
+        }
+    }
+    spec
+}
+
 fn secure_container(spec: &Spec, linux: &Linux) -> Result<()> {
     // NOTE: if we want init to pass signals to other processes, we may want
     //       to hold on to cap kill until after the final fork.
@@ -1420,7 +2099,7 @@ fn fork_first(
             if !init_only {
                 debug!("running prestart hooks");
                 if let Some(ref hooks) = spec.hooks {
-                    let st = state(id, "running", init_pid, &spec.root.path);
+                    let st = state(id, Status::Created, init_pid, Some(spec));
                     for h in &hooks.prestart {
                         execute_hook(h, &st)
                             .chain_err(|| "failed to execute prestart hooks")?;
@@ -1429,7 +2108,7 @@ fn fork_first(
                 wait_for_pipe_zero(rfd, -1)?;
                 debug!("running poststart hooks");
                 if let Some(ref hooks) = spec.hooks {
-                    let st = state(id, "running", init_pid, &spec.root.path);
+                    let st = state(id, Status::Running, init_pid, Some(spec));
                     for h in &hooks.poststart {
                         if let Err(e) = execute_hook(h, &st) {
                             warn!("failed to execute poststart hook: {}", e);
@@ -1448,6 +2127,12 @@ fn fork_first(
             cgroups::enya_remove(&process_cgroup)?;
             let system_cgroup = format!("{}/{}", &cpath, ENYA_SYSTEM_CGROUP);
             cgroups::enya_remove(&system_cgroup)?;
+            if let Ok(config) = Config::from_spec(spec) {
+                for group in &config.processes {
+                    let group_cgroup = format!("{}/{}", &cpath, group.name);
+                    cgroups::enya_remove(&group_cgroup)?;
+                }
+            }
             cgroups::remove(cpath)?;
             exit(exit_code as i8, sig)?;
         }
@@ -1485,26 +2170,28 @@ fn fork_enter_pid(init: bool, daemonize: bool) -> Result<()> {
     Ok(())
 }
 
+// Forks the Process, which continues with the returned fd that becomes
+// readable once the System is ready. The System never returns.
 fn fork_final_child(
     cgroups_path: &str,
     spec: &Spec,
-    wfd: RawFd,
+    fds: SystemFds,
     tfd: RawFd,
     daemonize: bool,
-) -> Result<()> {
+) -> Result<RawFd> {
     let ccond = Cond::new().chain_err(|| "failed to create cond")?;
     let (rfd, wfd) =
         pipe2(OFlag::O_CLOEXEC).chain_err(|| "failed to create pipe")?;
     // fork again so child becomes pid 2
     match fork()? {
         ForkResult::Child => {
-            close(rfd).chain_err(|| "could not close rfd")?;
+            close(wfd).chain_err(|| "could not close wfd")?;
             ccond.notify().chain_err(|| "failed to notify parent")?;
             // child continues on
-            Ok(())
+            Ok(rfd)
         }
-        ForkResult::Parent { .. } => {
-            close(wfd).chain_err(|| "could not close rfd")?;
+        ForkResult::Parent { child } => {
+            close(rfd).chain_err(|| "could not close rfd")?;
             ccond.wait().chain_err(|| "failed to wait for child")?;
 
             let cgroup_mount = spec.mounts.iter().find(|m| m.typ == "cgroup");
@@ -1513,7 +2200,8 @@ fn fork_final_child(
                 .expect("Could not locate cgroups mount path")
                 .destination;
 
-            final_enya_setup(&cgroup_mount_path, spec)?;
+            let (cgroup_fds, memfds, oom_eventfd) =
+                final_enya_setup(&cgroup_mount_path, spec)?;
             secure_container(
                 spec,
                 spec.linux.as_ref().expect("Failed to unwrap Linux in Spec"),
@@ -1523,13 +2211,33 @@ fn fork_final_child(
                 close(tfd).chain_err(|| "could not close trigger fd")?;
             }
 
-            system(&cgroup_mount_path, spec, wfd, daemonize)?;
-            Ok(())
+            system(
+                &cgroup_mount_path,
+                spec,
+                SystemFds {
+                    readyfd: wfd,
+                    process_memfd: memfds.0,
+                    system_memfd: memfds.1,
+                    oom_eventfd,
+                    ..fds
+                },
+                daemonize,
+                Some(child),
+                &cgroup_fds,
+            )?;
+            Ok(-1)
         }
     }
 }
 
-fn final_enya_setup(cgroups_path: &str, spec: &Spec) -> Result<()> {
+// Returns the fds of the cgroups the System moves processes into, the
+// memory cgroup dirs of the process and system cgroups if the System moves
+// memory between them, and the OOM eventfd of the process cgroup if the
+// System handles its OOMs
+fn final_enya_setup(
+    cgroups_path: &str,
+    spec: &Spec,
+) -> Result<(HashMap<String, Vec<RawFd>>, (RawFd, RawFd), RawFd)> {
     let system_pid: &str = "0"; // meaning this process
     cgroups::move_enya(cgroups_path, system_pid, ENYA_SYSTEM_CGROUP)?;
 
@@ -1537,101 +2245,230 @@ fn final_enya_setup(cgroups_path: &str, spec: &Spec) -> Result<()> {
     let process_pid: &str = "2";
     cgroups::move_enya(cgroups_path, process_pid, ENYA_PROCESS_CGROUP)?;
 
-    let share_check = (ENYA_SYSTEM_SHARE + ENYA_PROCESS_SHARE) as u32;
-    assert_eq!(share_check * 100, 100);
+    let config =
+        Config::from_spec(spec).chain_err(|| "invalid enya annotations")?;
+
+    // every enya cgroup gets its share of the container resources
+    let mut shares = vec![
+        (ENYA_SYSTEM_CGROUP, config.system_share),
+        (ENYA_PROCESS_CGROUP, config.process_share),
+    ];
+    for group in &config.processes {
+        shares.push((group.name.as_str(), group.share));
+    }
 
     if let Some(ref resources) = &spec.clone().linux.unwrap().resources {
         if let Some(ref mem) = &resources.memory {
             if let Some(limit) = mem.limit {
                 debug!("Memory limit: {}", limit);
                 let mem_limit_file = "memory.limit_in_bytes";
-
-                // System
-                let system_limit = (limit as f64 * ENYA_SYSTEM_SHARE) as u64;
-                let system_limit_str: &str = &system_limit.to_string();
-                let sys_mem_dir =
-                    format!("{}/memory/{}", cgroups_path, ENYA_SYSTEM_CGROUP);
-                cgroups::write_file(
-                    &sys_mem_dir,
-                    mem_limit_file,
-                    system_limit_str,
-                )?;
-
-                // Process
-                let process_limit = (limit as f64 * ENYA_PROCESS_SHARE) as u64;
-                let process_limit_str: &str = &process_limit.to_string();
-                let process_mem_dir =
-                    format!("{}/memory/{}", cgroups_path, ENYA_PROCESS_CGROUP);
-                cgroups::write_file(
-                    &process_mem_dir,
-                    mem_limit_file,
-                    process_limit_str,
-                )?;
+                for &(cgroup, share) in &shares {
+                    let mem_dir = format!("{}/memory/{}", cgroups_path, cgroup);
+                    let cgroup_limit = (limit as f64 * share) as u64;
+                    cgroups::write_file(
+                        &mem_dir,
+                        mem_limit_file,
+                        &cgroup_limit.to_string(),
+                    )?;
+                }
             }
         }
 
         if let Some(ref cpu) = &resources.cpu {
-            if let Some(shares) = cpu.shares {
-                debug!("CPU shares: {}", shares);
+            if let Some(shares_total) = cpu.shares {
+                debug!("CPU shares: {}", shares_total);
                 let cpu_shares_file = "cpu.shares";
-
-                // System
-                let sys_share = (shares as f64 * ENYA_SYSTEM_SHARE) as u64;
-                let sys_share_str: &str = &sys_share.to_string();
-                let sys_shares_dir =
-                    format!("{}/cpu/{}", cgroups_path, ENYA_SYSTEM_CGROUP);
-                cgroups::write_file(
-                    &sys_shares_dir,
-                    cpu_shares_file,
-                    sys_share_str,
-                )?;
-
-                // Process
-                let process_share = (shares as f64 * ENYA_PROCESS_SHARE) as u64;
-                let process_share_str: &str = &process_share.to_string();
-                let process_shares_dir =
-                    format!("{}/cpu/{}", cgroups_path, ENYA_PROCESS_CGROUP);
-                cgroups::write_file(
-                    &process_shares_dir,
-                    cpu_shares_file,
-                    process_share_str,
-                )?;
+                for &(cgroup, share) in &shares {
+                    let shares_dir = format!("{}/cpu/{}", cgroups_path, cgroup);
+                    let cgroup_shares = (shares_total as f64 * share) as u64;
+                    cgroups::write_file(
+                        &shares_dir,
+                        cpu_shares_file,
+                        &cgroup_shares.to_string(),
+                    )?;
+                }
             }
         }
     }
 
+    // The System needs to move restarted processes into the process
+    // cgroup and launch the process groups into theirs, after the cgroups
+    // mount is read-only
+    let mut cgroup_fds = HashMap::new();
+    if config.restart_policy != RestartPolicy::Never {
+        let fds =
+            cgroups::open_enya_detached(cgroups_path, ENYA_PROCESS_CGROUP)?;
+        cgroup_fds.insert(ENYA_PROCESS_CGROUP.to_string(), fds);
+    }
+    for group in &config.processes {
+        let fds = cgroups::open_enya_detached(cgroups_path, &group.name)?;
+        cgroup_fds.insert(group.name.clone(), fds);
+    }
+    let mut memfds = (-1, -1);
+    if config.moves_memory() {
+        let open = |name| cgroups::open_detached(cgroups_path, "memory", name);
+        memfds = (open(ENYA_PROCESS_CGROUP)?, open(ENYA_SYSTEM_CGROUP)?);
+    }
+    let mut oom_eventfd = -1;
+    if config.oom_policy != OomPolicy::Kernel {
+        oom_eventfd = cgroups::oom_eventfd(cgroups_path, ENYA_PROCESS_CGROUP)?;
+    }
+
     // Return the cgroups mount to read-only
     mounts::enya_remount(spec)?;
-    Ok(())
+    Ok((cgroup_fds, memfds, oom_eventfd))
+}
+
+fn some_fd(fd: RawFd) -> Option<RawFd> {
+    if fd != -1 {
+        Some(fd)
+    } else {
+        None
+    }
+}
+
+// fds through which the System reports that it is ready and how the
+// container ended
+#[derive(Clone, Copy)]
+struct SystemFds {
+    // pipe to the first parent, which runs the poststart hooks once the
+    // System is ready and raises the signal that killed the Process
+    wfd: RawFd,
+    exitfd: RawFd,
+    metricsfd: RawFd,
+    // pipe to the Process, which execs once the System is ready
+    readyfd: RawFd,
+    // memfd of the metrics page
+    pagefd: RawFd,
+    // record of the OOMs in the state dir
+    oomfd: RawFd,
+    // memory cgroup dirs of the process and system cgroups
+    process_memfd: RawFd,
+    system_memfd: RawFd,
+    oom_eventfd: RawFd,
 }
 
 fn system(
     cgroups_path: &str,
     spec: &Spec,
-    wfd: RawFd,
+    fds: SystemFds,
     daemonize: bool,
+    process: Option<Pid>,
+    cgroup_fds: &HashMap<String, Vec<RawFd>>,
 ) -> Result<()> {
-    if daemonize {
-        close(wfd).chain_err(|| "could not close wfd")?;
-    }
+    let config =
+        Config::from_spec(spec).chain_err(|| "invalid enya annotations")?;
+    let system_fds = Fds {
+        page: some_fd(fds.pagefd),
+        process_memory: some_fd(fds.process_memfd),
+        system_memory: some_fd(fds.system_memfd),
+        oom_events: some_fd(fds.oom_eventfd),
+        oom_record: some_fd(fds.oomfd),
+    };
+    let handle =
+        System::new(spec.clone(), Some(cgroups_path.to_string()), system_fds)
+            .and_then(|system| system.start())
+            .chain_err(|| "failed to start the System")?;
 
-    match System::new(spec.clone(), Some(cgroups_path.to_string())) {
-        Ok(system) => system.start(),
-        Err(e) => {
-            error!("{:?}", e);
-            std::process::exit(-1)
-        }
+    // the monitor is reachable, let the Process exec and the first parent
+    // continue
+    let data: &[u8] = &[0];
+    if fds.readyfd != -1 {
+        write(fds.readyfd, data).chain_err(|| "failed to write zero")?;
+        close(fds.readyfd).chain_err(|| "could not close readyfd")?;
+    }
+    debug!("writing zero to pipe to trigger poststart");
+    write(fds.wfd, data).chain_err(|| "failed to write zero")?;
+    if daemonize {
+        close(fds.wfd).chain_err(|| "could not close wfd")?;
     }
 
     let s = SigSet::all();
     s.thread_block()?;
+    // without a Process to supervise, the first child to exit ends the
+    // container
+    let supervise = process.is_some();
+    let mut process = process;
+    let mut restarts = 0;
+    let mut stopping = false;
+    let mut last_code = 0;
+    // launch the process groups next to the Process
+    let mut groups = HashMap::new();
+    for group in &config.processes {
+        if let Some(fds) = cgroup_fds.get(&group.name) {
+            let env = group.env_with(&spec.process.env);
+            let cwd = group.cwd.as_ref().map(String::as_str);
+            let pid = spawn_process(&group.args, &env, cwd, fds)?;
+            info!("started process group {} as {}", group.name, pid);
+            groups.insert(pid, group.name.clone());
+        }
+    }
+    // set while waiting to restart the Process
+    let mut restart_at: Option<Instant> = None;
+    // set while the Process is given time to stop
+    let mut kill_at: Option<Instant> = None;
     loop {
-        let signal = s.wait()?;
+        let deadline = match (restart_at, kill_at) {
+            (Some(r), Some(k)) => Some(std::cmp::min(r, k)),
+            (r, k) => r.or(k),
+        };
+        let timeout = deadline.map(|t| {
+            let now = Instant::now();
+            if t > now {
+                t - now
+            } else {
+                Duration::from_millis(0)
+            }
+        });
+        let (signal, flags) = match signals::wait_for_signal_flags(&s, timeout)?
+        {
+            Some(next) => next,
+            None => {
+                let now = Instant::now();
+                if kill_at.map_or(false, |t| t <= now) {
+                    kill_at = None;
+                    warn!("process did not stop in time, killing it");
+                    handle.kill_process();
+                }
+                if restart_at.map_or(false, |t| t <= now) {
+                    restart_at = None;
+                    let fds = cgroup_fds
+                        .get(ENYA_PROCESS_CGROUP)
+                        .map_or(&[][..], |fds| &fds[..]);
+                    let pid = spawn_process(
+                        &spec.process.args,
+                        &spec.process.env,
+                        None,
+                        fds,
+                    )?;
+                    restarts += 1;
+                    info!(
+                        "restarted process as {} ({} restarts)",
+                        pid, restarts
+                    );
+                    handle.process_restarted(pid.into(), restarts);
+                    process = Some(pid);
+                }
+                continue;
+            }
+        };
         if signal == Signal::SIGCHLD {
             debug!("got a sigchld");
+            let status = if supervise {
+                match reap_process(
+                    process.unwrap_or(Pid::from_raw(-1)),
+                    &handle,
+                    &mut groups,
+                )? {
+                    Some(status) => status,
+                    None => continue,
+                }
+            } else {
+                reap_children()?
+            };
             let mut sig = None;
             let code;
-            match reap_children()? {
+            match status {
                 WaitStatus::Exited(_, c) => code = c as i32,
                 WaitStatus::Signaled(_, s, _) => {
                     sig = Some(s);
@@ -1639,23 +2476,262 @@ fn system(
                 }
                 _ => continue,
             };
-            if !daemonize {
-                if let Some(s) = sig {
-                    // raising from pid 1 doesn't work as you would
-                    // expect, so write signal to pipe.
-                    let data: &[u8] = &[s as u8];
-                    write(wfd, data).chain_err(|| "failed to write signal")?;
+            if let Some(pid) = process.take() {
+                let restart = !stopping
+                    && !handle.stop_requested()
+                    && config.should_restart(code, restarts);
+                let signo = sig.map_or(0, |s| s as libc::c_int);
+                handle.process_exited(pid.into(), code, signo, restart);
+                if restart {
+                    let backoff = config.restart_backoff(restarts);
+                    info!(
+                        "process exited with {}, restart in {:?}",
+                        code, backoff
+                    );
+                    restart_at = Some(Instant::now() + backoff);
+                    last_code = code;
+                    continue;
+                }
+            }
+            return exit_system(code, sig, fds, daemonize, handle);
+        }
+        if flags & signals::SYSTEM_ONLY != 0 {
+            debug!("got {:?} for the system only", signal);
+            match signal {
+                // only sent by kill --all, which signals the Process
+                // itself, so it must not be brought back
+                Signal::SIGTERM | Signal::SIGINT | Signal::SIGQUIT => {
+                    stopping = true;
+                    if restart_at.is_some() {
+                        debug!("stopping while waiting for restart");
+                        return exit_system(
+                            last_code, None, fds, daemonize, handle,
+                        );
+                    }
+                }
+                _ => {}
+            }
+            continue;
+        }
+        if config.signals_system {
+            match signal {
+                Signal::SIGUSR1 => {
+                    dump_metrics(&handle);
+                    continue;
+                }
+                Signal::SIGUSR2 => {
+                    info!("log level is now {}", logger::toggle_debug());
+                    continue;
+                }
+                _ => {}
+            }
+        }
+        let forward = match config.forwarded_signal(signal as libc::c_int) {
+            Some(signo) => Signal::from_c_int(signo)?,
+            None => {
+                debug!("ignoring {:?}", signal);
+                continue;
+            }
+        };
+        match signal {
+            Signal::SIGTERM | Signal::SIGINT | Signal::SIGQUIT => {
+                // the Process is asked to stop, don't bring it back
+                stopping = true;
+                if restart_at.is_some() {
+                    debug!("stopping while waiting for restart");
+                    return exit_system(
+                        last_code, None, fds, daemonize, handle,
+                    );
+                }
+                // a timeout given with the signal replaces a running one
+                let timeout = signals::stop_timeout(flags);
+                if kill_at.is_none() || timeout.is_some() {
+                    let timeout = timeout.or_else(|| config.stop_timeout());
+                    kill_at = timeout.map(|t| Instant::now() + t);
+                    if let Some(pid) = process {
+                        let signo = signal as libc::c_int;
+                        handle.process_stopping(pid.into(), signo, timeout);
+                    }
+                }
+            }
+            _ => {}
+        }
+        match config.signals_forward {
+            SignalForward::All => {
+                debug!("passing {:?} on to children", forward);
+                let all = Pid::from_raw(-1);
+                if let Err(e) = signals::signal_process(all, forward) {
+                    warn!("failed to signal children, {}", e);
                 }
-                close(wfd).chain_err(|| "could not close wfd")?;
             }
-            debug!("all children terminated, exiting with {}", code);
-            std::process::exit(code)
+            SignalForward::Process => {
+                debug!("passing {:?} on to the process", forward);
+                handle.signal_process(forward as libc::c_int);
+            }
         }
-        debug!("passing {:?} on to children", signal);
-        if let Err(e) = signals::signal_process(Pid::from_raw(-1), signal) {
-            warn!("failed to signal children, {}", e);
+    }
+}
+
+fn dump_metrics(handle: &SystemHandle) {
+    let report = match handle.report() {
+        Some(report) => report,
+        None => {
+            warn!("no metrics from the monitor");
+            return;
+        }
+    };
+    match serde_json::to_string(&report::Stats::from(&report)) {
+        Ok(stats) => info!("metrics: {}", stats),
+        Err(e) => warn!("failed to serialize metrics: {}", e),
+    }
+}
+
+fn exit_system(
+    code: i32,
+    sig: Option<Signal>,
+    fds: SystemFds,
+    daemonize: bool,
+    handle: SystemHandle,
+) -> Result<()> {
+    if fds.exitfd != -1 {
+        if let Err(e) = state::write_exit_status(fds.exitfd, code) {
+            warn!("{}", e);
+        }
+    }
+    // subscribers get the final report before the System goes away
+    match handle.final_report() {
+        Some(ref report) if fds.metricsfd != -1 => {
+            let stats = report::Stats::from(report);
+            let res = serde_json::to_vec(&stats)
+                .chain_err(|| "failed to serialize final metrics")
+                .and_then(|data| {
+                    state::write_final_metrics(fds.metricsfd, &data)
+                });
+            if let Err(e) = res {
+                warn!("{}", e);
+            }
+        }
+        Some(_) => {}
+        None => warn!("no final metrics from the monitor"),
+    }
+    handle.shutdown();
+    if !daemonize {
+        if let Some(s) = sig {
+            // raising from pid 1 doesn't work as you would
+            // expect, so write signal to pipe.
+            let data: &[u8] = &[s as u8];
+            write(fds.wfd, data).chain_err(|| "failed to write signal")?;
         }
+        close(fds.wfd).chain_err(|| "could not close wfd")?;
     }
+    debug!("process terminated, exiting with {}", code);
+    std::process::exit(code)
+}
+
+// exit codes of a spawned child that could not exec
+const SPAWN_CGROUP_FAILED: i32 = 125;
+const SPAWN_CWD_FAILED: i32 = 126;
+const SPAWN_EXEC_FAILED: i32 = 127;
+
+// Forks and execs `args` in `cwd`, in the cgroups opened by
+// open_enya_detached.
+// The System is multithreaded, so everything the child needs is prepared
+// before the fork and the child only makes async-signal-safe calls: a lock
+// held by another thread, e.g. of the logger or allocator, would never be
+// released in the child.
+fn spawn_process(
+    args: &[String],
+    env: &[String],
+    cwd: Option<&str>,
+    cgroup_fds: &[RawFd],
+) -> Result<Pid> {
+    let path = CString::new(find_executable(&args[0], env))
+        .chain_err(|| format!("invalid path {}", args[0]))?;
+    let cwd = match cwd {
+        Some(cwd) => Some(
+            CString::new(cwd).chain_err(|| format!("invalid cwd {}", cwd))?,
+        ),
+        None => None,
+    };
+    let args = to_cstrings(args)?;
+    let env = to_cstrings(env)?;
+    let argv = null_terminated(&args);
+    let envp = null_terminated(&env);
+    let all = SigSet::all();
+    match fork()? {
+        ForkResult::Child => unsafe {
+            for &dirfd in cgroup_fds {
+                let procs = b"cgroup.procs\0".as_ptr() as *const libc::c_char;
+                let fd = libc::openat(
+                    dirfd,
+                    procs,
+                    libc::O_WRONLY | libc::O_CLOEXEC,
+                );
+                if fd < 0 {
+                    libc::_exit(SPAWN_CGROUP_FAILED);
+                }
+                let pid = b"0".as_ptr() as *const libc::c_void;
+                if libc::write(fd, pid, 1) != 1 {
+                    libc::_exit(SPAWN_CGROUP_FAILED);
+                }
+                libc::close(fd);
+            }
+            if let Some(ref cwd) = cwd {
+                if libc::chdir(cwd.as_ptr()) < 0 {
+                    libc::_exit(SPAWN_CWD_FAILED);
+                }
+            }
+            // the mask is inherited over exec, so undo the blocking done
+            // by the System
+            libc::sigprocmask(
+                libc::SIG_UNBLOCK,
+                all.as_ref(),
+                std::ptr::null_mut(),
+            );
+            libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+            libc::_exit(SPAWN_EXEC_FAILED)
+        },
+        ForkResult::Parent { child } => Ok(child),
+    }
+}
+
+// Resolves `name` through the PATH of `env`, like execvp would with that
+// environment. Falls back to `name` if nothing is found, exec then fails.
+fn find_executable(name: &str, env: &[String]) -> String {
+    if name.contains('/') {
+        return name.to_string();
+    }
+    let path = env
+        .iter()
+        .find(|e| e.starts_with("PATH="))
+        .map_or("/usr/local/bin:/usr/bin:/bin", |e| &e["PATH=".len()..]);
+    for dir in path.split(':').filter(|d| !d.is_empty()) {
+        let candidate = format!("{}/{}", dir, name);
+        let executable =
+            nix::unistd::access(&*candidate, nix::unistd::AccessFlags::X_OK);
+        if executable.is_ok() {
+            return candidate;
+        }
+    }
+    name.to_string()
+}
+
+fn to_cstrings(strings: &[String]) -> Result<Vec<CString>> {
+    strings
+        .iter()
+        .map(|s| {
+            CString::new(s.as_str()).chain_err(|| format!("invalid {:?}", s))
+        })
+        .collect()
+}
+
+// argv/envp layout for execve
+fn null_terminated(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
 }
 
 fn do_exec(path: &str, args: &[String], env: &[String]) -> Result<()> {
@@ -1884,6 +2960,51 @@ fn reap_children() -> Result<(WaitStatus)> {
     Ok(result)
 }
 
+// Reaps all exited children. Returns the status of `pid`, if it was
+// among them, the others are handed to the System.
+fn reap_process(
+    pid: Pid,
+    handle: &SystemHandle,
+    groups: &mut HashMap<Pid, String>,
+) -> Result<Option<WaitStatus>> {
+    let mut result = None;
+    loop {
+        match waitpid(Pid::from_raw(-1), Some(WaitPidFlag::WNOHANG)) {
+            Err(e) => {
+                if e != ::nix::Error::Sys(Errno::ECHILD) {
+                    return Err(e).chain_err(|| "could not waitpid")?;
+                }
+                break;
+            }
+            Ok(WaitStatus::StillAlive) => break,
+            Ok(s) => match s {
+                WaitStatus::Exited(p, _) | WaitStatus::Signaled(p, _, _)
+                    if p == pid =>
+                {
+                    result = Some(s)
+                }
+                WaitStatus::Exited(p, c) if groups.contains_key(&p) => {
+                    let group = groups.remove(&p).unwrap_or_default();
+                    warn!("process group {} exited with {}", group, c);
+                    handle.group_exited(&group, p.into(), c, 0);
+                }
+                WaitStatus::Signaled(p, sig, _) if groups.contains_key(&p) => {
+                    let group = groups.remove(&p).unwrap_or_default();
+                    warn!("process group {} was killed by {:?}", group, sig);
+                    let signo = sig as libc::c_int;
+                    handle.group_exited(&group, p.into(), 128 + signo, signo);
+                }
+                WaitStatus::Exited(p, c) => handle.child_reaped(p.into(), c),
+                WaitStatus::Signaled(p, sig, _) => {
+                    handle.child_reaped(p.into(), 128 + sig as libc::c_int)
+                }
+                _ => debug!("reaped {:?}", s),
+            },
+        }
+    }
+    Ok(result)
+}
+
 fn setid(uid: Uid, gid: Gid) -> Result<()> {
     // set uid/gid
     if let Err(e) = prctl::set_keep_capabilities(true) {
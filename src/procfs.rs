@@ -0,0 +1,118 @@
+use errors::*;
+use libc;
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+
+pub struct ProcStat {
+    // time spent in user and kernel mode, in clock ticks
+    pub utime: u64,
+    pub stime: u64,
+    // time the process started after boot, in clock ticks
+    pub start_time: u64,
+    // resident set size in pages
+    pub rss: u64,
+}
+
+fn read(path: &str) -> Result<String> {
+    let mut f = File::open(path)?;
+    let mut result = String::new();
+    f.read_to_string(&mut result)?;
+    Ok(result)
+}
+
+pub fn stat(pid: Pid) -> Result<ProcStat> {
+    let path = format!("/proc/{}/stat", pid);
+    let data = read(&path)?;
+    // comm may contain spaces and parentheses, so skip past the last ')'
+    let rest = match data.rfind(')') {
+        Some(i) => &data[i + 1..],
+        None => bail!("malformed {}", path),
+    };
+    // fields are numbered from 3 (state) onwards after comm
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    let field = |n: usize| -> Result<u64> {
+        fields
+            .get(n - 3)
+            .and_then(|f| f.parse::<u64>().ok())
+            .ok_or_else(|| format!("missing field {} in {}", n, path).into())
+    };
+    Ok(ProcStat {
+        utime: field(14)?,
+        stime: field(15)?,
+        start_time: field(22)?,
+        rss: field(24)?,
+    })
+}
+
+/// Returns the real uid of `pid`
+pub fn uid(pid: Pid) -> Result<u32> {
+    let path = format!("/proc/{}/status", pid);
+    for line in read(&path)?.lines() {
+        if line.starts_with("Uid:") {
+            if let Some(uid) = line.split_whitespace().nth(1) {
+                return uid.parse::<u32>().chain_err(|| "invalid uid");
+            }
+        }
+    }
+    bail!("no uid in {}", path);
+}
+
+pub fn cmdline(pid: Pid) -> Result<String> {
+    let args = read(&format!("/proc/{}/cmdline", pid))?;
+    let args = args.trim_right_matches('\0').replace('\0', " ");
+    if !args.is_empty() {
+        return Ok(args);
+    }
+    // kernel threads and zombies have no command line
+    let comm = read(&format!("/proc/{}/comm", pid))?;
+    Ok(format!("[{}]", comm.trim_right()))
+}
+
+/// Looks up the name of `uid` in the passwd file of the root `pid` sees,
+/// falling back to the uid itself.
+pub fn user_name(pid: Pid, uid: u32) -> String {
+    let path = format!("/proc/{}/root/etc/passwd", pid);
+    if let Ok(f) = File::open(path) {
+        for line in BufReader::new(f).lines() {
+            let line = match line {
+                Ok(l) => l,
+                Err(_) => break,
+            };
+            let fields: Vec<&str> = line.split(':').collect();
+            if fields.len() > 2 && fields[2] == uid.to_string() {
+                return fields[0].to_string();
+            }
+        }
+    }
+    uid.to_string()
+}
+
+/// Seconds since the epoch at which the system booted
+pub fn boot_time() -> Result<u64> {
+    for line in read("/proc/stat")?.lines() {
+        if line.starts_with("btime") {
+            if let Some(btime) = line.split_whitespace().nth(1) {
+                return btime.parse::<u64>().chain_err(|| "invalid btime");
+            }
+        }
+    }
+    bail!("no btime in /proc/stat");
+}
+
+/// Seconds since the system booted
+pub fn uptime() -> Result<f64> {
+    let data = read("/proc/uptime")?;
+    match data.split_whitespace().next().map(|u| u.parse::<f64>()) {
+        Some(Ok(uptime)) => Ok(uptime),
+        _ => bail!("malformed /proc/uptime"),
+    }
+}
+
+pub fn clock_ticks() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_CLK_TCK) as u64 }
+}
+
+pub fn page_size() -> u64 {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
+}
@@ -0,0 +1,255 @@
+// JSON representation of System monitor reports, loosely following
+// the output of `runc events`
+use api::{
+    CustomMetric_Kind, Health_Status, MetricReport, ProcessEvent,
+    ProcessEvent_oneof_event,
+};
+use std::collections::HashMap;
+
+#[derive(Serialize, Debug)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub typ: String,
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Stats>,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub fields: HashMap<String, String>,
+}
+
+impl Event {
+    pub fn new(typ: &str, id: &str) -> Event {
+        Event {
+            typ: typ.to_string(),
+            id: id.to_string(),
+            data: None,
+            fields: HashMap::new(),
+        }
+    }
+
+    pub fn stats(id: &str, report: &MetricReport) -> Event {
+        let mut event = Event::new("stats", id);
+        event.data = Some(Stats::from(report));
+        event
+    }
+
+    /// "exit", "restart", "stopping", "oom" or "oom-predicted" event of the
+    /// Process supervised by the System
+    pub fn process(id: &str, event: &ProcessEvent) -> Event {
+        match event.event {
+            Some(ProcessEvent_oneof_event::exited(ref e)) => {
+                let event = Event::new("exit", id)
+                    .with("pid", &e.get_pid().to_string())
+                    .with("exitCode", &e.get_exit_code().to_string())
+                    .with("signal", &e.get_signal().to_string())
+                    .with("restarting", &e.get_restarting().to_string());
+                if e.get_group().is_empty() {
+                    event
+                } else {
+                    event.with("group", e.get_group())
+                }
+            }
+            Some(ProcessEvent_oneof_event::restarted(ref r)) => {
+                Event::new("restart", id)
+                    .with("pid", &r.get_pid().to_string())
+                    .with("restarts", &r.get_restarts().to_string())
+            }
+            Some(ProcessEvent_oneof_event::stopping(ref s)) => {
+                Event::new("stopping", id)
+                    .with("pid", &s.get_pid().to_string())
+                    .with("signal", &s.get_signal().to_string())
+                    .with("timeoutMs", &s.get_timeout_ms().to_string())
+            }
+            Some(ProcessEvent_oneof_event::oom(ref o)) => {
+                let event = Event::new("oom", id)
+                    .with("action", o.get_action())
+                    .with("usage", &o.get_usage().to_string())
+                    .with("limit", &o.get_limit().to_string());
+                if o.get_error().is_empty() {
+                    event
+                } else {
+                    event.with("error", o.get_error())
+                }
+            }
+            Some(ProcessEvent_oneof_event::oom_predicted(ref p)) => {
+                Event::new("oom-predicted", id)
+                    .with("seconds", &format!("{:.0}", p.get_seconds()))
+                    .with("growth", &format!("{:.0}", p.get_growth()))
+                    .with("workingSet", &p.get_working_set().to_string())
+                    .with("limit", &p.get_limit().to_string())
+            }
+            None => Event::new("process", id),
+        }
+    }
+
+    pub fn with(mut self, key: &str, value: &str) -> Event {
+        self.fields.insert(key.to_string(), value.to_string());
+        self
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct Stats {
+    pub cgroup: String,
+    pub memory: Memory,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cpu: Option<Cpu>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<Network>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub io: Option<Io>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub health: Option<Health>,
+    // the additional process groups
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<Stats>,
+    // metrics pushed by the Process
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub custom: HashMap<String, Custom>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub predicted_oom_seconds: Option<f64>,
+    // 0 for the process groups
+    #[serde(skip_serializing_if = "is_zero")]
+    pub interval_ms: u64,
+}
+
+fn is_zero(n: &u64) -> bool {
+    *n == 0
+}
+
+#[derive(Serialize, Debug)]
+pub struct Memory {
+    pub usage: u64,
+    pub limit: u64,
+    pub working_set: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Cpu {
+    pub total: u64,
+    pub system: u64,
+    pub percent: f64,
+    pub avg_1m: f64,
+    pub avg_5m: f64,
+    pub avg_15m: f64,
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Network {
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Io {
+    pub read: u64,
+    pub write: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Health {
+    pub status: String,
+    pub failures: u32,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub last_error: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Custom {
+    pub kind: String,
+    pub value: f64,
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub labels: HashMap<String, String>,
+}
+
+impl<'a> From<&'a MetricReport> for Stats {
+    fn from(report: &MetricReport) -> Stats {
+        let mem = report.get_memory();
+        Stats {
+            cgroup: report.get_id().to_string(),
+            memory: Memory {
+                usage: mem.get_usage(),
+                limit: mem.get_limit(),
+                working_set: mem.get_working_set(),
+            },
+            cpu: if report.has_cpu() {
+                let cpu = report.get_cpu();
+                Some(Cpu {
+                    total: cpu.get_total(),
+                    system: cpu.get_system(),
+                    percent: cpu.get_percent(),
+                    avg_1m: cpu.get_avg_1m(),
+                    avg_5m: cpu.get_avg_5m(),
+                    avg_15m: cpu.get_avg_15m(),
+                    p50: cpu.get_p50(),
+                    p95: cpu.get_p95(),
+                    max: cpu.get_max(),
+                })
+            } else {
+                None
+            },
+            network: if report.has_network() {
+                let net = report.get_network();
+                Some(Network {
+                    tx_bytes: net.get_tx_bytes(),
+                    tx_packets: net.get_tx_packets(),
+                    rx_bytes: net.get_rx_bytes(),
+                    rx_packets: net.get_rx_packets(),
+                })
+            } else {
+                None
+            },
+            io: if report.has_io() {
+                let io = report.get_io();
+                Some(Io {
+                    read: io.get_read(),
+                    write: io.get_write(),
+                })
+            } else {
+                None
+            },
+            health: if report.has_health() {
+                let health = report.get_health();
+                let status = match health.get_status() {
+                    Health_Status::UNKNOWN => "starting",
+                    Health_Status::HEALTHY => "healthy",
+                    Health_Status::UNHEALTHY => "unhealthy",
+                };
+                Some(Health {
+                    status: status.to_string(),
+                    failures: health.get_failures(),
+                    last_error: health.get_last_error().to_string(),
+                })
+            } else {
+                None
+            },
+            groups: report.get_groups().iter().map(Stats::from).collect(),
+            custom: report
+                .get_custom()
+                .iter()
+                .map(|(name, metric)| {
+                    let kind = match metric.get_kind() {
+                        CustomMetric_Kind::GAUGE => "gauge",
+                        CustomMetric_Kind::COUNTER => "counter",
+                    };
+                    let custom = Custom {
+                        kind: kind.to_string(),
+                        value: metric.get_value(),
+                        labels: metric.get_labels().clone(),
+                    };
+                    (name.clone(), custom)
+                })
+                .collect(),
+            predicted_oom_seconds: match report.get_predicted_oom_seconds() {
+                s if s > 0.0 => Some(s),
+                _ => None,
+            },
+            interval_ms: report.get_interval_ms(),
+        }
+    }
+}
@@ -1,8 +1,10 @@
 use errors::*;
-use libc::c_int;
+use libc::{c_int, c_void};
+use nix::errno::Errno;
 use nix::sys::signal::{kill, raise, sigaction};
 use nix::sys::signal::{SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::unistd::Pid;
+use std::time::Duration;
 
 pub fn pass_signals(child_pid: Pid) -> Result<()> {
     unsafe {
@@ -93,6 +95,91 @@ pub fn signal_process<T: Into<Option<Signal>>>(
     Ok(())
 }
 
+// Signals queued by enya carry a value so that the System can tell them
+// apart from signals sent by anyone else.
+const ENYA_SIGVAL: i32 = 0x4000_0000;
+/// The System handles the signal itself instead of forwarding it
+pub const SYSTEM_ONLY: i32 = 0x1;
+/// The signal carries a stop timeout in seconds, see `stop_timeout`
+pub const STOP_TIMEOUT: i32 = 0x2;
+// the timeout takes up the bits between the flags and ENYA_SIGVAL
+const TIMEOUT_SHIFT: u32 = 8;
+const MAX_TIMEOUT_SECS: u64 = 0x3f_ffff;
+
+/// Flags asking the System to kill the Process if it is still running
+/// `timeout` after the signal.
+pub fn timeout_flags(timeout: Duration) -> i32 {
+    let secs = ::std::cmp::min(timeout.as_secs(), MAX_TIMEOUT_SECS) as i32;
+    STOP_TIMEOUT | secs << TIMEOUT_SHIFT
+}
+
+pub fn stop_timeout(flags: i32) -> Option<Duration> {
+    if flags & STOP_TIMEOUT == 0 {
+        return None;
+    }
+    let secs = (flags >> TIMEOUT_SHIFT) as u64 & MAX_TIMEOUT_SECS;
+    Some(Duration::from_secs(secs))
+}
+
+pub fn queue_signal(pid: Pid, signal: Signal, flags: i32) -> Result<()> {
+    let value = libc::sigval {
+        sival_ptr: (ENYA_SIGVAL | flags) as isize as *mut c_void,
+    };
+    let res = unsafe { libc::sigqueue(pid.into(), signal as c_int, value) };
+    Errno::result(res)
+        .chain_err(|| format!("failed to queue signal {:?}", signal))?;
+    Ok(())
+}
+
+// Prefix of siginfo_t for signals sent with sigqueue
+#[repr(C)]
+struct QueueInfo {
+    si_signo: c_int,
+    si_errno: c_int,
+    si_code: c_int,
+    #[cfg(target_pointer_width = "64")]
+    _pad: c_int,
+    si_pid: libc::pid_t,
+    si_uid: libc::uid_t,
+    si_value: *mut c_void,
+}
+
+/// Waits for one of the signals in `set`, at most `timeout` if given.
+/// Returns the signal together with the enya flags it was queued with,
+/// or `None` on timeout.
+pub fn wait_for_signal_flags(
+    set: &SigSet,
+    timeout: Option<Duration>,
+) -> Result<Option<(Signal, i32)>> {
+    let mut info: libc::siginfo_t = unsafe { ::std::mem::zeroed() };
+    let res = match timeout {
+        Some(t) => {
+            let ts = libc::timespec {
+                tv_sec: t.as_secs() as libc::time_t,
+                tv_nsec: t.subsec_nanos() as libc::c_long,
+            };
+            unsafe { libc::sigtimedwait(set.as_ref(), &mut info, &ts) }
+        }
+        None => unsafe { libc::sigwaitinfo(set.as_ref(), &mut info) },
+    };
+    let signo = match Errno::result(res) {
+        Ok(signo) => signo,
+        Err(::nix::Error::Sys(Errno::EAGAIN))
+        | Err(::nix::Error::Sys(Errno::EINTR)) => return Ok(None),
+        Err(e) => return Err(e).chain_err(|| "failed to wait for signal"),
+    };
+    let signal = Signal::from_c_int(signo)?;
+    let queued = unsafe { &*(&info as *const _ as *const QueueInfo) };
+    let mut flags = 0;
+    if queued.si_code == libc::SI_QUEUE {
+        let value = queued.si_value as isize as i32;
+        if value & ENYA_SIGVAL != 0 {
+            flags = value & !ENYA_SIGVAL;
+        }
+    }
+    Ok(Some((signal, flags)))
+}
+
 pub fn raise_for_parent(signal: Signal) -> Result<()> {
     // reset the sigaction for the signal
     if signal != Signal::SIGKILL && signal != Signal::SIGSTOP {
@@ -120,3 +207,49 @@ pub fn wait_for_signal() -> Result<Signal> {
     s.thread_unblock()?;
     Ok(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeout_round_trip() {
+        let secs = |s| Some(Duration::from_secs(s));
+        assert_eq!(
+            stop_timeout(timeout_flags(Duration::from_secs(0))),
+            secs(0)
+        );
+        assert_eq!(
+            stop_timeout(timeout_flags(Duration::from_secs(30))),
+            secs(30)
+        );
+        let max = timeout_flags(Duration::from_secs(MAX_TIMEOUT_SECS));
+        assert_eq!(stop_timeout(max), secs(MAX_TIMEOUT_SECS));
+
+        // longer timeouts are clamped
+        let over = timeout_flags(Duration::from_secs(MAX_TIMEOUT_SECS + 1));
+        assert_eq!(stop_timeout(over), secs(MAX_TIMEOUT_SECS));
+        let huge = timeout_flags(Duration::from_secs(u64::max_value()));
+        assert_eq!(stop_timeout(huge), secs(MAX_TIMEOUT_SECS));
+
+        assert_eq!(stop_timeout(0), None);
+        assert_eq!(stop_timeout(SYSTEM_ONLY), None);
+    }
+
+    #[test]
+    fn timeout_bits() {
+        let max = timeout_flags(Duration::from_secs(MAX_TIMEOUT_SECS));
+        assert_eq!(max & ENYA_SIGVAL, 0);
+        assert_eq!(max & SYSTEM_ONLY, 0);
+        assert!(max > 0);
+
+        // as sent with sigqueue and read back by the System
+        let value = ENYA_SIGVAL | SYSTEM_ONLY | max;
+        let flags = value & !ENYA_SIGVAL;
+        assert_ne!(flags & SYSTEM_ONLY, 0);
+        assert_eq!(
+            stop_timeout(flags),
+            Some(Duration::from_secs(MAX_TIMEOUT_SECS))
+        );
+    }
+}
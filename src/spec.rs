@@ -0,0 +1,299 @@
+// Default config.json for `enya spec`, modelled after image/config.json
+use nix::unistd::{getegid, geteuid};
+use oci::*;
+use system::config::Config;
+
+use std::collections::HashMap;
+
+const DEFAULT_CAPS: &[LinuxCapabilityType] = &[
+    LinuxCapabilityType::CAP_AUDIT_WRITE,
+    LinuxCapabilityType::CAP_KILL,
+    LinuxCapabilityType::CAP_NET_BIND_SERVICE,
+];
+
+// Syscalls that a container has no business making. Everything else is
+// allowed, which keeps the profile usable for the System process as well.
+const DENIED_SYSCALLS: &[&str] = &[
+    "acct",
+    "add_key",
+    "bpf",
+    "clock_adjtime",
+    "clock_settime",
+    "create_module",
+    "delete_module",
+    "finit_module",
+    "get_kernel_syms",
+    "get_mempolicy",
+    "init_module",
+    "ioperm",
+    "iopl",
+    "kcmp",
+    "kexec_file_load",
+    "kexec_load",
+    "keyctl",
+    "lookup_dcookie",
+    "mbind",
+    "mount",
+    "move_pages",
+    "name_to_handle_at",
+    "nfsservctl",
+    "open_by_handle_at",
+    "perf_event_open",
+    "personality",
+    "pivot_root",
+    "process_vm_readv",
+    "process_vm_writev",
+    "ptrace",
+    "query_module",
+    "quotactl",
+    "reboot",
+    "request_key",
+    "set_mempolicy",
+    "setns",
+    "settimeofday",
+    "stime",
+    "swapoff",
+    "swapon",
+    "sysfs",
+    "umount",
+    "umount2",
+    "unshare",
+    "uselib",
+    "userfaultfd",
+    "ustat",
+    "vm86",
+    "vm86old",
+];
+
+fn mount(
+    destination: &str,
+    typ: &str,
+    source: &str,
+    options: &[&str],
+) -> Mount {
+    Mount {
+        destination: destination.to_string(),
+        typ: typ.to_string(),
+        source: source.to_string(),
+        options: options.iter().map(|o| o.to_string()).collect(),
+    }
+}
+
+fn namespace(typ: LinuxNamespaceType) -> LinuxNamespace {
+    LinuxNamespace {
+        typ,
+        path: "".to_string(),
+    }
+}
+
+fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|v| v.to_string()).collect()
+}
+
+fn default_seccomp() -> LinuxSeccomp {
+    LinuxSeccomp {
+        default_action: LinuxSeccompAction::SCMP_ACT_ALLOW,
+        architectures: Vec::new(),
+        syscalls: vec![LinuxSyscall {
+            name: "".to_string(),
+            names: strings(DENIED_SYSCALLS),
+            action: LinuxSeccompAction::SCMP_ACT_ERRNO,
+            args: Vec::new(),
+        }],
+    }
+}
+
+pub fn default_spec(rootless: bool) -> Spec {
+    let caps = DEFAULT_CAPS.to_vec();
+    let mut permitted = caps.clone();
+    // the System sets up traffic control for the container
+    permitted.push(LinuxCapabilityType::CAP_NET_ADMIN);
+
+    let mut mounts = vec![
+        mount("/proc", "proc", "proc", &[]),
+        mount(
+            "/dev",
+            "tmpfs",
+            "tmpfs",
+            &["nosuid", "strictatime", "mode=755", "size=65536k"],
+        ),
+        mount(
+            "/dev/pts",
+            "devpts",
+            "devpts",
+            &[
+                "nosuid",
+                "noexec",
+                "newinstance",
+                "ptmxmode=0666",
+                "mode=0620",
+                "gid=5",
+            ],
+        ),
+        mount(
+            "/dev/shm",
+            "tmpfs",
+            "shm",
+            &["nosuid", "noexec", "nodev", "mode=1777", "size=65536k"],
+        ),
+        mount(
+            "/dev/mqueue",
+            "mqueue",
+            "mqueue",
+            &["nosuid", "noexec", "nodev"],
+        ),
+        mount(
+            "/sys",
+            "sysfs",
+            "sysfs",
+            &["nosuid", "noexec", "nodev", "ro"],
+        ),
+        // NOTE: enya requires the cgroup mount. It is writable while the
+        //       System and Process cgroups are set up and is then
+        //       remounted read-only.
+        mount(
+            "/sys/fs/cgroup",
+            "cgroup",
+            "cgroup",
+            &["nosuid", "noexec", "nodev", "relatime", "ro"],
+        ),
+    ];
+
+    let mut namespaces = vec![
+        namespace(LinuxNamespaceType::pid),
+        namespace(LinuxNamespaceType::network),
+        namespace(LinuxNamespaceType::ipc),
+        namespace(LinuxNamespaceType::uts),
+        namespace(LinuxNamespaceType::mount),
+    ];
+
+    let mut resources = Some(LinuxResources {
+        devices: vec![LinuxDeviceCgroup {
+            allow: false,
+            typ: LinuxDeviceType::a,
+            major: None,
+            minor: None,
+            access: "rwm".to_string(),
+        }],
+        // cpu shares are split between the System and the Process
+        cpu: Some(LinuxCPU {
+            shares: Some(1024),
+            quota: None,
+            period: None,
+            realtime_runtime: None,
+            realtime_period: None,
+            cpus: "".to_string(),
+            mems: "".to_string(),
+        }),
+        ..Default::default()
+    });
+
+    let mut uid_mappings = Vec::new();
+    let mut gid_mappings = Vec::new();
+
+    if rootless {
+        // NOTE: enya still needs a delegated cgroup hierarchy to split
+        //       resources between the System and the Process.
+        namespaces.push(namespace(LinuxNamespaceType::user));
+        uid_mappings.push(LinuxIDMapping {
+            host_id: geteuid().into(),
+            container_id: 0,
+            size: 1,
+        });
+        gid_mappings.push(LinuxIDMapping {
+            host_id: getegid().into(),
+            container_id: 0,
+            size: 1,
+        });
+        for m in &mut mounts {
+            if m.destination == "/dev/pts" {
+                // there is no gid 5 in the user namespace
+                m.options.retain(|o| o != "gid=5");
+            } else if m.destination == "/sys" {
+                // sysfs can't be mounted in a user namespace
+                *m = mount(
+                    "/sys",
+                    "none",
+                    "/sys",
+                    &["rbind", "nosuid", "noexec", "nodev", "ro"],
+                );
+            }
+        }
+        resources = None;
+    }
+
+    Spec {
+        version: "1.0.0".to_string(),
+        platform: None,
+        process: Process {
+            terminal: true,
+            console_size: Default::default(),
+            user: User {
+                uid: 0,
+                gid: 0,
+                additional_gids: Vec::new(),
+                username: "".to_string(),
+            },
+            args: strings(&["sh"]),
+            env: strings(&[
+                "PATH=/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin",
+                "TERM=xterm",
+            ]),
+            cwd: "/".to_string(),
+            capabilities: Some(LinuxCapabilities {
+                bounding: caps.clone(),
+                effective: caps.clone(),
+                inheritable: caps.clone(),
+                permitted,
+                ambient: caps,
+            }),
+            rlimits: vec![LinuxRlimit {
+                typ: LinuxRlimitType::RLIMIT_NOFILE,
+                hard: 1024,
+                soft: 1024,
+            }],
+            no_new_privileges: true,
+            apparmor_profile: "".to_string(),
+            selinux_label: "".to_string(),
+        },
+        root: Root {
+            path: "rootfs".to_string(),
+            readonly: true,
+        },
+        hostname: "enya".to_string(),
+        mounts,
+        hooks: None,
+        annotations: Config::default().annotations(),
+        linux: Some(Linux {
+            uid_mappings,
+            gid_mappings,
+            sysctl: HashMap::new(),
+            resources,
+            cgroups_path: "".to_string(),
+            namespaces,
+            devices: Vec::new(),
+            seccomp: Some(default_seccomp()),
+            rootfs_propagation: "".to_string(),
+            masked_paths: strings(&[
+                "/proc/kcore",
+                "/proc/latency_stats",
+                "/proc/timer_list",
+                "/proc/timer_stats",
+                "/proc/sched_debug",
+                "/sys/firmware",
+                "/proc/scsi",
+            ]),
+            readonly_paths: strings(&[
+                "/proc/asound",
+                "/proc/bus",
+                "/proc/fs",
+                "/proc/irq",
+                "/proc/sys",
+                "/proc/sysrq-trigger",
+            ]),
+            mount_label: "".to_string(),
+        }),
+        solaris: None,
+        windows: None,
+    }
+}
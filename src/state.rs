@@ -0,0 +1,397 @@
+// Lifecycle of a container, persisted in its instance dir
+use chrono::Utc;
+use errors::*;
+use nix::errno::Errno;
+use nix::fcntl::{flock, open, FlockArg, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, write, Pid};
+use procfs;
+use serde_json;
+use std::env::current_dir;
+use std::fs::{metadata, rename, File};
+use std::io::{Read, Write};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicIsize, Ordering, ATOMIC_ISIZE_INIT};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+pub const OCI_VERSION: &str = "1.0.2";
+pub const CREATED_ANNOTATION: &str = "io.enya.created";
+pub const EXIT_STATUS_ANNOTATION: &str = "io.enya.exit-status";
+pub const FINAL_METRICS_ANNOTATION: &str = "io.enya.final-metrics";
+pub const OOM_RECORD_ANNOTATION: &str = "io.enya.oom-record";
+
+const STATE_FILE: &str = "state.json";
+// written by the System, which only holds an fd to it
+const EXIT_FILE: &str = "exit.status";
+// last metrics of the Process, also written by the System
+const METRICS_FILE: &str = "metrics.json";
+// OOMs of the Process handled by the System, one JSON object per line
+const OOM_FILE: &str = "oom.json";
+const LOCK_TIMEOUT_SECS: u64 = 10;
+
+// fd of the instance dir while it is locked by this process
+static LOCK_FD: AtomicIsize = ATOMIC_ISIZE_INIT;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Status {
+    #[serde(rename = "creating")]
+    Creating,
+    #[serde(rename = "created")]
+    Created,
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+impl Status {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Status::Creating => "creating",
+            Status::Created => "created",
+            Status::Running => "running",
+            Status::Stopped => "stopped",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Lifecycle {
+    pub status: Status,
+    // RFC 3339
+    pub created: String,
+    pub bundle: String,
+    // start time of the init process in clock ticks after boot, which
+    // tells it apart from a process that got its pid recycled
+    #[serde(default)]
+    pub init_start_time: u64,
+}
+
+// all functions below must be called in the instance dir
+impl Lifecycle {
+    pub fn new(bundle: &str) -> Lifecycle {
+        Lifecycle {
+            status: Status::Creating,
+            created: Utc::now().to_rfc3339(),
+            bundle: bundle.to_string(),
+            init_start_time: 0,
+        }
+    }
+
+    pub fn load() -> Result<Lifecycle> {
+        let f = File::open(STATE_FILE)
+            .chain_err(|| format!("failed to open {}", STATE_FILE))?;
+        serde_json::from_reader(f)
+            .chain_err(|| format!("failed to parse {}", STATE_FILE))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let data = serde_json::to_vec(self)
+            .chain_err(|| format!("failed to serialize {}", STATE_FILE))?;
+        write_atomic(STATE_FILE, &data)
+    }
+
+    pub fn set_status(status: Status) -> Result<()> {
+        let mut lifecycle = Lifecycle::load()?;
+        lifecycle.status = status;
+        lifecycle.save()
+    }
+
+    /// The saved status, or stopped if the instance was created or running
+    /// but its init process `init_pid` is gone
+    pub fn current_status(&self, init_pid: Pid) -> Status {
+        match self.status {
+            Status::Created | Status::Running
+                if liveness(init_pid, self.init_start_time)
+                    != Liveness::Alive =>
+            {
+                Status::Stopped
+            }
+            status => status,
+        }
+    }
+}
+
+/// Opens the exit status file for the System. The fd stays valid after
+/// the System pivots into the rootfs and drops privileges.
+pub fn open_exit_file() -> Result<RawFd> {
+    open_for_system(EXIT_FILE)
+}
+
+/// Opens the file the System writes its final metrics to
+pub fn open_metrics_file() -> Result<RawFd> {
+    open_for_system(METRICS_FILE)
+}
+
+/// Opens the file the System records the OOMs of the Process in
+pub fn open_oom_file() -> Result<RawFd> {
+    open_for_system(OOM_FILE)
+}
+
+fn open_for_system(path: &str) -> Result<RawFd> {
+    let flags =
+        OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC | OFlag::O_CLOEXEC;
+    let fd = open(path, flags, Mode::from_bits_truncate(0o644))
+        .chain_err(|| format!("failed to open {}", path))?;
+    Ok(fd)
+}
+
+pub fn write_exit_status(fd: RawFd, code: i32) -> Result<()> {
+    write(fd, code.to_string().as_bytes())
+        .chain_err(|| "failed to write exit status")?;
+    Ok(())
+}
+
+pub fn write_final_metrics(fd: RawFd, data: &[u8]) -> Result<()> {
+    write(fd, data).chain_err(|| "failed to write final metrics")?;
+    Ok(())
+}
+
+/// Absolute path of the final metrics, if the System wrote them
+pub fn final_metrics() -> Option<String> {
+    written(METRICS_FILE)
+}
+
+/// Absolute path of the OOM record, if the System recorded any
+pub fn oom_record() -> Option<String> {
+    written(OOM_FILE)
+}
+
+fn written(file: &str) -> Option<String> {
+    let meta = metadata(file).ok()?;
+    if meta.len() == 0 {
+        return None;
+    }
+    let path = current_dir().ok()?.join(file);
+    Some(path.to_string_lossy().into_owned())
+}
+
+pub fn exit_status() -> Option<i32> {
+    let mut result = String::new();
+    File::open(EXIT_FILE)
+        .and_then(|mut f| f.read_to_string(&mut result))
+        .ok()?;
+    result.trim().parse::<i32>().ok()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Liveness {
+    Alive,
+    Exited,
+    // the pid now belongs to an unrelated process
+    Recycled,
+}
+
+pub fn liveness(pid: Pid, start_time: u64) -> Liveness {
+    if pid == Pid::from_raw(-1) {
+        return Liveness::Exited;
+    }
+    match procfs::stat(pid) {
+        Err(_) => Liveness::Exited,
+        // instances created by older versions have no start time
+        Ok(ref stat) if start_time != 0 && stat.start_time != start_time => {
+            Liveness::Recycled
+        }
+        Ok(_) => Liveness::Alive,
+    }
+}
+
+/// Replaces `path` with `data`, so that readers never see a partially
+/// written file.
+pub fn write_atomic(path: &str, data: &[u8]) -> Result<()> {
+    let tmp = format!("{}.tmp", path);
+    {
+        let mut f = File::create(&tmp)
+            .chain_err(|| format!("failed to create {}", tmp))?;
+        f.write_all(data)?;
+        f.sync_all()?;
+    }
+    rename(&tmp, path)
+        .chain_err(|| format!("failed to rename {} to {}", tmp, path))?;
+    Ok(())
+}
+
+/// Takes an exclusive lock on the instance dir, which is held until the
+/// process exits. Waits for concurrent operations on the same instance.
+pub fn lock(dir: &str) -> Result<()> {
+    lock_within(dir, Duration::from_secs(LOCK_TIMEOUT_SECS))
+}
+
+fn lock_within(dir: &str, timeout: Duration) -> Result<()> {
+    let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+    let fd = open(dir, flags, Mode::empty())
+        .chain_err(|| format!("failed to open {}", dir))?;
+    let start = Instant::now();
+    loop {
+        match flock(fd, FlockArg::LockExclusiveNonblock) {
+            Ok(()) => break,
+            // EWOULDBLOCK, which is EAGAIN on Linux
+            Err(::nix::Error::Sys(Errno::EAGAIN)) => {
+                if start.elapsed() > timeout {
+                    let _ = close(fd);
+                    bail!("{} is locked by another operation", dir);
+                }
+                sleep(Duration::from_millis(10));
+            }
+            Err(e) => {
+                let _ = close(fd);
+                return Err(e).chain_err(|| format!("failed to lock {}", dir));
+            }
+        }
+    }
+    LOCK_FD.store(fd as isize, Ordering::SeqCst);
+    Ok(())
+}
+
+/// Closes the inherited lock fd in a forked child. The lock itself stays
+/// with the parent, which still holds its copy.
+pub fn close_lock() {
+    let fd = LOCK_FD.swap(0, Ordering::SeqCst);
+    if fd > 0 {
+        let _ = close(fd as RawFd);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env::set_current_dir;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::{self, Command};
+    use std::sync::Mutex;
+
+    lazy_static! {
+        // the state functions work in the current dir
+        static ref CWD: Mutex<()> = Mutex::new(());
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "enya-state-{}-{}",
+            process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // runs `f` in a fresh instance dir
+    fn in_instance_dir<F: FnOnce()>(name: &str, f: F) {
+        let _cwd = CWD.lock().unwrap_or_else(|e| e.into_inner());
+        let dir = temp_dir(name);
+        let old = current_dir().unwrap();
+        set_current_dir(&dir).unwrap();
+        f();
+        set_current_dir(old).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn atomic_write() {
+        in_instance_dir("write", || {
+            write_atomic("pid", b"1234").unwrap();
+            write_atomic("pid", b"56").unwrap();
+            assert_eq!(fs::read_to_string("pid").unwrap(), "56");
+            let files: Vec<_> = fs::read_dir(".")
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect();
+            assert_eq!(files, vec!["pid"]);
+        });
+    }
+
+    #[test]
+    fn locked() {
+        let dir = temp_dir("lock");
+        let dir = dir.to_str().unwrap();
+        lock(dir).unwrap();
+        // flock conflicts between open file descriptions of one process
+        let timeout = Duration::from_millis(50);
+        let err = lock_within(dir, timeout).unwrap_err();
+        assert!(err.to_string().contains("locked by another operation"));
+        close_lock();
+        lock_within(dir, timeout).unwrap();
+        close_lock();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn recycled() {
+        let pid = Pid::from_raw(process::id() as i32);
+        let start_time = procfs::stat(pid).unwrap().start_time;
+        assert_eq!(liveness(pid, start_time), Liveness::Alive);
+        assert_eq!(liveness(pid, start_time + 1), Liveness::Recycled);
+        // no start time recorded
+        assert_eq!(liveness(pid, 0), Liveness::Alive);
+
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let gone = Pid::from_raw(child.id() as i32);
+        assert_eq!(liveness(gone, start_time), Liveness::Exited);
+        assert_eq!(liveness(Pid::from_raw(-1), 0), Liveness::Exited);
+    }
+
+    #[test]
+    fn lifecycle() {
+        in_instance_dir("lifecycle", || {
+            assert!(Lifecycle::load().is_err());
+            let mut lifecycle = Lifecycle::new("/bundle");
+            lifecycle.init_start_time = 42;
+            lifecycle.save().unwrap();
+            let loaded = Lifecycle::load().unwrap();
+            assert_eq!(loaded.status, Status::Creating);
+            assert_eq!(loaded.created, lifecycle.created);
+            assert_eq!(loaded.bundle, "/bundle");
+            assert_eq!(loaded.init_start_time, 42);
+
+            for &status in &[Status::Created, Status::Running, Status::Stopped]
+            {
+                Lifecycle::set_status(status).unwrap();
+                let loaded = Lifecycle::load().unwrap();
+                assert_eq!(loaded.status, status);
+                assert_eq!(loaded.init_start_time, 42);
+            }
+            let data = fs::read_to_string(STATE_FILE).unwrap();
+            assert!(data.contains("\"status\":\"stopped\""));
+        });
+    }
+
+    #[test]
+    fn exit() {
+        in_instance_dir("exit", || {
+            assert_eq!(exit_status(), None);
+            let fd = open_exit_file().unwrap();
+            write_exit_status(fd, 137).unwrap();
+            close(fd).unwrap();
+            assert_eq!(exit_status(), Some(137));
+        });
+    }
+
+    #[test]
+    fn stopped() {
+        let pid = Pid::from_raw(process::id() as i32);
+        let mut lifecycle = Lifecycle::new("/bundle");
+        lifecycle.init_start_time = procfs::stat(pid).unwrap().start_time;
+
+        // before the init process is started
+        assert_eq!(lifecycle.current_status(pid), Status::Creating);
+        for &status in &[Status::Created, Status::Running] {
+            lifecycle.status = status;
+            assert_eq!(lifecycle.current_status(pid), status);
+            let none = Pid::from_raw(-1);
+            assert_eq!(lifecycle.current_status(none), Status::Stopped);
+        }
+
+        let mut child = Command::new("true").spawn().unwrap();
+        child.wait().unwrap();
+        let gone = Pid::from_raw(child.id() as i32);
+        assert_eq!(lifecycle.current_status(gone), Status::Stopped);
+        // the pid of another process
+        lifecycle.init_start_time += 1;
+        assert_eq!(lifecycle.current_status(pid), Status::Stopped);
+        lifecycle.status = Status::Stopped;
+        assert_eq!(lifecycle.current_status(pid), Status::Stopped);
+    }
+}
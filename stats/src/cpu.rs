@@ -1,10 +1,12 @@
-use crate::util;
+use crate::util::{self, StatFile};
 
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
 
 use crate::error::ErrorKind::*;
 use crate::error::*;
+use crate::source::MetricSource;
 use crate::sysconf::*;
 
 lazy_static! {
@@ -15,34 +17,73 @@ lazy_static! {
 const CPUACCT_USAGE: &str = "cpuacct.usage";
 const CPUACCT_USAGE_PERCPU: &str = "cpuacct.usage_percpu";
 const NANO_PER_SEC: u64 = 1_000_000_000;
+const PROC_STAT: &str = "/proc/stat";
+// the aggregated cpu line comes first, the rest of /proc/stat is not read
+const PROC_STAT_HEAD: usize = 256;
+/// Default window of the percentiles
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+// time constants of the load-style averages, in seconds
+const LOAD_WINDOWS: [f64; 3] = [60.0, 300.0, 900.0];
 
 #[derive(Debug)]
 pub struct Cpu {
     cgroups_path: String,
-    total_usage_path: String,
-    per_cpu_path: String,
+    total_usage_file: StatFile,
+    per_cpu_file: StatFile,
+    proc_stat: StatFile,
     pub total_usage: u64,
     pub system_usage: u64,
-    pub avg: f64,
+    /// Usage since the previous collection, in percent of one cpu
+    pub percent: f64,
+    /// Exponentially weighted averages over 1, 5 and 15 minutes, like the
+    /// load average
+    pub avg_1m: f64,
+    pub avg_5m: f64,
+    pub avg_15m: f64,
+    /// Percentiles and maximum of the usage within the window
+    pub p50: f64,
+    pub p95: f64,
+    pub max: f64,
     collections: u64,
+    window: Duration,
+    samples: VecDeque<(Instant, f64)>,
+    // reused for the percentiles
+    sorted: Vec<f64>,
+    last_sample: Option<Instant>,
 }
 
 impl Cpu {
     pub fn new(path: String) -> Cpu {
+        Cpu::with_window(path, DEFAULT_WINDOW)
+    }
+
+    /// Keeps the samples of the last `window` for the percentiles
+    pub fn with_window(path: String, window: Duration) -> Cpu {
         let total_usage_path = path.to_owned() + CPUACCT_USAGE;
         let per_cpu_path = path.to_owned() + CPUACCT_USAGE_PERCPU;
         Cpu {
             cgroups_path: path,
-            total_usage_path,
-            per_cpu_path,
+            total_usage_file: StatFile::new(total_usage_path),
+            per_cpu_file: StatFile::new(per_cpu_path),
+            proc_stat: StatFile::head(PROC_STAT.to_string(), PROC_STAT_HEAD),
             total_usage: 0,
             system_usage: 0,
-            avg: 0.0,
+            percent: 0.0,
+            avg_1m: 0.0,
+            avg_5m: 0.0,
+            avg_15m: 0.0,
+            p50: 0.0,
+            p95: 0.0,
+            max: 0.0,
             collections: 0,
+            window,
+            samples: VecDeque::new(),
+            sorted: Vec::new(),
+            last_sample: None,
         }
     }
     pub fn update(&mut self) {
-        let total_usage = util::read_u64_from(&self.total_usage_path);
+        let total_usage = self.total_usage_file.read_u64();
 
         if let Ok(usage) = total_usage {
             let mut cpu_percent = 0.0;
@@ -52,27 +93,16 @@ impl Cpu {
                 let system_delta = sys as f64 - self.system_usage as f64;
 
                 if cpu_delta > 0.0 && system_delta > 0.0 {
-                    let per_cpu_len =
-                        self.get_per_cpu_usage().map(|v| v.len()).unwrap_or(0);
+                    let per_cpu_len = self.online_cpus().unwrap_or(0);
                     let percent =
                         (cpu_delta / system_delta) * per_cpu_len as f64 * 100.0;
-                    if let Ok(res) = util::fmt_float(percent) {
-                        cpu_percent = res;
-                    }
+                    cpu_percent = util::round2(percent);
                 }
 
                 // NOTE: we skip putting at 0 as the collections
                 //       depend on known total_usage and system_usage data
-                if self.collections == 1 {
-                    self.avg = cpu_percent;
-                } else {
-                    let ema: f64 = (cpu_percent - self.avg)
-                        * (2.0 / (self.collections + 1) as f64)
-                        + self.avg;
-
-                    if let Ok(ema_fmt) = util::fmt_float(ema) {
-                        self.avg = ema_fmt;
-                    }
+                if self.collections > 0 {
+                    self.sample(Instant::now(), cpu_percent);
                 }
 
                 self.collections += 1;
@@ -82,53 +112,103 @@ impl Cpu {
         }
     }
 
-    fn get_system_cpu_usage(&self) -> Result<u64> {
-        match File::open("/proc/stat") {
-            Ok(file) => {
-                let mut reader = BufReader::new(file);
-                let mut line = String::new();
-                let _ = reader
-                    .read_line(&mut line)
-                    .map_err(|e| Error::with_cause(ReadFailed, e));
-
-                let mut fields = line.split_whitespace();
-                let cpu_opt = fields.next();
-
-                if let Some(field) = cpu_opt {
-                    if field == "cpu" {
-                        let count = fields.clone().count();
-                        if count < 8 {
-                            Err(Error::new(CpuParseError))
-                        } else {
-                            let ticks: Result<u64> = fields
-                                .map(|x| {
-                                    x.parse::<u64>().map_err(|e| {
-                                        Error::with_cause(ParseError, e)
-                                    })
-                                })
-                                .sum::<Result<u64>>()
-                                .map(|x| (x * NANO_PER_SEC) / *CLOCK_TICKS);
-                            ticks
-                        }
-                    } else {
-                        Err(Error::new(CpuParseError))
-                    }
-                } else {
-                    Err(Error::new(CpuParseError))
-                }
+    fn sample(&mut self, at: Instant, percent: f64) {
+        self.percent = percent;
+        match self.last_sample {
+            None => {
+                self.avg_1m = percent;
+                self.avg_5m = percent;
+                self.avg_15m = percent;
             }
-            Err(e) => Err(Error::with_cause(ReadFailed, e)),
+            Some(last) => {
+                // the weight depends on the time since the last sample, so
+                // the averages don't change with the collection interval
+                let elapsed = util::duration_secs(at.duration_since(last));
+                decay(&mut self.avg_1m, percent, elapsed, LOAD_WINDOWS[0]);
+                decay(&mut self.avg_5m, percent, elapsed, LOAD_WINDOWS[1]);
+                decay(&mut self.avg_15m, percent, elapsed, LOAD_WINDOWS[2]);
+            }
+        }
+        self.last_sample = Some(at);
+
+        while let Some(&(first, _)) = self.samples.front() {
+            if at.duration_since(first) < self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, percent));
+
+        self.sorted.clear();
+        self.sorted.extend(self.samples.iter().map(|s| s.1));
+        self.sorted
+            .sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+        self.p50 = percentile(&self.sorted, 0.50);
+        self.p95 = percentile(&self.sorted, 0.95);
+        self.max = self.sorted.last().cloned().unwrap_or(0.0);
+    }
+
+    fn get_system_cpu_usage(&mut self) -> Result<u64> {
+        let head = self.proc_stat.read()?;
+        let line = head
+            .split(|&b| b == b'\n')
+            .next()
+            .ok_or_else(|| Error::new(CpuParseError))?;
+
+        let mut fields = util::fields(line);
+        if fields.next() != Some(&b"cpu"[..]) {
+            return Err(Error::new(CpuParseError));
+        }
+        let mut count = 0;
+        let mut ticks: u64 = 0;
+        for field in fields {
+            let value =
+                util::parse_u64(field).ok_or_else(|| Error::new(ParseError))?;
+            ticks += value;
+            count += 1;
+        }
+        if count < 8 {
+            return Err(Error::new(CpuParseError));
         }
+        Ok((ticks * NANO_PER_SEC) / *CLOCK_TICKS)
+    }
+
+    // number of cpus in cpuacct.usage_percpu
+    fn online_cpus(&mut self) -> Result<usize> {
+        let line = self.per_cpu_file.read()?;
+        let mut count = 0;
+        for field in util::fields(line) {
+            util::parse_u64(field).ok_or_else(|| Error::new(ParseError))?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+// moves `avg` towards `value` by the weight `elapsed` seconds have with
+// the time constant `window`
+fn decay(avg: &mut f64, value: f64, elapsed: f64, window: f64) {
+    *avg += (value - *avg) * (1.0 - (-elapsed / window).exp());
+}
+
+// nearest-rank percentile of sorted values
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
     }
+    let rank = (p * sorted.len() as f64).ceil() as usize;
+    sorted[rank.max(1) - 1]
+}
 
-    fn get_per_cpu_usage(&self) -> Result<Vec<u64>> {
-        let line = util::read_string_from(&self.per_cpu_path)?;
-        line.split_whitespace()
-            .map(|x| {
-                x.parse::<u64>()
-                    .map_err(|e| Error::with_cause(ParseError, e))
-            })
-            .collect()
+impl MetricSource for Cpu {
+    fn name(&self) -> &'static str {
+        "cpu"
+    }
+    fn probe(&mut self) -> bool {
+        self.total_usage_file.read().is_ok()
+    }
+    fn update(&mut self) {
+        Cpu::update(self)
     }
 }
 
@@ -139,16 +219,16 @@ mod tests {
 
     #[test]
     fn cpu_usage() {
-        let cpu = Cpu::new(CGROUPS_PATH.to_string());
-        let res = Cpu::get_system_cpu_usage(&cpu);
+        let mut cpu = Cpu::new(CGROUPS_PATH.to_string());
+        let res = Cpu::get_system_cpu_usage(&mut cpu);
         assert!(res.unwrap() > 0);
     }
 
     #[test]
     fn per_cpu() {
-        let cpu = Cpu::new(CGROUPS_PATH.to_string());
-        let res = Cpu::get_per_cpu_usage(&cpu);
-        assert!(res.unwrap().len() > 0);
+        let mut cpu = Cpu::new(CGROUPS_PATH.to_string());
+        let res = Cpu::online_cpus(&mut cpu);
+        assert!(res.unwrap() > 0);
     }
 
     #[test]
@@ -160,6 +240,34 @@ mod tests {
         std::thread::sleep(std::time::Duration::from_millis(200));
         let _ = cpu.update();
         std::thread::sleep(std::time::Duration::from_millis(200));
-        assert!(cpu.avg > 0.0);
+        assert!(cpu.avg_1m > 0.0);
+    }
+
+    #[test]
+    fn load_averages() {
+        let mut cpu = Cpu::new(CGROUPS_PATH.to_string());
+        let start = Instant::now();
+        cpu.sample(start, 0.0);
+        cpu.sample(start + Duration::from_secs(60), 100.0);
+        // one time constant brings the average 63% of the way
+        assert!((cpu.avg_1m - 63.21).abs() < 0.01);
+        assert!(cpu.avg_5m < cpu.avg_1m);
+        assert!(cpu.avg_15m < cpu.avg_5m);
+        assert_eq!(cpu.percent, 100.0);
+    }
+
+    #[test]
+    fn percentiles() {
+        let mut cpu =
+            Cpu::with_window(CGROUPS_PATH.to_string(), Duration::from_secs(20));
+        let start = Instant::now();
+        for i in 0..30 {
+            cpu.sample(start + Duration::from_secs(i), i as f64);
+        }
+        // only the samples of the last 20 seconds, 10 to 29
+        assert_eq!(cpu.samples.len(), 20);
+        assert_eq!(cpu.p50, 19.0);
+        assert_eq!(cpu.p95, 28.0);
+        assert_eq!(cpu.max, 29.0);
     }
 }
@@ -1,88 +1,72 @@
-use std::fs::File;
-use std::io::{BufRead, BufReader};
+use crate::util::{self, StatFile};
 
 use crate::error::ErrorKind::*;
 use crate::error::*;
+use crate::source::MetricSource;
 
 const BLKIO_SERVICE_BYTES: &str = "blkio.io_service_bytes";
+// a few lines per block device
+const BLKIO_CAPACITY: usize = 1024;
 
 #[derive(Debug)]
 pub struct Io {
-    cgroups_path: String,
+    service_bytes: StatFile,
     pub write: u64,
     pub read: u64,
 }
 
 impl Io {
     pub fn new(path: String) -> Io {
+        let service_bytes =
+            StatFile::with_capacity(path + BLKIO_SERVICE_BYTES, BLKIO_CAPACITY);
         Io {
-            cgroups_path: path,
+            service_bytes,
             write: 0,
             read: 0,
         }
     }
 
     pub fn update(&mut self) {
-        let path = &(self.cgroups_path.to_owned() + BLKIO_SERVICE_BYTES);
-        if let Ok((read, write)) = Io::parse_blkio_stat(path) {
-            self.read = read;
-            self.write = write;
+        if let Ok(data) = self.service_bytes.read() {
+            if let Ok((read, write)) = Io::parse_blkio_stat(data) {
+                self.read = read;
+                self.write = write;
+            }
         }
     }
 
-    // Refactor
-    fn parse_blkio_stat(path: &str) -> Result<(u64, u64)> {
-        match File::open(path) {
-            Ok(file) => {
-                let mut reader = BufReader::new(file);
-                let mut line = String::new();
-                let _ = reader
-                    .read_line(&mut line)
-                    .map_err(|e| Error::with_cause(ReadFailed, e));
-
-                let read_vec: Vec<_> = line.split_whitespace().collect();
-                if read_vec.len() > 2 {
-                    if read_vec[1] == "Read" {
-                        let bytes_read = read_vec[2]
-                            .parse::<u64>()
-                            .map_err(|e| Error::with_cause(ParseError, e));
-
-                        line.clear();
-                        let _ = reader
-                            .read_line(&mut line)
-                            .map_err(|e| Error::with_cause(ReadFailed, e));
-
-                        let write_vec: Vec<_> =
-                            line.split_whitespace().collect();
-                        if write_vec.len() > 2 {
-                            if write_vec[1] == "Write" {
-                                let bytes_write =
-                                    write_vec[2].parse::<u64>().map_err(|e| {
-                                        Error::with_cause(ParseError, e)
-                                    });
+    // the first two lines are the `<major>:<minor> Read <bytes>` and
+    // `<major>:<minor> Write <bytes>` of the first device
+    fn parse_blkio_stat(data: &[u8]) -> Result<(u64, u64)> {
+        let mut lines = data.split(|&b| b == b'\n');
+        let read = Io::parse_line(lines.next(), b"Read")?;
+        let write = Io::parse_line(lines.next(), b"Write")?;
+        Ok((read, write))
+    }
 
-                                // Return read, write
-                                bytes_read.and_then(|r| {
-                                    bytes_write.and_then(|w| Ok((r, w)))
-                                })
-                            } else {
-                                Err(Error::new(BlkioParseError))
-                            }
-                        } else {
-                            Err(Error::new(BlkioParseError))
-                        }
-                    } else {
-                        Err(Error::new(BlkioParseError))
-                    }
-                } else {
-                    Err(Error::new(BlkioParseError))
-                }
+    fn parse_line(line: Option<&[u8]>, op: &[u8]) -> Result<u64> {
+        let mut fields = util::fields(line.unwrap_or(&[]));
+        match (fields.next(), fields.next(), fields.next()) {
+            (Some(_), Some(o), Some(bytes)) if o == op => {
+                util::parse_u64(bytes).ok_or_else(|| Error::new(ParseError))
             }
-            Err(e) => Err(Error::with_cause(ReadFailed, e)),
+            _ => Err(Error::new(BlkioParseError)),
         }
     }
 }
 
+impl MetricSource for Io {
+    fn name(&self) -> &'static str {
+        "io"
+    }
+    fn probe(&mut self) -> bool {
+        self.service_bytes.read().is_ok()
+    }
+    fn update(&mut self) {
+        Io::update(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -95,4 +79,11 @@ mod tests {
         assert!(io.read > 0);
         assert!(io.write > 0);
     }
+
+    #[test]
+    fn blkio_parse() {
+        let data = b"8:0 Read 4096\n8:0 Write 8192\n8:0 Sync 0\nTotal 12288\n";
+        assert_eq!(Io::parse_blkio_stat(data).unwrap(), (4096, 8192));
+        assert!(Io::parse_blkio_stat(b"Total 0\n").is_err());
+    }
 }
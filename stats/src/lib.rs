@@ -11,3 +11,4 @@ pub mod cpu;
 pub mod io;
 pub mod memory;
 pub mod network;
+pub mod source;
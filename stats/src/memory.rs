@@ -1,7 +1,19 @@
-use crate::util;
+use crate::source::MetricSource;
+use crate::util::{self, StatFile};
+
+use std::collections::VecDeque;
+use std::time::Instant;
 
 const MEMORY_USAGE: &str = "memory.usage_in_bytes";
 const MEMORY_LIMIT: &str = "memory.limit_in_bytes";
+const MEMORY_STAT: &str = "memory.stat";
+// memory.stat has a few dozen lines
+const STAT_CAPACITY: usize = 4096;
+
+/// Number of working set samples the growth is computed over
+pub const TREND_WINDOW: usize = 60;
+// fewer samples are too noisy for a trend
+const MIN_TREND_SAMPLES: usize = 5;
 
 // LOW: >= 0 <= 30
 // MEDIUM: > 30 <= 60
@@ -21,47 +33,60 @@ pub enum MemoryStatus {
 #[derive(Debug)]
 pub struct Memory {
     cgroups_path: String,
-    usage_path: String,
-    limit_path: String,
+    usage_file: StatFile,
+    limit_file: StatFile,
+    stat_file: StatFile,
     pub usage: u64,
     pub limit: u64,
     pub procentage: f32,
+    /// Usage without the inactive page cache, which the kernel reclaims
+    /// before it runs out of memory
+    pub working_set: u64,
+    samples: VecDeque<(Instant, u64)>,
 }
 
 impl Memory {
     pub fn new(path: String) -> Memory {
         let up = path.to_owned() + MEMORY_USAGE;
         let lp = path.to_owned() + MEMORY_LIMIT;
+        let sp = path.to_owned() + MEMORY_STAT;
         Memory {
             cgroups_path: path,
-            usage_path: up,
-            limit_path: lp,
+            usage_file: StatFile::new(up),
+            limit_file: StatFile::new(lp),
+            stat_file: StatFile::with_capacity(sp, STAT_CAPACITY),
             usage: 0,
             limit: 0,
             procentage: 0.0,
+            working_set: 0,
+            samples: VecDeque::with_capacity(TREND_WINDOW),
         }
     }
     pub fn update(&mut self) -> MemoryStatus {
-        let usage = util::read_u64_from(&self.usage_path);
+        let usage = self.usage_file.read_u64();
         self.usage = usage.unwrap_or(0);
 
         // Perhaps just read it once at start?
         // However, might change if container updates the limit
-        let limit = util::read_u64_from(&self.limit_path);
+        let limit = self.limit_file.read_u64();
         self.limit = limit.unwrap_or(0);
 
         let mut mem_percent: f32 = 0.0;
 
         if self.limit != 0 {
-            let avg = self.usage as f32 / self.limit as f32 * 100.0;
-            let f = format!("{:.2}", avg).parse::<f32>();
-            if let Ok(v) = f {
-                mem_percent = v;
-            }
+            let avg = self.usage as f64 / self.limit as f64 * 100.0;
+            mem_percent = util::round2(avg) as f32;
         }
 
         self.procentage = mem_percent;
 
+        let inactive = self.inactive_file().unwrap_or(0);
+        self.working_set = self.usage.saturating_sub(inactive);
+        if self.usage != 0 {
+            let working_set = self.working_set;
+            self.sample(Instant::now(), working_set);
+        }
+
         let level = self.procentage as u16;
 
         if level <= LOW {
@@ -74,4 +99,112 @@ impl Memory {
             MemoryStatus::Critical
         }
     }
+
+    /// Growth of the working set in bytes per second, a least squares fit
+    /// over the sliding window. `None` until the window has enough samples.
+    pub fn growth(&self) -> Option<f64> {
+        if self.samples.len() < MIN_TREND_SAMPLES {
+            return None;
+        }
+        let first = self.samples[0].0;
+        let n = self.samples.len() as f64;
+        let xs = self
+            .samples
+            .iter()
+            .map(|&(at, _)| util::duration_secs(at.duration_since(first)));
+        let mean_x = xs.clone().sum::<f64>() / n;
+        let mean_y =
+            self.samples.iter().map(|&(_, ws)| ws as f64).sum::<f64>() / n;
+
+        let (mut cov, mut var) = (0.0, 0.0);
+        for (x, &(_, ws)) in xs.zip(self.samples.iter()) {
+            cov += (x - mean_x) * (ws as f64 - mean_y);
+            var += (x - mean_x) * (x - mean_x);
+        }
+        if var == 0.0 {
+            return None;
+        }
+        Some(cov / var)
+    }
+
+    /// Seconds until the working set reaches the limit if it keeps growing
+    /// at the current rate. `None` if it doesn't grow or there is no limit.
+    pub fn predicted_oom_seconds(&self) -> Option<f64> {
+        let growth = self.growth()?;
+        if growth <= 0.0 || self.limit == 0 {
+            return None;
+        }
+        let headroom = self.limit.saturating_sub(self.working_set);
+        Some(headroom as f64 / growth)
+    }
+
+    fn sample(&mut self, at: Instant, working_set: u64) {
+        if self.samples.len() == TREND_WINDOW {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((at, working_set));
+    }
+
+    fn inactive_file(&mut self) -> Option<u64> {
+        let stat = self.stat_file.read().ok()?;
+        util::keyed_u64(stat, "total_inactive_file")
+    }
+}
+
+impl MetricSource for Memory {
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+    fn probe(&mut self) -> bool {
+        self.usage_file.read().is_ok()
+    }
+    fn update(&mut self) {
+        Memory::update(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn with_samples(limit: u64, samples: &[u64]) -> Memory {
+        let mut memory = Memory::new(String::new());
+        memory.limit = limit;
+        let start = Instant::now();
+        for (i, &ws) in samples.iter().enumerate() {
+            memory.sample(start + Duration::from_secs(i as u64), ws);
+            memory.working_set = ws;
+        }
+        memory
+    }
+
+    #[test]
+    fn growth() {
+        assert_eq!(with_samples(1000, &[100, 110, 120]).growth(), None);
+
+        let memory = with_samples(1000, &[100, 110, 120, 130, 140, 150]);
+        assert!((memory.growth().unwrap() - 10.0).abs() < 1e-9);
+        // 850 bytes of headroom at 10 bytes/s
+        let seconds = memory.predicted_oom_seconds().unwrap();
+        assert!((seconds - 85.0).abs() < 1e-9);
+
+        let flat = with_samples(1000, &[500, 500, 500, 500, 500]);
+        assert_eq!(flat.predicted_oom_seconds(), None);
+        let shrinking = with_samples(1000, &[500, 400, 300, 200, 100]);
+        assert_eq!(shrinking.predicted_oom_seconds(), None);
+        let unlimited = with_samples(0, &[100, 110, 120, 130, 140]);
+        assert_eq!(unlimited.predicted_oom_seconds(), None);
+    }
+
+    #[test]
+    fn window() {
+        let samples: Vec<u64> = (0..TREND_WINDOW as u64 * 2)
+            .map(|i| if i < TREND_WINDOW as u64 { 0 } else { i })
+            .collect();
+        let memory = with_samples(u64::max_value(), &samples);
+        assert_eq!(memory.samples.len(), TREND_WINDOW);
+        // the flat first half has been dropped
+        assert!((memory.growth().unwrap() - 1.0).abs() < 1e-9);
+    }
 }
@@ -1,12 +1,13 @@
-use crate::util;
+use crate::source::MetricSource;
+use crate::util::StatFile;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct Network {
     interface: String,
-    tx_bytes_path: String,
-    tx_packets_path: String,
-    rx_bytes_path: String,
-    rx_packets_path: String,
+    tx_bytes_file: StatFile,
+    tx_packets_file: StatFile,
+    rx_bytes_file: StatFile,
+    rx_packets_file: StatFile,
     pub rx_bytes: u64,
     pub rx_packets: u64,
     pub tx_bytes: u64,
@@ -33,10 +34,10 @@ impl Network {
 
         Network {
             interface: iface,
-            tx_bytes_path: tx_b,
-            tx_packets_path: tx_p,
-            rx_bytes_path: rx_b,
-            rx_packets_path: rx_p,
+            tx_bytes_file: StatFile::new(tx_b),
+            tx_packets_file: StatFile::new(tx_p),
+            rx_bytes_file: StatFile::new(rx_b),
+            rx_packets_file: StatFile::new(rx_p),
             rx_bytes: 0,
             rx_packets: 0,
             tx_bytes: 0,
@@ -45,15 +46,22 @@ impl Network {
     }
 
     pub fn update(&mut self) {
-        self.tx_bytes = util::read_u64_from(&self.tx_bytes_path).unwrap_or(0);
-
-        self.tx_packets =
-            util::read_u64_from(&self.tx_packets_path).unwrap_or(0);
-
-        self.rx_bytes = util::read_u64_from(&self.rx_bytes_path).unwrap_or(0);
+        self.tx_bytes = self.tx_bytes_file.read_u64().unwrap_or(0);
+        self.tx_packets = self.tx_packets_file.read_u64().unwrap_or(0);
+        self.rx_bytes = self.rx_bytes_file.read_u64().unwrap_or(0);
+        self.rx_packets = self.rx_packets_file.read_u64().unwrap_or(0);
+    }
+}
 
-        self.rx_packets =
-            util::read_u64_from(&self.rx_packets_path).unwrap_or(0);
+impl MetricSource for Network {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+    fn probe(&mut self) -> bool {
+        self.tx_bytes_file.read().is_ok()
+    }
+    fn update(&mut self) {
+        Network::update(self)
     }
 }
 
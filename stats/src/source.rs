@@ -0,0 +1,38 @@
+//! Common interface of the collectors, so that a consumer can hold a
+//! registry of sources instead of a field per collector.
+
+pub trait MetricSource: Send {
+    /// Name the source is enabled by
+    fn name(&self) -> &'static str;
+    /// Whether the files of the source can be read. Sources that are not
+    /// available, e.g. on kernels without the controller, are left out.
+    fn probe(&mut self) -> bool;
+    fn update(&mut self);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::io::Io;
+    use crate::memory::Memory;
+    use crate::network::Network;
+
+    #[test]
+    fn probe() {
+        let mut lo = Network::new(String::from("lo"));
+        assert_eq!(lo.name(), "network");
+        assert!(lo.probe());
+        lo.update();
+
+        let missing = "/sys/fs/cgroup/enya-missing/";
+        let mut sources: Vec<Box<dyn MetricSource>> = vec![
+            Box::new(Memory::new(missing.to_string())),
+            Box::new(Cpu::new(missing.to_string())),
+            Box::new(Io::new(missing.to_string())),
+        ];
+        let names: Vec<_> = sources.iter().map(|s| s.name()).collect();
+        assert_eq!(names, vec!["memory", "cpu", "io"]);
+        assert!(sources.iter_mut().all(|s| !s.probe()));
+    }
+}
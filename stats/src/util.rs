@@ -1,40 +1,181 @@
+use std::fmt;
 use std::fs::File;
-use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::time::Duration;
 
 use crate::error::ErrorKind::*;
 use crate::error::*;
 
-pub fn read_string_from(path: &str) -> Result<String> {
-    match File::open(path) {
-        Ok(mut f) => {
-            let mut string = String::new();
-            match f.read_to_string(&mut string) {
-                Ok(_) => Ok(string.trim().to_string()),
-                Err(e) => Err(Error::with_cause(ReadFailed, e)),
-            }
+// enough for the single value files, grown on demand for the others
+const DEFAULT_CAPACITY: usize = 64;
+
+/// A cgroup, sysfs or proc file that is opened once and read again from
+/// offset 0 with `pread` on every update. It is reopened only after a
+/// failed read, e.g. if the cgroup was recreated.
+pub struct StatFile {
+    path: String,
+    file: Option<File>,
+    buf: Vec<u8>,
+    // whether the buffer grows to fit the whole file
+    grow: bool,
+}
+
+impl StatFile {
+    pub fn new(path: String) -> StatFile {
+        StatFile::with_capacity(path, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(path: String, capacity: usize) -> StatFile {
+        StatFile {
+            path,
+            file: None,
+            buf: vec![0; capacity],
+            grow: true,
+        }
+    }
+
+    /// Only ever reads the first `len` bytes of the file
+    pub fn head(path: String, len: usize) -> StatFile {
+        StatFile {
+            grow: false,
+            ..StatFile::with_capacity(path, len)
         }
-        Err(e) => Err(Error::with_cause(InvalidPath, e)),
     }
-}
 
-pub fn read_u64_from(path: &str) -> Result<u64> {
-    match File::open(path) {
-        Ok(mut f) => {
-            let mut string = String::new();
-            match f.read_to_string(&mut string) {
-                Ok(_) => string
-                    .trim()
-                    .parse()
-                    .map_err(|e| Error::with_cause(ParseError, e)),
-                Err(e) => Err(Error::with_cause(ReadFailed, e)),
+    /// Current contents of the file. The buffer is reused, so nothing is
+    /// allocated unless the file outgrew it.
+    pub fn read(&mut self) -> Result<&[u8]> {
+        let len = match self.read_at_start() {
+            Ok(len) => len,
+            Err(_) => {
+                // the file may be stale, try a fresh one
+                self.file = None;
+                self.read_at_start()?
             }
+        };
+        Ok(&self.buf[..len])
+    }
+
+    /// Parses the file as a single unsigned number
+    pub fn read_u64(&mut self) -> Result<u64> {
+        let data = self.read()?;
+        parse_u64(data).ok_or_else(|| Error::new(ParseError))
+    }
+
+    fn read_at_start(&mut self) -> Result<usize> {
+        if self.file.is_none() {
+            let file = File::open(&self.path)
+                .map_err(|e| Error::with_cause(InvalidPath, e))?;
+            self.file = Some(file);
+        }
+        let file = self.file.as_ref().unwrap();
+        loop {
+            let n = file
+                .read_at(&mut self.buf, 0)
+                .map_err(|e| Error::with_cause(ReadFailed, e))?;
+            // pseudo files are read in one go, a full buffer may have
+            // been cut short
+            if n < self.buf.len() || !self.grow {
+                return Ok(n);
+            }
+            let len = self.buf.len() * 2;
+            self.buf.resize(len, 0);
         }
-        Err(e) => Err(Error::with_cause(InvalidPath, e)),
     }
 }
 
-pub fn fmt_float(f: f64) -> Result<f64> {
-    format!("{:.2}", f)
-        .parse::<f64>()
-        .map_err(|_| Error::new(ParseError))
+impl fmt::Debug for StatFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StatFile")
+            .field("path", &self.path)
+            .field("open", &self.file.is_some())
+            .finish()
+    }
+}
+
+/// Whitespace separated fields of `data`
+pub fn fields(data: &[u8]) -> impl Iterator<Item = &[u8]> {
+    data.split(u8::is_ascii_whitespace)
+        .filter(|field| !field.is_empty())
+}
+
+/// Parses an unsigned number, ignoring surrounding whitespace
+pub fn parse_u64(data: &[u8]) -> Option<u64> {
+    let mut fields = fields(data);
+    let digits = fields.next()?;
+    if fields.next().is_some() {
+        return None;
+    }
+    digits.iter().try_fold(0u64, |n, &b| {
+        if b.is_ascii_digit() {
+            n.checked_mul(10)?.checked_add(u64::from(b - b'0'))
+        } else {
+            None
+        }
+    })
+}
+
+/// Value of the line `<key> <value>` in a flat keyed file such as
+/// `memory.stat`
+pub fn keyed_u64(data: &[u8], key: &str) -> Option<u64> {
+    data.split(|&b| b == b'\n')
+        .filter_map(|line| {
+            let mut fields = fields(line);
+            match (fields.next(), fields.next()) {
+                (Some(k), Some(v)) if k == key.as_bytes() => parse_u64(v),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Rounds to two decimals
+pub fn round2(f: f64) -> f64 {
+    (f * 100.0).round() / 100.0
+}
+
+pub fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1e9
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_u64(b"1234\n"), Some(1234));
+        assert_eq!(parse_u64(b" 0 "), Some(0));
+        assert_eq!(parse_u64(b"12 34"), None);
+        assert_eq!(parse_u64(b"-1"), None);
+        assert_eq!(parse_u64(b"18446744073709551616"), None);
+        assert_eq!(parse_u64(b""), None);
+
+        let stat = b"cache 10\ntotal_cache 20\ntotal_inactive_file 30\n";
+        assert_eq!(keyed_u64(stat, "total_cache"), Some(20));
+        assert_eq!(keyed_u64(stat, "total_inactive_file"), Some(30));
+        assert_eq!(keyed_u64(stat, "rss"), None);
+    }
+
+    #[test]
+    fn stat_file() {
+        let path = std::env::temp_dir()
+            .join(format!("stats-file-{}", std::process::id()));
+        let path_str = path.to_str().unwrap().to_string();
+        let mut file = StatFile::with_capacity(path_str, 4);
+        assert!(file.read().is_err());
+
+        // opened once the file exists, the buffer grows to fit
+        fs::write(&path, "12345678\n").unwrap();
+        assert_eq!(file.read_u64().unwrap(), 12_345_678);
+        // the same fd sees the new contents
+        fs::write(&path, "42\n").unwrap();
+        assert_eq!(file.read_u64().unwrap(), 42);
+
+        let mut head = StatFile::head(path.to_str().unwrap().to_string(), 1);
+        assert_eq!(head.read_u64().unwrap(), 4);
+
+        fs::remove_file(&path).unwrap();
+    }
 }
@@ -0,0 +1,1452 @@
+//! enya settings carried in the annotations of the OCI spec.
+//!
+//! | Annotation                        | Default     | Description                                        |
+//! |-----------------------------------|-------------|----------------------------------------------------|
+//! | `io.enya.monitor.host`            | `127.0.0.1` | IP address the System monitor binds to             |
+//! | `io.enya.monitor.port`            | `2000`      | Port the System monitor binds to                   |
+//! | `io.enya.monitor.interval-ms`     | `2000`      | Time between metric collections                    |
+//! | `io.enya.monitor.min-interval-ms` | `0`         | Fastest adaptive interval, 0 = fixed interval      |
+//! | `io.enya.monitor.fast-threshold`  | `0.8`       | Share of the memory limit that samples faster      |
+//! | `io.enya.monitor.fast-change`     | `0.01`      | Share of the limit per second that samples faster  |
+//! | `io.enya.monitor.sources`         | `all`       | Metric sources to collect, e.g. `cpu,io`           |
+//! | `io.enya.cpu.window-ms`           | `60000`     | Window of the cpu usage percentiles                |
+//! | `io.enya.share.process`           | `0.85`      | Share of memory/cpu for the Process                |
+//! | `io.enya.share.system`            | `0.15`      | Share of memory/cpu for the System                 |
+//! | `io.enya.restart.policy`          | `never`     | `never`, `on-failure` or `always`                  |
+//! | `io.enya.restart.max-retries`     | `5`         | Restarts before giving up, 0 = no limit            |
+//! | `io.enya.restart.backoff-ms`      | `100`       | Delay before the first restart                     |
+//! | `io.enya.restart.max-backoff-ms`  | `30000`     | Upper bound of the doubling delay                  |
+//! | `io.enya.health.check`            |             | `exec:<cmd>`, `tcp:<port>` or `http:<port>/<path>` |
+//! | `io.enya.health.interval-ms`      | `10000`     | Time between health checks                         |
+//! | `io.enya.health.timeout-ms`       | `1000`      | Time before a check counts as failed               |
+//! | `io.enya.health.retries`          | `3`         | Failed checks before being unhealthy               |
+//! | `io.enya.health.action`           | `none`      | `none`, `restart` or `kill` when unhealthy         |
+//! | `io.enya.stop.timeout-ms`         | `10000`     | Grace period before SIGKILL on stop, 0 = no limit  |
+//! | `io.enya.signals.forward`         | `all`       | Forward signals to `all` processes or `process`    |
+//! | `io.enya.signals.ignore`          |             | Signals that are not forwarded, e.g. `HUP,WINCH`   |
+//! | `io.enya.signals.translate`       |             | Signals forwarded as another, e.g. `TERM:INT`      |
+//! | `io.enya.signals.system`          | `false`     | SIGUSR1/SIGUSR2 dump metrics/toggle debug logging  |
+//! | `io.enya.processes`               |             | Names of additional process groups, e.g. `a,b`     |
+//! | `io.enya.processes.<name>.args`   |             | Command line of the group                          |
+//! | `io.enya.processes.<name>.env`    |             | JSON array of `KEY=VALUE` added to the env         |
+//! | `io.enya.processes.<name>.cwd`    |             | Absolute working dir of the group                  |
+//! | `io.enya.processes.<name>.share`  |             | Share of memory/cpu for the group                  |
+//! | `io.enya.custom.stale-ms`         | `30000`     | Time before a pushed metric is dropped             |
+//! | `io.enya.release.threshold`       | `0`         | Share of the limit that asks for memory, 0 = off   |
+//! | `io.enya.release.target`          | `0.8`       | Share of the limit to get back down to             |
+//! | `io.enya.release.deadline-ms`     | `5000`      | Time the Process has to release memory             |
+//! | `io.enya.release.escalation`      | `none`      | `none`, `raise`, `reclaim` or `signal:<SIG>`       |
+//! | `io.enya.oom.policy`              | `kernel`    | What happens when the Process is out of memory     |
+//! | `io.enya.oom.borrow-ms`           | `30000`     | Time before borrowed memory is given back          |
+//! | `io.enya.oom.horizon-ms`          | `0`         | Predicted time to OOM that alerts, 0 = off         |
+//!
+//! The metric sources are `memory`, `cpu`, `network` and `io`. Memory is
+//! always collected, as releases, OOM predictions and adaptive intervals
+//! depend on it. Sources whose files can't be read are left out.
+//!
+//! With a minimum interval, the collections speed up to it while the
+//! memory usage is above the fast threshold or changes by more than the
+//! fast change per second, and slow down to the interval again once it
+//! is stable.
+//!
+//! The shares are applied to the memory limit and cpu shares of the
+//! container and may not add up to more than 1.0, including the shares of
+//! the process groups.
+//!
+//! Every process group is launched by the System next to the Process, in
+//! a cgroup named after the group. Group names may only contain lowercase
+//! letters, digits, `-` and `_`, and `process` and `system` are taken.
+//! Groups get the env of the Process with their own variables on top.
+//!
+//! Command lines, of process groups and `exec` health checks, are either
+//! split on whitespace or given as a JSON array, e.g.
+//! `["sh", "-c", "exec helper -v"]` for arguments with spaces.
+//!
+//! Health checks run from inside the container, so `tcp` and `http`
+//! checks connect to localhost. The `restart` action requires a restart
+//! policy.
+//!
+//! The stop timeout starts when the System forwards SIGTERM, SIGINT or
+//! SIGQUIT to the Process. `enya kill --timeout` overrides it per signal.
+//!
+//! Signals are named with or without the `SIG` prefix, or by number.
+//! SIGCHLD, SIGKILL and SIGSTOP can be neither ignored nor translated.
+//! A translated SIGTERM, SIGINT or SIGQUIT still starts the stop timeout,
+//! an ignored one doesn't.
+//!
+//! Metrics pushed by the Process are part of every report until they are
+//! pushed again or have not been updated for the stale timeout.
+//!
+//! Once the memory usage of the process cgroup crosses the release
+//! threshold, registered endpoints of the Process are asked to release
+//! memory down to the target. Without an acknowledgement before the
+//! deadline, the System escalates: `raise` moves the missing bytes from
+//! the slack of the system cgroup to the process cgroup, `reclaim` makes
+//! the kernel reclaim them and `signal` signals the process cgroup.
+//!
+//! With an OOM policy other than `kernel`, the OOM killer is disabled for
+//! the process cgroup and the System decides instead. Subscribers are told
+//! about every OOM and `notify` leaves it at that, `kill-largest` kills
+//! the task with the largest RSS, `borrow` lends the process cgroup half
+//! of the slack of the system cgroup and `freeze` stops every task of the
+//! process cgroup until it receives SIGCONT.
+//!
+//! The time to OOM is predicted from the growth of the working set over
+//! the last samples. Subscribers are alerted when it drops below the
+//! horizon, and again after it recovered in between.
+
+use oci::Spec;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::error::ErrorKind::*;
+use crate::error::*;
+use crate::registry::SOURCES;
+
+pub const MONITOR_HOST: &str = "io.enya.monitor.host";
+pub const MONITOR_PORT: &str = "io.enya.monitor.port";
+pub const MONITOR_INTERVAL: &str = "io.enya.monitor.interval-ms";
+pub const MONITOR_MIN_INTERVAL: &str = "io.enya.monitor.min-interval-ms";
+pub const MONITOR_FAST_THRESHOLD: &str = "io.enya.monitor.fast-threshold";
+pub const MONITOR_FAST_CHANGE: &str = "io.enya.monitor.fast-change";
+pub const MONITOR_SOURCES: &str = "io.enya.monitor.sources";
+pub const CPU_WINDOW: &str = "io.enya.cpu.window-ms";
+pub const PROCESS_SHARE: &str = "io.enya.share.process";
+pub const SYSTEM_SHARE: &str = "io.enya.share.system";
+pub const RESTART_POLICY: &str = "io.enya.restart.policy";
+pub const RESTART_MAX_RETRIES: &str = "io.enya.restart.max-retries";
+pub const RESTART_BACKOFF: &str = "io.enya.restart.backoff-ms";
+pub const RESTART_MAX_BACKOFF: &str = "io.enya.restart.max-backoff-ms";
+pub const HEALTH_CHECK: &str = "io.enya.health.check";
+pub const HEALTH_INTERVAL: &str = "io.enya.health.interval-ms";
+pub const HEALTH_TIMEOUT: &str = "io.enya.health.timeout-ms";
+pub const HEALTH_RETRIES: &str = "io.enya.health.retries";
+pub const HEALTH_ACTION: &str = "io.enya.health.action";
+pub const STOP_TIMEOUT: &str = "io.enya.stop.timeout-ms";
+pub const SIGNALS_FORWARD: &str = "io.enya.signals.forward";
+pub const SIGNALS_IGNORE: &str = "io.enya.signals.ignore";
+pub const SIGNALS_TRANSLATE: &str = "io.enya.signals.translate";
+pub const SIGNALS_SYSTEM: &str = "io.enya.signals.system";
+pub const PROCESSES: &str = "io.enya.processes";
+pub const CUSTOM_STALE: &str = "io.enya.custom.stale-ms";
+pub const RELEASE_THRESHOLD: &str = "io.enya.release.threshold";
+pub const RELEASE_TARGET: &str = "io.enya.release.target";
+pub const RELEASE_DEADLINE: &str = "io.enya.release.deadline-ms";
+pub const RELEASE_ESCALATION: &str = "io.enya.release.escalation";
+pub const OOM_POLICY: &str = "io.enya.oom.policy";
+pub const OOM_BORROW: &str = "io.enya.oom.borrow-ms";
+pub const OOM_HORIZON: &str = "io.enya.oom.horizon-ms";
+
+const DEFAULT_MONITOR_HOST: &str = "127.0.0.1";
+const DEFAULT_MONITOR_PORT: u16 = 2000;
+const DEFAULT_MONITOR_INTERVAL_MS: u64 = 2000;
+const DEFAULT_MONITOR_FAST_THRESHOLD: f64 = 0.8;
+const DEFAULT_MONITOR_FAST_CHANGE: f64 = 0.01;
+const DEFAULT_CPU_WINDOW_MS: u64 = 60_000;
+const DEFAULT_PROCESS_SHARE: f64 = 0.85;
+const DEFAULT_SYSTEM_SHARE: f64 = 0.15;
+const DEFAULT_RESTART_MAX_RETRIES: u32 = 5;
+const DEFAULT_RESTART_BACKOFF_MS: u64 = 100;
+const DEFAULT_RESTART_MAX_BACKOFF_MS: u64 = 30_000;
+const DEFAULT_HEALTH_INTERVAL_MS: u64 = 10_000;
+const DEFAULT_HEALTH_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_HEALTH_RETRIES: u32 = 3;
+const DEFAULT_STOP_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_CUSTOM_STALE_MS: u64 = 30_000;
+const DEFAULT_RELEASE_TARGET: f64 = 0.8;
+const DEFAULT_RELEASE_DEADLINE_MS: u64 = 5000;
+const DEFAULT_OOM_BORROW_MS: u64 = 30_000;
+const MONITOR_ALIAS: &str = "monitor";
+
+const SIGNALS: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ILL", libc::SIGILL),
+    ("TRAP", libc::SIGTRAP),
+    ("ABRT", libc::SIGABRT),
+    ("BUS", libc::SIGBUS),
+    ("FPE", libc::SIGFPE),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("SEGV", libc::SIGSEGV),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CHLD", libc::SIGCHLD),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("URG", libc::SIGURG),
+    ("XCPU", libc::SIGXCPU),
+    ("XFSZ", libc::SIGXFSZ),
+    ("VTALRM", libc::SIGVTALRM),
+    ("PROF", libc::SIGPROF),
+    ("WINCH", libc::SIGWINCH),
+    ("IO", libc::SIGIO),
+    ("PWR", libc::SIGPWR),
+    ("SYS", libc::SIGSYS),
+];
+
+/// Number of a signal named `TERM`, `SIGTERM` or `15`
+pub fn signal_number(name: &str) -> Option<i32> {
+    let name = name.trim();
+    if let Ok(signo) = name.parse::<i32>() {
+        return SIGNALS.iter().map(|s| s.1).find(|s| *s == signo);
+    }
+    let name = name.trim_start_matches("SIG");
+    SIGNALS.iter().find(|s| s.0 == name).map(|s| s.1)
+}
+
+fn signal_name(signo: i32) -> String {
+    match SIGNALS.iter().find(|s| s.1 == signo) {
+        Some(s) => s.0.to_string(),
+        None => signo.to_string(),
+    }
+}
+
+// signals the System can't do without, or that can't be caught at all
+fn is_reserved(signo: i32) -> bool {
+    signo == libc::SIGCHLD || signo == libc::SIGKILL || signo == libc::SIGSTOP
+}
+
+/// What the System does when the Process exits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl FromStr for RestartPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<RestartPolicy, ()> {
+        match s {
+            "never" => Ok(RestartPolicy::Never),
+            "on-failure" => Ok(RestartPolicy::OnFailure),
+            "always" => Ok(RestartPolicy::Always),
+            _ => Err(()),
+        }
+    }
+}
+
+impl RestartPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            RestartPolicy::Never => "never",
+            RestartPolicy::OnFailure => "on-failure",
+            RestartPolicy::Always => "always",
+        }
+    }
+}
+
+/// Who the System forwards signals to
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalForward {
+    /// Every other process in the container
+    All,
+    /// Only the members of the process cgroup
+    Process,
+}
+
+impl FromStr for SignalForward {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<SignalForward, ()> {
+        match s {
+            "all" => Ok(SignalForward::All),
+            "process" => Ok(SignalForward::Process),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SignalForward {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            SignalForward::All => "all",
+            SignalForward::Process => "process",
+        }
+    }
+}
+
+/// What the System does when the Process misses the release deadline
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReleaseEscalation {
+    None,
+    /// Raise the limit of the process cgroup from the system cgroup slack
+    Raise,
+    /// Make the kernel reclaim memory of the process cgroup
+    Reclaim,
+    /// Send the signal to the process cgroup
+    Signal(i32),
+}
+
+impl FromStr for ReleaseEscalation {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<ReleaseEscalation, ()> {
+        match s {
+            "none" => Ok(ReleaseEscalation::None),
+            "raise" => Ok(ReleaseEscalation::Raise),
+            "reclaim" => Ok(ReleaseEscalation::Reclaim),
+            _ if s.starts_with("signal:") => {
+                match signal_number(&s["signal:".len()..]) {
+                    Some(signo) if signo != libc::SIGSTOP => {
+                        Ok(ReleaseEscalation::Signal(signo))
+                    }
+                    _ => Err(()),
+                }
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl ReleaseEscalation {
+    pub fn to_annotation(&self) -> String {
+        match *self {
+            ReleaseEscalation::None => "none".to_string(),
+            ReleaseEscalation::Raise => "raise".to_string(),
+            ReleaseEscalation::Reclaim => "reclaim".to_string(),
+            ReleaseEscalation::Signal(signo) => {
+                format!("signal:{}", signal_name(signo))
+            }
+        }
+    }
+}
+
+/// What happens when the process cgroup runs out of memory
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OomPolicy {
+    /// Leave it to the OOM killer
+    Kernel,
+    Notify,
+    KillLargest,
+    Borrow,
+    Freeze,
+}
+
+impl FromStr for OomPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<OomPolicy, ()> {
+        match s {
+            "kernel" => Ok(OomPolicy::Kernel),
+            "notify" => Ok(OomPolicy::Notify),
+            "kill-largest" => Ok(OomPolicy::KillLargest),
+            "borrow" => Ok(OomPolicy::Borrow),
+            "freeze" => Ok(OomPolicy::Freeze),
+            _ => Err(()),
+        }
+    }
+}
+
+impl OomPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            OomPolicy::Kernel => "kernel",
+            OomPolicy::Notify => "notify",
+            OomPolicy::KillLargest => "kill-largest",
+            OomPolicy::Borrow => "borrow",
+            OomPolicy::Freeze => "freeze",
+        }
+    }
+}
+
+/// An additional process the System launches into its own cgroup
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProcessGroup {
+    /// Name of the group, which is also the name of its cgroup
+    pub name: String,
+    pub args: Vec<String>,
+    /// `KEY=VALUE` pairs on top of the env of the Process
+    pub env: Vec<String>,
+    pub cwd: Option<String>,
+    /// Share of the memory limit and cpu shares of the container
+    pub share: f64,
+}
+
+impl ProcessGroup {
+    /// The env of the group, `base` with the variables of the group
+    /// replacing those of the same name
+    pub fn env_with(&self, base: &[String]) -> Vec<String> {
+        let key = |var: &str| var.split('=').next().unwrap_or("").to_string();
+        let mut env: Vec<String> = base
+            .iter()
+            .filter(|var| !self.env.iter().any(|own| key(own) == key(var)))
+            .cloned()
+            .collect();
+        env.extend(self.env.iter().cloned());
+        env
+    }
+
+    fn args_key(name: &str) -> String {
+        format!("{}.{}.args", PROCESSES, name)
+    }
+
+    fn env_key(name: &str) -> String {
+        format!("{}.{}.env", PROCESSES, name)
+    }
+
+    fn cwd_key(name: &str) -> String {
+        format!("{}.{}.cwd", PROCESSES, name)
+    }
+
+    fn share_key(name: &str) -> String {
+        format!("{}.{}.share", PROCESSES, name)
+    }
+}
+
+/// How the System probes the health of the Process
+#[derive(Debug, Clone, PartialEq)]
+pub enum HealthCheck {
+    /// Command run inside the container, healthy if it exits with 0
+    Exec(Vec<String>),
+    /// Healthy if a TCP connection to the port on localhost succeeds
+    Tcp(u16),
+    /// Healthy if a GET on localhost returns a 2xx or 3xx status
+    Http { port: u16, path: String },
+}
+
+impl FromStr for HealthCheck {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<HealthCheck, ()> {
+        let mut parts = s.splitn(2, ':');
+        let kind = parts.next().unwrap_or_default();
+        let rest = parts.next().ok_or(())?.trim();
+        match kind {
+            "exec" => parse_command(rest).map(HealthCheck::Exec).ok_or(()),
+            "tcp" => rest.parse().map(HealthCheck::Tcp).map_err(|_| ()),
+            "http" => {
+                let (port, path) = match rest.find('/') {
+                    Some(i) => (&rest[..i], &rest[i..]),
+                    None => (rest, "/"),
+                };
+                Ok(HealthCheck::Http {
+                    port: port.parse().map_err(|_| ())?,
+                    path: path.to_string(),
+                })
+            }
+            _ => Err(()),
+        }
+    }
+}
+
+impl HealthCheck {
+    pub fn to_annotation(&self) -> String {
+        match *self {
+            HealthCheck::Exec(ref args) => {
+                format!("exec:{}", command_annotation(args))
+            }
+            HealthCheck::Tcp(port) => format!("tcp:{}", port),
+            HealthCheck::Http { port, ref path } => {
+                format!("http:{}{}", port, path)
+            }
+        }
+    }
+}
+
+/// What the System does once the Process is unhealthy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HealthAction {
+    None,
+    Restart,
+    Kill,
+}
+
+impl FromStr for HealthAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<HealthAction, ()> {
+        match s {
+            "none" => Ok(HealthAction::None),
+            "restart" => Ok(HealthAction::Restart),
+            "kill" => Ok(HealthAction::Kill),
+            _ => Err(()),
+        }
+    }
+}
+
+impl HealthAction {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            HealthAction::None => "none",
+            HealthAction::Restart => "restart",
+            HealthAction::Kill => "kill",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub monitor_host: String,
+    pub monitor_port: u16,
+    pub monitor_interval_ms: u64,
+    pub monitor_min_interval_ms: u64,
+    pub monitor_fast_threshold: f64,
+    pub monitor_fast_change: f64,
+    /// Names of the enabled metric sources
+    pub monitor_sources: Vec<String>,
+    pub cpu_window_ms: u64,
+    pub process_share: f64,
+    pub system_share: f64,
+    pub restart_policy: RestartPolicy,
+    pub restart_max_retries: u32,
+    pub restart_backoff_ms: u64,
+    pub restart_max_backoff_ms: u64,
+    pub health_check: Option<HealthCheck>,
+    pub health_interval_ms: u64,
+    pub health_timeout_ms: u64,
+    pub health_retries: u32,
+    pub health_action: HealthAction,
+    pub stop_timeout_ms: u64,
+    pub signals_forward: SignalForward,
+    pub signals_ignore: Vec<i32>,
+    /// Pairs of received and forwarded signal
+    pub signals_translate: Vec<(i32, i32)>,
+    pub signals_system: bool,
+    pub processes: Vec<ProcessGroup>,
+    pub custom_stale_ms: u64,
+    pub release_threshold: f64,
+    pub release_target: f64,
+    pub release_deadline_ms: u64,
+    pub release_escalation: ReleaseEscalation,
+    pub oom_policy: OomPolicy,
+    pub oom_borrow_ms: u64,
+    pub oom_horizon_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            monitor_host: DEFAULT_MONITOR_HOST.to_string(),
+            monitor_port: DEFAULT_MONITOR_PORT,
+            monitor_interval_ms: DEFAULT_MONITOR_INTERVAL_MS,
+            monitor_min_interval_ms: 0,
+            monitor_fast_threshold: DEFAULT_MONITOR_FAST_THRESHOLD,
+            monitor_fast_change: DEFAULT_MONITOR_FAST_CHANGE,
+            monitor_sources: SOURCES.iter().map(|s| s.to_string()).collect(),
+            cpu_window_ms: DEFAULT_CPU_WINDOW_MS,
+            process_share: DEFAULT_PROCESS_SHARE,
+            system_share: DEFAULT_SYSTEM_SHARE,
+            restart_policy: RestartPolicy::Never,
+            restart_max_retries: DEFAULT_RESTART_MAX_RETRIES,
+            restart_backoff_ms: DEFAULT_RESTART_BACKOFF_MS,
+            restart_max_backoff_ms: DEFAULT_RESTART_MAX_BACKOFF_MS,
+            health_check: None,
+            health_interval_ms: DEFAULT_HEALTH_INTERVAL_MS,
+            health_timeout_ms: DEFAULT_HEALTH_TIMEOUT_MS,
+            health_retries: DEFAULT_HEALTH_RETRIES,
+            health_action: HealthAction::None,
+            stop_timeout_ms: DEFAULT_STOP_TIMEOUT_MS,
+            signals_forward: SignalForward::All,
+            signals_ignore: Vec::new(),
+            signals_translate: Vec::new(),
+            signals_system: false,
+            processes: Vec::new(),
+            custom_stale_ms: DEFAULT_CUSTOM_STALE_MS,
+            release_threshold: 0.0,
+            release_target: DEFAULT_RELEASE_TARGET,
+            release_deadline_ms: DEFAULT_RELEASE_DEADLINE_MS,
+            release_escalation: ReleaseEscalation::None,
+            oom_policy: OomPolicy::Kernel,
+            oom_borrow_ms: DEFAULT_OOM_BORROW_MS,
+            oom_horizon_ms: 0,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_spec(spec: &Spec) -> Result<Config> {
+        let a = &spec.annotations;
+        let default = Config::default();
+        let config = Config {
+            monitor_host: a
+                .get(MONITOR_HOST)
+                .cloned()
+                .unwrap_or(default.monitor_host),
+            monitor_port: parse(a, MONITOR_PORT, default.monitor_port)?,
+            monitor_interval_ms: parse(
+                a,
+                MONITOR_INTERVAL,
+                default.monitor_interval_ms,
+            )?,
+            monitor_min_interval_ms: parse(
+                a,
+                MONITOR_MIN_INTERVAL,
+                default.monitor_min_interval_ms,
+            )?,
+            monitor_fast_threshold: parse(
+                a,
+                MONITOR_FAST_THRESHOLD,
+                default.monitor_fast_threshold,
+            )?,
+            monitor_fast_change: parse(
+                a,
+                MONITOR_FAST_CHANGE,
+                default.monitor_fast_change,
+            )?,
+            monitor_sources: parse_sources(a, default.monitor_sources)?,
+            cpu_window_ms: parse(a, CPU_WINDOW, default.cpu_window_ms)?,
+            process_share: parse(a, PROCESS_SHARE, default.process_share)?,
+            system_share: parse(a, SYSTEM_SHARE, default.system_share)?,
+            restart_policy: parse(a, RESTART_POLICY, default.restart_policy)?,
+            restart_max_retries: parse(
+                a,
+                RESTART_MAX_RETRIES,
+                default.restart_max_retries,
+            )?,
+            restart_backoff_ms: parse(
+                a,
+                RESTART_BACKOFF,
+                default.restart_backoff_ms,
+            )?,
+            restart_max_backoff_ms: parse(
+                a,
+                RESTART_MAX_BACKOFF,
+                default.restart_max_backoff_ms,
+            )?,
+            health_check: match a.get(HEALTH_CHECK) {
+                Some(_) => Some(parse(a, HEALTH_CHECK, HealthCheck::Tcp(0))?),
+                None => None,
+            },
+            health_interval_ms: parse(
+                a,
+                HEALTH_INTERVAL,
+                default.health_interval_ms,
+            )?,
+            health_timeout_ms: parse(
+                a,
+                HEALTH_TIMEOUT,
+                default.health_timeout_ms,
+            )?,
+            health_retries: parse(a, HEALTH_RETRIES, default.health_retries)?,
+            health_action: parse(a, HEALTH_ACTION, default.health_action)?,
+            stop_timeout_ms: parse(a, STOP_TIMEOUT, default.stop_timeout_ms)?,
+            signals_forward: parse(
+                a,
+                SIGNALS_FORWARD,
+                default.signals_forward,
+            )?,
+            signals_ignore: parse_signals(a)?,
+            signals_translate: parse_translations(a)?,
+            signals_system: parse(a, SIGNALS_SYSTEM, default.signals_system)?,
+            processes: parse_groups(a)?,
+            custom_stale_ms: parse(a, CUSTOM_STALE, default.custom_stale_ms)?,
+            release_threshold: parse(
+                a,
+                RELEASE_THRESHOLD,
+                default.release_threshold,
+            )?,
+            release_target: parse(a, RELEASE_TARGET, default.release_target)?,
+            release_deadline_ms: parse(
+                a,
+                RELEASE_DEADLINE,
+                default.release_deadline_ms,
+            )?,
+            release_escalation: parse(
+                a,
+                RELEASE_ESCALATION,
+                default.release_escalation,
+            )?,
+            oom_policy: parse(a, OOM_POLICY, default.oom_policy)?,
+            oom_borrow_ms: parse(a, OOM_BORROW, default.oom_borrow_ms)?,
+            oom_horizon_ms: parse(a, OOM_HORIZON, default.oom_horizon_ms)?,
+        };
+
+        if config.monitor_host.parse::<IpAddr>().is_err() {
+            return Err(Error::new(InvalidAnnotation(MONITOR_HOST)));
+        }
+        if config.monitor_interval_ms == 0 {
+            return Err(Error::new(InvalidAnnotation(MONITOR_INTERVAL)));
+        }
+        if config.monitor_min_interval_ms > config.monitor_interval_ms {
+            return Err(Error::new(InvalidAnnotation(MONITOR_MIN_INTERVAL)));
+        }
+        if config.monitor_fast_threshold <= 0.0
+            || config.monitor_fast_threshold > 1.0
+        {
+            return Err(Error::new(InvalidAnnotation(MONITOR_FAST_THRESHOLD)));
+        }
+        if config.monitor_fast_change <= 0.0 {
+            return Err(Error::new(InvalidAnnotation(MONITOR_FAST_CHANGE)));
+        }
+        // a shorter window would only ever hold a single sample
+        if config.cpu_window_ms < config.monitor_interval_ms {
+            return Err(Error::new(InvalidAnnotation(CPU_WINDOW)));
+        }
+        if config.process_share <= 0.0 {
+            return Err(Error::new(InvalidAnnotation(PROCESS_SHARE)));
+        }
+        if config.system_share <= 0.0
+            || config.process_share + config.system_share > 1.0
+        {
+            return Err(Error::new(InvalidAnnotation(SYSTEM_SHARE)));
+        }
+        let groups_share: f64 = config.processes.iter().map(|p| p.share).sum();
+        if config.process_share + config.system_share + groups_share > 1.0 {
+            return Err(Error::new(InvalidAnnotation(PROCESSES)));
+        }
+        if config.restart_max_backoff_ms < config.restart_backoff_ms {
+            return Err(Error::new(InvalidAnnotation(RESTART_MAX_BACKOFF)));
+        }
+        if config.custom_stale_ms == 0 {
+            return Err(Error::new(InvalidAnnotation(CUSTOM_STALE)));
+        }
+        if config.release_threshold < 0.0 || config.release_threshold > 1.0 {
+            return Err(Error::new(InvalidAnnotation(RELEASE_THRESHOLD)));
+        }
+        if config.release_enabled()
+            && (config.release_target <= 0.0
+                || config.release_target >= config.release_threshold)
+        {
+            return Err(Error::new(InvalidAnnotation(RELEASE_TARGET)));
+        }
+        if config.release_deadline_ms == 0 {
+            return Err(Error::new(InvalidAnnotation(RELEASE_DEADLINE)));
+        }
+        if config.oom_borrow_ms == 0 {
+            return Err(Error::new(InvalidAnnotation(OOM_BORROW)));
+        }
+        if config.health_interval_ms == 0 {
+            return Err(Error::new(InvalidAnnotation(HEALTH_INTERVAL)));
+        }
+        if config.health_timeout_ms == 0 {
+            return Err(Error::new(InvalidAnnotation(HEALTH_TIMEOUT)));
+        }
+        if config.health_retries == 0 {
+            return Err(Error::new(InvalidAnnotation(HEALTH_RETRIES)));
+        }
+        if config.health_action == HealthAction::Restart
+            && config.restart_policy == RestartPolicy::Never
+        {
+            return Err(Error::new(InvalidAnnotation(HEALTH_ACTION)));
+        }
+        Ok(config)
+    }
+
+    /// Annotations describing this configuration
+    pub fn annotations(&self) -> HashMap<String, String> {
+        let mut a = HashMap::new();
+        a.insert(MONITOR_HOST.to_string(), self.monitor_host.clone());
+        a.insert(MONITOR_PORT.to_string(), self.monitor_port.to_string());
+        a.insert(
+            MONITOR_INTERVAL.to_string(),
+            self.monitor_interval_ms.to_string(),
+        );
+        if self.monitor_min_interval().is_some() {
+            a.insert(
+                MONITOR_MIN_INTERVAL.to_string(),
+                self.monitor_min_interval_ms.to_string(),
+            );
+            a.insert(
+                MONITOR_FAST_THRESHOLD.to_string(),
+                self.monitor_fast_threshold.to_string(),
+            );
+            a.insert(
+                MONITOR_FAST_CHANGE.to_string(),
+                self.monitor_fast_change.to_string(),
+            );
+        }
+        a.insert(MONITOR_SOURCES.to_string(), self.monitor_sources.join(","));
+        a.insert(CPU_WINDOW.to_string(), self.cpu_window_ms.to_string());
+        a.insert(PROCESS_SHARE.to_string(), self.process_share.to_string());
+        a.insert(SYSTEM_SHARE.to_string(), self.system_share.to_string());
+        a.insert(
+            RESTART_POLICY.to_string(),
+            self.restart_policy.as_str().to_string(),
+        );
+        a.insert(
+            RESTART_MAX_RETRIES.to_string(),
+            self.restart_max_retries.to_string(),
+        );
+        a.insert(
+            RESTART_BACKOFF.to_string(),
+            self.restart_backoff_ms.to_string(),
+        );
+        a.insert(
+            RESTART_MAX_BACKOFF.to_string(),
+            self.restart_max_backoff_ms.to_string(),
+        );
+        if let Some(ref check) = self.health_check {
+            a.insert(HEALTH_CHECK.to_string(), check.to_annotation());
+            a.insert(
+                HEALTH_INTERVAL.to_string(),
+                self.health_interval_ms.to_string(),
+            );
+            a.insert(
+                HEALTH_TIMEOUT.to_string(),
+                self.health_timeout_ms.to_string(),
+            );
+            a.insert(
+                HEALTH_RETRIES.to_string(),
+                self.health_retries.to_string(),
+            );
+            a.insert(
+                HEALTH_ACTION.to_string(),
+                self.health_action.as_str().to_string(),
+            );
+        }
+        a.insert(STOP_TIMEOUT.to_string(), self.stop_timeout_ms.to_string());
+        a.insert(
+            SIGNALS_FORWARD.to_string(),
+            self.signals_forward.as_str().to_string(),
+        );
+        if !self.signals_ignore.is_empty() {
+            let names: Vec<String> = self
+                .signals_ignore
+                .iter()
+                .map(|s| signal_name(*s))
+                .collect();
+            a.insert(SIGNALS_IGNORE.to_string(), names.join(","));
+        }
+        if !self.signals_translate.is_empty() {
+            let pairs: Vec<String> = self
+                .signals_translate
+                .iter()
+                .map(|&(from, to)| {
+                    format!("{}:{}", signal_name(from), signal_name(to))
+                })
+                .collect();
+            a.insert(SIGNALS_TRANSLATE.to_string(), pairs.join(","));
+        }
+        a.insert(SIGNALS_SYSTEM.to_string(), self.signals_system.to_string());
+        if !self.processes.is_empty() {
+            let names: Vec<&str> =
+                self.processes.iter().map(|p| p.name.as_str()).collect();
+            a.insert(PROCESSES.to_string(), names.join(","));
+            for group in &self.processes {
+                let name = &group.name;
+                a.insert(
+                    ProcessGroup::args_key(name),
+                    command_annotation(&group.args),
+                );
+                if !group.env.is_empty() {
+                    a.insert(
+                        ProcessGroup::env_key(name),
+                        serde_json::to_string(&group.env).unwrap_or_default(),
+                    );
+                }
+                if let Some(ref cwd) = group.cwd {
+                    a.insert(ProcessGroup::cwd_key(name), cwd.clone());
+                }
+                a.insert(
+                    ProcessGroup::share_key(name),
+                    group.share.to_string(),
+                );
+            }
+        }
+        a.insert(CUSTOM_STALE.to_string(), self.custom_stale_ms.to_string());
+        if self.release_enabled() {
+            a.insert(
+                RELEASE_THRESHOLD.to_string(),
+                self.release_threshold.to_string(),
+            );
+            a.insert(
+                RELEASE_TARGET.to_string(),
+                self.release_target.to_string(),
+            );
+            a.insert(
+                RELEASE_DEADLINE.to_string(),
+                self.release_deadline_ms.to_string(),
+            );
+            a.insert(
+                RELEASE_ESCALATION.to_string(),
+                self.release_escalation.to_annotation(),
+            );
+        }
+        a.insert(OOM_POLICY.to_string(), self.oom_policy.as_str().to_string());
+        if self.oom_policy == OomPolicy::Borrow {
+            a.insert(OOM_BORROW.to_string(), self.oom_borrow_ms.to_string());
+        }
+        if self.oom_horizon_ms != 0 {
+            a.insert(OOM_HORIZON.to_string(), self.oom_horizon_ms.to_string());
+        }
+        a
+    }
+
+    /// Signal to forward to the Process for `signo` received by the
+    /// System, `None` if it is ignored
+    pub fn forwarded_signal(&self, signo: i32) -> Option<i32> {
+        if self.signals_ignore.contains(&signo) {
+            return None;
+        }
+        match self.signals_translate.iter().find(|t| t.0 == signo) {
+            Some(&(_, to)) => Some(to),
+            None => Some(signo),
+        }
+    }
+
+    /// Whether the Process should be restarted after exiting with
+    /// `exit_code`, having been restarted `restarts` times already
+    pub fn should_restart(&self, exit_code: i32, restarts: u32) -> bool {
+        if self.restart_max_retries != 0 && restarts >= self.restart_max_retries
+        {
+            return false;
+        }
+        match self.restart_policy {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => exit_code != 0,
+            RestartPolicy::Always => true,
+        }
+    }
+
+    /// Delay before the next restart, doubling with every restart
+    pub fn restart_backoff(&self, restarts: u32) -> Duration {
+        let factor = 1u64.checked_shl(restarts).unwrap_or(u64::max_value());
+        let ms = self
+            .restart_backoff_ms
+            .saturating_mul(factor)
+            .min(self.restart_max_backoff_ms);
+        Duration::from_millis(ms)
+    }
+
+    /// Grace period of the Process after being asked to stop, `None` if
+    /// the System waits for it indefinitely
+    pub fn stop_timeout(&self) -> Option<Duration> {
+        match self.stop_timeout_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Fastest interval of the adaptive collections, `None` if metrics
+    /// are collected at a fixed interval
+    pub fn monitor_min_interval(&self) -> Option<Duration> {
+        match self.monitor_min_interval_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Window of the cpu usage percentiles
+    pub fn cpu_window(&self) -> Duration {
+        Duration::from_millis(self.cpu_window_ms)
+    }
+
+    /// Time after which a metric pushed by the Process is dropped
+    pub fn custom_stale(&self) -> Duration {
+        Duration::from_millis(self.custom_stale_ms)
+    }
+
+    /// Whether the Process is asked to release memory
+    pub fn release_enabled(&self) -> bool {
+        self.release_threshold > 0.0
+    }
+
+    pub fn release_deadline(&self) -> Duration {
+        Duration::from_millis(self.release_deadline_ms)
+    }
+
+    /// Predicted time to OOM below which subscribers are alerted, `None`
+    /// if they are not
+    pub fn oom_horizon(&self) -> Option<Duration> {
+        match self.oom_horizon_ms {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        }
+    }
+
+    /// Whether the System writes to the memory cgroups of the process and
+    /// system cgroups
+    pub fn moves_memory(&self) -> bool {
+        let escalates = match self.release_escalation {
+            ReleaseEscalation::Raise | ReleaseEscalation::Reclaim => true,
+            _ => false,
+        };
+        (self.release_enabled() && escalates)
+            || self.oom_policy == OomPolicy::Borrow
+    }
+
+    pub fn monitor_alias(&self) -> &'static str {
+        MONITOR_ALIAS
+    }
+
+    /// Address the System monitor binds to
+    pub fn monitor_addr(&self) -> SocketAddr {
+        // validated by from_spec
+        let ip = self
+            .monitor_host
+            .parse()
+            .unwrap_or_else(|_| IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
+        SocketAddr::new(ip, self.monitor_port)
+    }
+
+    /// Socket address of the System monitor, IPv6 hosts in brackets
+    pub fn monitor_socket(&self) -> String {
+        self.monitor_addr().to_string()
+    }
+
+    /// Actor path of the System monitor, as seen from inside the container
+    pub fn monitor_path(&self) -> String {
+        format!("tcp://{}/{}", self.monitor_socket(), MONITOR_ALIAS)
+    }
+}
+
+fn parse<T: FromStr>(
+    annotations: &HashMap<String, String>,
+    key: &'static str,
+    default: T,
+) -> Result<T> {
+    match annotations.get(key) {
+        Some(value) => value
+            .trim()
+            .parse::<T>()
+            .map_err(|_| Error::new(InvalidAnnotation(key))),
+        None => Ok(default),
+    }
+}
+
+fn parse_sources(
+    annotations: &HashMap<String, String>,
+    default: Vec<String>,
+) -> Result<Vec<String>> {
+    let value = match annotations.get(MONITOR_SOURCES) {
+        Some(value) if value.trim() != "all" => value,
+        _ => return Ok(default),
+    };
+    let mut sources = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        if !SOURCES.contains(&name) {
+            return Err(Error::new(InvalidAnnotation(MONITOR_SOURCES)));
+        }
+        if !sources.iter().any(|s| s == name) {
+            sources.push(name.to_string());
+        }
+    }
+    Ok(sources)
+}
+
+fn parse_signals(annotations: &HashMap<String, String>) -> Result<Vec<i32>> {
+    let value = match annotations.get(SIGNALS_IGNORE) {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let invalid = || Error::new(InvalidAnnotation(SIGNALS_IGNORE));
+    let mut signals = Vec::new();
+    for name in value.split(',').filter(|n| !n.trim().is_empty()) {
+        let signo = signal_number(name).ok_or_else(invalid)?;
+        if is_reserved(signo) {
+            return Err(invalid());
+        }
+        signals.push(signo);
+    }
+    Ok(signals)
+}
+
+fn parse_translations(
+    annotations: &HashMap<String, String>,
+) -> Result<Vec<(i32, i32)>> {
+    let value = match annotations.get(SIGNALS_TRANSLATE) {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let invalid = || Error::new(InvalidAnnotation(SIGNALS_TRANSLATE));
+    let mut translations = Vec::new();
+    for pair in value.split(',').filter(|p| !p.trim().is_empty()) {
+        let mut names = pair.splitn(2, ':');
+        let from = names.next().and_then(signal_number);
+        let to = names.next().and_then(signal_number);
+        match (from, to) {
+            (Some(from), Some(to)) if !is_reserved(from) => {
+                translations.push((from, to))
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Ok(translations)
+}
+
+fn parse_groups(
+    annotations: &HashMap<String, String>,
+) -> Result<Vec<ProcessGroup>> {
+    let value = match annotations.get(PROCESSES) {
+        Some(value) => value,
+        None => return Ok(Vec::new()),
+    };
+    let invalid = || Error::new(InvalidAnnotation(PROCESSES));
+    let mut groups: Vec<ProcessGroup> = Vec::new();
+    for name in value.split(',').map(str::trim).filter(|n| !n.is_empty()) {
+        let valid = name.chars().all(|c| {
+            c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '_'
+        });
+        if !valid
+            || name == "process"
+            || name == "system"
+            || groups.iter().any(|g| g.name == name)
+        {
+            return Err(invalid());
+        }
+        let args = annotations
+            .get(&ProcessGroup::args_key(name))
+            .and_then(|a| parse_command(a))
+            .ok_or_else(invalid)?;
+        let env_key = ProcessGroup::env_key(name);
+        let env: Vec<String> = match annotations.get(&env_key) {
+            Some(env) => serde_json::from_str(env).map_err(|_| invalid())?,
+            None => Vec::new(),
+        };
+        let valid_var = |var: &String| var.find('=').map_or(false, |i| i > 0);
+        let cwd = annotations.get(&ProcessGroup::cwd_key(name)).cloned();
+        let share = annotations
+            .get(&ProcessGroup::share_key(name))
+            .and_then(|s| s.trim().parse::<f64>().ok())
+            .ok_or_else(invalid)?;
+        if share <= 0.0
+            || !env.iter().all(valid_var)
+            || cwd.as_ref().map_or(false, |cwd| !cwd.starts_with('/'))
+        {
+            return Err(invalid());
+        }
+        groups.push(ProcessGroup {
+            name: name.to_string(),
+            args,
+            env,
+            cwd,
+            share,
+        });
+    }
+    Ok(groups)
+}
+
+// A command line given as a JSON array of arguments, or split on
+// whitespace. `None` if there are no arguments.
+fn parse_command(value: &str) -> Option<Vec<String>> {
+    let value = value.trim();
+    let args = match serde_json::from_str::<Vec<String>>(value) {
+        Ok(args) => args,
+        // not JSON, also for e.g. `[ -f /ready ]`
+        Err(_) => value.split_whitespace().map(String::from).collect(),
+    };
+    if args.is_empty() || args[0].is_empty() {
+        return None;
+    }
+    Some(args)
+}
+
+// the annotation value of a command line, which parse_command reads back
+fn command_annotation(args: &[String]) -> String {
+    serde_json::to_string(args).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(annotations: &[(&str, &str)]) -> Spec {
+        let mut spec: Spec = oci::serialize::from_str(
+            r#"{"process": {"user": {}, "args": []}, "root": {}}"#,
+        )
+        .unwrap();
+        for &(k, v) in annotations {
+            spec.annotations.insert(k.to_string(), v.to_string());
+        }
+        spec
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(config.monitor_path(), "tcp://127.0.0.1:2000/monitor");
+        assert_eq!(config.process_share, DEFAULT_PROCESS_SHARE);
+        assert_eq!(config.stop_timeout(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn monitor_host() {
+        let v6 = Config::from_spec(&spec(&[(MONITOR_HOST, "::1")])).unwrap();
+        assert_eq!(v6.monitor_socket(), "[::1]:2000");
+        assert_eq!(v6.monitor_path(), "tcp://[::1]:2000/monitor");
+        assert!(v6.monitor_socket().parse::<SocketAddr>().is_ok());
+
+        let name = spec(&[(MONITOR_HOST, "localhost")]);
+        assert!(Config::from_spec(&name).is_err());
+    }
+
+    #[test]
+    fn shares() {
+        let ok = spec(&[(PROCESS_SHARE, "0.7"), (SYSTEM_SHARE, "0.3")]);
+        let config = Config::from_spec(&ok).unwrap();
+        assert_eq!(config.system_share, 0.3);
+
+        let too_much = spec(&[(PROCESS_SHARE, "0.9"), (SYSTEM_SHARE, "0.3")]);
+        assert!(Config::from_spec(&too_much).is_err());
+    }
+
+    #[test]
+    fn stop_timeout() {
+        let config = Config::from_spec(&spec(&[(STOP_TIMEOUT, "0")])).unwrap();
+        assert_eq!(config.stop_timeout(), None);
+        assert!(Config::from_spec(&spec(&[(STOP_TIMEOUT, "-1")])).is_err());
+    }
+
+    #[test]
+    fn adaptive_interval() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(config.monitor_min_interval(), None);
+        assert!(!config.annotations().contains_key(MONITOR_MIN_INTERVAL));
+
+        let adaptive = spec(&[(MONITOR_MIN_INTERVAL, "100")]);
+        let config = Config::from_spec(&adaptive).unwrap();
+        assert_eq!(
+            config.monitor_min_interval(),
+            Some(Duration::from_millis(100))
+        );
+        assert_eq!(config.annotations()[MONITOR_FAST_THRESHOLD], "0.8");
+
+        let slower = spec(&[(MONITOR_MIN_INTERVAL, "5000")]);
+        assert!(Config::from_spec(&slower).is_err());
+        let threshold = spec(&[(MONITOR_FAST_THRESHOLD, "1.5")]);
+        assert!(Config::from_spec(&threshold).is_err());
+    }
+
+    #[test]
+    fn sources() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(
+            config.monitor_sources,
+            vec!["memory", "cpu", "network", "io"]
+        );
+
+        let some = spec(&[(MONITOR_SOURCES, "cpu, io,cpu")]);
+        let config = Config::from_spec(&some).unwrap();
+        assert_eq!(config.monitor_sources, vec!["cpu", "io"]);
+        assert_eq!(config.annotations()[MONITOR_SOURCES], "cpu,io");
+
+        let psi = spec(&[(MONITOR_SOURCES, "cpu,psi")]);
+        assert!(Config::from_spec(&psi).is_err());
+    }
+
+    #[test]
+    fn cpu_window() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(config.cpu_window(), Duration::from_secs(60));
+        let short = spec(&[(MONITOR_INTERVAL, "5000"), (CPU_WINDOW, "1000")]);
+        assert!(Config::from_spec(&short).is_err());
+    }
+
+    #[test]
+    fn custom_stale() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(config.custom_stale(), Duration::from_secs(30));
+        assert!(Config::from_spec(&spec(&[(CUSTOM_STALE, "0")])).is_err());
+    }
+
+    #[test]
+    fn release() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert!(!config.release_enabled());
+
+        let config = Config::from_spec(&spec(&[
+            (RELEASE_THRESHOLD, "0.9"),
+            (RELEASE_ESCALATION, "signal:USR1"),
+        ]))
+        .unwrap();
+        assert!(config.release_enabled());
+        assert_eq!(
+            config.release_escalation,
+            ReleaseEscalation::Signal(libc::SIGUSR1)
+        );
+        assert_eq!(config.annotations()[RELEASE_ESCALATION], "signal:USR1");
+
+        // the target has to be below the threshold
+        let bad = spec(&[(RELEASE_THRESHOLD, "0.7"), (RELEASE_TARGET, "0.8")]);
+        assert!(Config::from_spec(&bad).is_err());
+        let bad = spec(&[(RELEASE_ESCALATION, "signal:STOP")]);
+        assert!(Config::from_spec(&bad).is_err());
+    }
+
+    #[test]
+    fn oom() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(config.oom_policy, OomPolicy::Kernel);
+        assert!(!config.moves_memory());
+
+        let borrow = spec(&[(OOM_POLICY, "borrow")]);
+        let config = Config::from_spec(&borrow).unwrap();
+        assert!(config.moves_memory());
+        assert_eq!(config.annotations()[OOM_BORROW], "30000");
+
+        assert!(Config::from_spec(&spec(&[(OOM_POLICY, "ignore")])).is_err());
+
+        assert_eq!(config.oom_horizon(), None);
+        assert!(!config.annotations().contains_key(OOM_HORIZON));
+        let horizon = spec(&[(OOM_HORIZON, "60000")]);
+        let config = Config::from_spec(&horizon).unwrap();
+        assert_eq!(config.oom_horizon(), Some(Duration::from_secs(60)));
+        assert_eq!(config.annotations()[OOM_HORIZON], "60000");
+    }
+
+    #[test]
+    fn signals() {
+        assert_eq!(signal_number("SIGTERM"), Some(libc::SIGTERM));
+        assert_eq!(signal_number("TERM"), Some(libc::SIGTERM));
+        assert_eq!(signal_number("10"), Some(libc::SIGUSR1));
+        assert_eq!(signal_number("FOO"), None);
+
+        let config = Config::from_spec(&spec(&[
+            (SIGNALS_IGNORE, "HUP, WINCH"),
+            (SIGNALS_TRANSLATE, "SIGTERM:SIGINT"),
+        ]))
+        .unwrap();
+        assert_eq!(config.forwarded_signal(libc::SIGHUP), None);
+        assert_eq!(config.forwarded_signal(libc::SIGTERM), Some(libc::SIGINT));
+        assert_eq!(config.forwarded_signal(libc::SIGUSR1), Some(libc::SIGUSR1));
+        assert_eq!(config.annotations()[SIGNALS_TRANSLATE], "TERM:INT");
+
+        assert!(Config::from_spec(&spec(&[(SIGNALS_IGNORE, "CHLD")])).is_err());
+        let bad = spec(&[(SIGNALS_TRANSLATE, "TERM")]);
+        assert!(Config::from_spec(&bad).is_err());
+    }
+
+    #[test]
+    fn processes() {
+        let config = Config::from_spec(&spec(&[
+            (PROCESS_SHARE, "0.6"),
+            (PROCESSES, "helper"),
+            ("io.enya.processes.helper.args", "/bin/helper -v"),
+            ("io.enya.processes.helper.share", "0.2"),
+        ]))
+        .unwrap();
+        assert_eq!(config.processes.len(), 1);
+        assert_eq!(config.processes[0].args, vec!["/bin/helper", "-v"]);
+        assert_eq!(config.annotations()[PROCESSES], "helper");
+
+        // the defaults leave no room for another share
+        let too_much = spec(&[
+            (PROCESSES, "helper"),
+            ("io.enya.processes.helper.args", "/bin/helper"),
+            ("io.enya.processes.helper.share", "0.2"),
+        ]);
+        assert!(Config::from_spec(&too_much).is_err());
+
+        let no_args = spec(&[
+            (PROCESS_SHARE, "0.6"),
+            (PROCESSES, "helper"),
+            ("io.enya.processes.helper.share", "0.2"),
+        ]);
+        assert!(Config::from_spec(&no_args).is_err());
+        assert!(Config::from_spec(&spec(&[(PROCESSES, "system")])).is_err());
+    }
+
+    #[test]
+    fn process_commands() {
+        let groups = |args: &str, env: &str, cwd: &str| {
+            let mut annotations = vec![
+                (PROCESS_SHARE, "0.6"),
+                (PROCESSES, "helper"),
+                ("io.enya.processes.helper.args", args),
+                ("io.enya.processes.helper.share", "0.2"),
+            ];
+            if !env.is_empty() {
+                annotations.push(("io.enya.processes.helper.env", env));
+            }
+            if !cwd.is_empty() {
+                annotations.push(("io.enya.processes.helper.cwd", cwd));
+            }
+            Config::from_spec(&spec(&annotations)).map(|c| c.processes)
+        };
+        let args = r#"["sh", "-c", "exec helper --name 'a b'"]"#;
+        let env = r#"["MODE=fast", "GREETING=hello world"]"#;
+        let helpers = groups(args, env, "/srv/helper").unwrap();
+        let helper = &helpers[0];
+        assert_eq!(helper.args, vec!["sh", "-c", "exec helper --name 'a b'"]);
+        assert_eq!(helper.env, vec!["MODE=fast", "GREETING=hello world"]);
+        assert_eq!(helper.cwd, Some("/srv/helper".to_string()));
+
+        // the annotations read back the same
+        let config = Config {
+            processes: vec![helper.clone()],
+            ..Config::default()
+        };
+        let again = parse_groups(&config.annotations()).unwrap();
+        assert_eq!(&again[0], helper);
+
+        let base = vec!["PATH=/bin".to_string(), "MODE=slow".to_string()];
+        assert_eq!(
+            helper.env_with(&base),
+            vec!["PATH=/bin", "MODE=fast", "GREETING=hello world"]
+        );
+
+        // [ is a command too
+        let test = groups("[ -f /ready ]", "", "").unwrap();
+        assert_eq!(test[0].args, vec!["[", "-f", "/ready", "]"]);
+        assert!(groups("[]", "", "").is_err());
+        assert!(groups("/bin/helper", r#"["MODE"]"#, "").is_err());
+        assert!(groups("/bin/helper", "MODE=fast", "").is_err());
+        assert!(groups("/bin/helper", "", "srv").is_err());
+    }
+
+    #[test]
+    fn restart() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert!(!config.should_restart(1, 0));
+
+        let on_failure =
+            spec(&[(RESTART_POLICY, "on-failure"), (RESTART_MAX_RETRIES, "2")]);
+        let config = Config::from_spec(&on_failure).unwrap();
+        assert!(config.should_restart(1, 0));
+        assert!(!config.should_restart(0, 0));
+        assert!(!config.should_restart(1, 2));
+
+        let always = spec(&[
+            (RESTART_POLICY, "always"),
+            (RESTART_BACKOFF, "100"),
+            (RESTART_MAX_BACKOFF, "1000"),
+        ]);
+        let config = Config::from_spec(&always).unwrap();
+        assert_eq!(config.restart_backoff(0), Duration::from_millis(100));
+        assert_eq!(config.restart_backoff(2), Duration::from_millis(400));
+        assert_eq!(config.restart_backoff(10), Duration::from_millis(1000));
+        assert_eq!(config.restart_backoff(70), Duration::from_millis(1000));
+
+        let invalid = spec(&[(RESTART_POLICY, "sometimes")]);
+        assert!(Config::from_spec(&invalid).is_err());
+    }
+
+    #[test]
+    fn health() {
+        let config = Config::from_spec(&spec(&[])).unwrap();
+        assert_eq!(config.health_check, None);
+
+        let http = spec(&[(HEALTH_CHECK, "http:8080/healthz")]);
+        let config = Config::from_spec(&http).unwrap();
+        assert_eq!(
+            config.health_check,
+            Some(HealthCheck::Http {
+                port: 8080,
+                path: "/healthz".to_string()
+            })
+        );
+
+        let exec = spec(&[(HEALTH_CHECK, "exec: pg_isready -q")]);
+        let config = Config::from_spec(&exec).unwrap();
+        let args = vec!["pg_isready".to_string(), "-q".to_string()];
+        assert_eq!(config.health_check, Some(HealthCheck::Exec(args)));
+
+        let quoted = r#"exec:["sh", "-c", "test -f '/run/my app/ready'"]"#;
+        let config =
+            Config::from_spec(&spec(&[(HEALTH_CHECK, quoted)])).unwrap();
+        let check = config.health_check.unwrap();
+        let args = ["sh", "-c", "test -f '/run/my app/ready'"];
+        let args = args.iter().map(|a| a.to_string()).collect();
+        assert_eq!(check, HealthCheck::Exec(args));
+        assert_eq!(check.to_annotation().parse(), Ok(check));
+
+        let invalid = spec(&[(HEALTH_CHECK, "tcp:http")]);
+        assert!(Config::from_spec(&invalid).is_err());
+
+        // nothing would bring the Process back
+        let restart =
+            spec(&[(HEALTH_CHECK, "tcp:80"), (HEALTH_ACTION, "restart")]);
+        assert!(Config::from_spec(&restart).is_err());
+    }
+}
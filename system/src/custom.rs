@@ -0,0 +1,153 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Metrics pushed by the Process, one entry per series. A series is a
+/// metric name with one set of labels, keyed as `name{label="value",..}`
+/// with the labels sorted, or just `name` without labels.
+pub(crate) struct CustomMetrics {
+    max: usize,
+    stale: Duration,
+    // the metrics with the time they were pushed
+    series: HashMap<String, (api::CustomMetric, Instant)>,
+}
+
+impl CustomMetrics {
+    pub fn new(max: usize, stale: Duration) -> CustomMetrics {
+        CustomMetrics {
+            max,
+            stale,
+            series: HashMap::new(),
+        }
+    }
+
+    /// Adds or replaces the series of `metric`. Returns its key, or `None`
+    /// if it is new and there are already as many series as allowed.
+    pub fn push(
+        &mut self,
+        name: &str,
+        metric: api::CustomMetric,
+        at: Instant,
+    ) -> Option<String> {
+        let key = series_key(name, &metric);
+        if self.series.len() >= self.max && !self.series.contains_key(&key) {
+            return None;
+        }
+        self.series.insert(key.clone(), (metric, at));
+        Some(key)
+    }
+
+    /// Drops the series that were not pushed within the stale time
+    pub fn expire(&mut self, now: Instant) {
+        let stale = self.stale;
+        self.series
+            .retain(|_, &mut (_, pushed)| now.duration_since(pushed) < stale);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &api::CustomMetric)> {
+        self.series
+            .iter()
+            .map(|(key, &(ref metric, _))| (key, metric))
+    }
+}
+
+fn series_key(name: &str, metric: &api::CustomMetric) -> String {
+    let mut labels: Vec<_> = metric.get_labels().iter().collect();
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    labels.sort();
+    let labels: Vec<String> = labels
+        .into_iter()
+        .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+        .collect();
+    format!("{}{{{}}}", name, labels.join(","))
+}
+
+// escapes a label value like the Prometheus text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metric(value: f64, labels: &[(&str, &str)]) -> api::CustomMetric {
+        let mut metric = api::CustomMetric::new();
+        metric.set_value(value);
+        for &(label, v) in labels {
+            metric.mut_labels().insert(label.to_string(), v.to_string());
+        }
+        metric
+    }
+
+    #[test]
+    fn series() {
+        let mut custom = CustomMetrics::new(10, Duration::from_secs(30));
+        let now = Instant::now();
+        let get = metric(1.0, &[("method", "get"), ("code", "200")]);
+        let post = metric(2.0, &[("method", "post"), ("code", "200")]);
+        assert_eq!(
+            custom.push("requests", get, now).unwrap(),
+            "requests{code=\"200\",method=\"get\"}"
+        );
+        assert!(custom.push("requests", post, now).is_some());
+        assert_eq!(
+            custom.push("queue", metric(3.0, &[]), now).unwrap(),
+            "queue"
+        );
+        assert_eq!(custom.iter().count(), 3);
+
+        // the same labels replace the series
+        let again = metric(4.0, &[("code", "200"), ("method", "get")]);
+        custom.push("requests", again, now);
+        let values: Vec<f64> = custom
+            .iter()
+            .filter(|&(key, _)| key.starts_with("requests"))
+            .map(|(_, m)| m.get_value())
+            .collect();
+        assert_eq!(values.len(), 2);
+        assert!(values.contains(&4.0) && values.contains(&2.0));
+
+        let quoted = metric(5.0, &[("path", "/a\"b")]);
+        let key = custom.push("hits", quoted, now).unwrap();
+        assert_eq!(key, "hits{path=\"/a\\\"b\"}");
+    }
+
+    #[test]
+    fn stale() {
+        let mut custom = CustomMetrics::new(10, Duration::from_secs(30));
+        let start = Instant::now();
+        custom.push("old", metric(1.0, &[]), start);
+        let later = start + Duration::from_secs(20);
+        custom.push("new", metric(2.0, &[]), later);
+
+        custom.expire(start + Duration::from_secs(29));
+        assert_eq!(custom.iter().count(), 2);
+        custom.expire(start + Duration::from_secs(30));
+        let keys: Vec<&String> = custom.iter().map(|(key, _)| key).collect();
+        assert_eq!(keys, vec!["new"]);
+        // pushing again keeps a series alive
+        custom.push("new", metric(3.0, &[]), start + Duration::from_secs(45));
+        custom.expire(start + Duration::from_secs(60));
+        assert_eq!(custom.iter().count(), 1);
+    }
+
+    #[test]
+    fn limit() {
+        let mut custom = CustomMetrics::new(2, Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(custom.push("a", metric(1.0, &[]), now).is_some());
+        assert!(custom.push("a", metric(1.0, &[("x", "1")]), now).is_some());
+        // full, new series are dropped but known ones are still updated
+        assert!(custom.push("b", metric(1.0, &[]), now).is_none());
+        assert!(custom.push("a", metric(2.0, &[]), now).is_some());
+        assert_eq!(custom.iter().count(), 2);
+
+        custom.expire(now + Duration::from_secs(30));
+        assert!(custom.push("b", metric(1.0, &[]), now).is_some());
+    }
+}
@@ -4,6 +4,9 @@ use std::fmt;
 #[derive(Debug, Eq, PartialEq)]
 pub enum ErrorKind {
     ReadFailed,
+    ConnectFailed,
+    RegistrationFailed,
+    InvalidAnnotation(&'static str),
 }
 
 #[derive(Debug)]
@@ -14,10 +17,18 @@ pub struct Error {
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let msg = match self.kind {
-            ErrorKind::ReadFailed => "Gnable to read file",
-        };
-        write!(f, "{}", msg)
+        match self.kind {
+            ErrorKind::ReadFailed => write!(f, "Gnable to read file"),
+            ErrorKind::ConnectFailed => {
+                write!(f, "Unable to connect to monitor")
+            }
+            ErrorKind::RegistrationFailed => {
+                write!(f, "Unable to register the monitor")
+            }
+            ErrorKind::InvalidAnnotation(key) => {
+                write!(f, "Invalid value for annotation {}", key)
+            }
+        }
     }
 }
 
@@ -0,0 +1,163 @@
+use api::kompact_api::*;
+use kompact::default_components::DeadletterBox;
+use kompact::*;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use crate::error::ErrorKind::*;
+use crate::error::{Error, Result};
+
+/// Messages pushed by the System monitor
+#[derive(Debug)]
+pub enum Update {
+    Metrics(api::MetricReport),
+    Process(api::ProcessEvent),
+}
+
+// how long dropping the stream waits for the unsubscribe to be sent
+const UNSUBSCRIBE_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Subscribes to a System monitor and hands received reports
+/// over to a non-Kompact consumer, e.g. the `events` subcommand.
+/// Dropping it unsubscribes from the monitor.
+pub struct EventStream {
+    system: Option<KompicsSystem>,
+    listener: ActorRef,
+    updates: Receiver<Update>,
+}
+
+impl EventStream {
+    pub fn connect(monitor_path: &str) -> Result<EventStream> {
+        let monitor = ActorPath::from_str(monitor_path)
+            .map_err(|_| Error::new(ConnectFailed))?;
+
+        // the dispatcher binds any free port and replies are sent to the
+        // one it actually bound
+        let socket_addr =
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0);
+
+        let mut cfg = KompicsConfig::new();
+        cfg.label(String::from("EventStream"));
+        cfg.system_components(DeadletterBox::new, move || {
+            let net_config = NetworkConfig::new(socket_addr);
+            NetworkDispatcher::with_config(net_config)
+        });
+        let system = KompicsSystem::new(cfg);
+        if system.system_path().port() == 0 {
+            let _ = system.shutdown();
+            return Err(Error::new(ConnectFailed));
+        }
+
+        let (tx, rx) = channel();
+        let (listener, _) =
+            system.create_and_register(move || EventListener::new(monitor, tx));
+        system.start(&listener);
+
+        Ok(EventStream {
+            listener: listener.actor_ref(),
+            system: Some(system),
+            updates: rx,
+        })
+    }
+
+    /// Waits for the next update. Returns `Ok(None)` on timeout.
+    pub fn next(&self, timeout: Duration) -> Result<Option<Update>> {
+        match self.updates.recv_timeout(timeout) {
+            Ok(update) => Ok(Some(update)),
+            Err(RecvTimeoutError::Timeout) => Ok(None),
+            Err(RecvTimeoutError::Disconnected) => {
+                Err(Error::new(ConnectFailed))
+            }
+        }
+    }
+
+    /// Unsubscribes and stops the stream, the same as dropping it
+    pub fn shutdown(self) {
+        drop(self)
+    }
+}
+
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        let system = match self.system.take() {
+            Some(system) => system,
+            None => return,
+        };
+        let (tx, rx) = channel();
+        self.listener
+            .tell(Box::new(Unsubscribe { sent: tx }), &system);
+        let _ = rx.recv_timeout(UNSUBSCRIBE_TIMEOUT);
+        let _ = system.shutdown();
+    }
+}
+
+// asks the listener to unsubscribe, acknowledged on `sent`
+struct Unsubscribe {
+    sent: Sender<()>,
+}
+
+#[derive(ComponentDefinition)]
+struct EventListener {
+    ctx: ComponentContext<EventListener>,
+    monitor: ActorPath,
+    updates: Sender<Update>,
+}
+
+impl EventListener {
+    fn new(monitor: ActorPath, updates: Sender<Update>) -> Self {
+        EventListener {
+            ctx: ComponentContext::new(),
+            monitor,
+            updates,
+        }
+    }
+}
+
+impl Provide<ControlPort> for EventListener {
+    fn handle(&mut self, event: ControlEvent) {
+        if let ControlEvent::Start = event {
+            debug!(self.ctx.log(), "Subscribing to {}", self.monitor);
+            let msg = api::Subscribe::new();
+            self.monitor.tell(msg, self);
+        }
+    }
+}
+
+impl Actor for EventListener {
+    fn receive_local(&mut self, _sender: ActorRef, msg: Box<Any>) {
+        if let Ok(unsubscribe) = msg.downcast::<Unsubscribe>() {
+            debug!(self.ctx.log(), "Unsubscribing from {}", self.monitor);
+            self.monitor.tell(api::Unsubscribe::new(), self);
+            let _ = unsubscribe.sent.send(());
+        }
+    }
+
+    fn receive_message(
+        &mut self,
+        sender: ActorPath,
+        ser_id: u64,
+        buf: &mut Buf,
+    ) {
+        let update = match ser_id {
+            serids::METRIC_REPORT => {
+                // the updates are queued, ready for the next report
+                let mut credit = api::Credit::new();
+                credit.set_credits(1);
+                self.monitor.tell(credit, self);
+                ProtoSer::deserialise(buf).map(Update::Metrics)
+            }
+            serids::PROCESS_EVENT => {
+                ProtoSer::deserialise(buf).map(Update::Process)
+            }
+            _ => Err(SerError::InvalidData(format!("serid {}", ser_id))),
+        };
+        if let Ok(update) = update {
+            // the receiving end is gone when the consumer has shut down
+            let _ = self.updates.send(update);
+        } else {
+            error!(self.ctx.log(), "Got unexpected message from {}", sender);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+use kompact::*;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::config::{Config, HealthAction, HealthCheck};
+
+const POLL_MS: u64 = 10;
+
+/// Exit codes of children that the System reaped on behalf of the
+/// components that spawned them, e.g. exec health checks. Only the pids
+/// spawned through `spawn` are recorded, so orphans the System reaps don't
+/// pile up in the table.
+#[derive(Clone, Default)]
+pub struct Reaped(Arc<Mutex<HashMap<i32, Option<i32>>>>);
+
+impl Reaped {
+    pub fn insert(&self, pid: i32, code: i32) {
+        if let Some(exit) = self.0.lock().unwrap().get_mut(&pid) {
+            *exit = Some(code);
+        }
+    }
+
+    // the lock is held until the pid is watched, so the System can't reap
+    // the child before its exit code would be recorded
+    fn spawn(&self, command: &mut Command) -> io::Result<Child> {
+        let mut reaped = self.0.lock().unwrap();
+        let child = command.spawn()?;
+        reaped.insert(child.id() as i32, None);
+        Ok(child)
+    }
+
+    fn take(&self, pid: i32) -> Option<i32> {
+        let mut reaped = self.0.lock().unwrap();
+        let code = *reaped.get(&pid)?;
+        if code.is_some() {
+            reaped.remove(&pid);
+        }
+        code
+    }
+
+    fn forget(&self, pid: i32) {
+        self.0.lock().unwrap().remove(&pid);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Check {}
+
+// outcome of a check, sent back by the thread that ran it
+struct Checked(Result<(), String>);
+
+#[derive(ComponentDefinition)]
+pub struct HealthChecker {
+    ctx: ComponentContext<HealthChecker>,
+    check: HealthCheck,
+    interval_ms: u64,
+    timeout: Duration,
+    retries: u32,
+    action: HealthAction,
+    monitor: ActorRef,
+    reaped: Reaped,
+    stop: Arc<AtomicBool>,
+    procs_path: String,
+    failures: u32,
+    // whether a check is still running on its thread
+    running: bool,
+    timer: Option<ScheduledTimer>,
+}
+
+impl HealthChecker {
+    pub fn new(
+        config: &Config,
+        check: HealthCheck,
+        monitor: ActorRef,
+        reaped: Reaped,
+        stop: Arc<AtomicBool>,
+        procs_path: String,
+    ) -> HealthChecker {
+        HealthChecker {
+            ctx: ComponentContext::new(),
+            check,
+            interval_ms: config.health_interval_ms,
+            timeout: Duration::from_millis(config.health_timeout_ms),
+            retries: config.health_retries,
+            action: config.health_action,
+            monitor,
+            reaped,
+            stop,
+            procs_path,
+            failures: 0,
+            running: false,
+            timer: None,
+        }
+    }
+
+    // Runs the check on its own thread, it may block for up to the
+    // timeout and must not hold up the executor.
+    fn run_check(&mut self) {
+        if self.running {
+            debug!(self.ctx.log(), "Previous health check still running");
+            return;
+        }
+        let check = self.check.clone();
+        let timeout = self.timeout;
+        let reaped = self.reaped.clone();
+        let me = self.actor_ref();
+        let spawned = thread::Builder::new()
+            .name("health-check".to_string())
+            .spawn(move || {
+                let result = match check {
+                    HealthCheck::Exec(ref args) => {
+                        exec_check(args, timeout, &reaped)
+                    }
+                    HealthCheck::Tcp(port) => tcp_check(port, timeout),
+                    HealthCheck::Http { port, ref path } => {
+                        http_check(port, path, timeout)
+                    }
+                };
+                me.tell(Box::new(Checked(result)), &me);
+            });
+        match spawned {
+            Ok(_) => self.running = true,
+            Err(e) => self.checked(Err(format!("failed to run check: {}", e))),
+        }
+    }
+
+    fn checked(&mut self, result: Result<(), String>) {
+        let mut health = api::Health::new();
+        match result {
+            Ok(()) => {
+                self.failures = 0;
+                health.set_status(api::Health_Status::HEALTHY);
+            }
+            Err(e) => {
+                self.failures += 1;
+                debug!(self.ctx.log(), "Health check failed: {}", e);
+                if self.failures >= self.retries {
+                    health.set_status(api::Health_Status::UNHEALTHY);
+                }
+                health.set_last_error(e);
+            }
+        }
+        health.set_failures(self.failures);
+        self.monitor.tell(Box::new(health), self);
+
+        if self.failures == self.retries {
+            self.act();
+        }
+    }
+
+    fn act(&mut self) {
+        let signal = match self.action {
+            HealthAction::None => return,
+            // the restart policy brings the Process back
+            HealthAction::Restart => libc::SIGKILL,
+            HealthAction::Kill => {
+                self.stop.store(true, Ordering::SeqCst);
+                libc::SIGKILL
+            }
+        };
+        warn!(
+            self.ctx.log(),
+            "Process is unhealthy, {}ing it",
+            self.action.as_str()
+        );
+        self.failures = 0;
+        for pid in procs(&self.procs_path) {
+            unsafe {
+                libc::kill(pid, signal);
+            }
+        }
+    }
+
+    fn stop_checks(&mut self) {
+        if let Some(timer) = self.timer.clone() {
+            self.cancel_timer(timer);
+            self.timer = None;
+        }
+    }
+}
+
+impl Provide<ControlPort> for HealthChecker {
+    fn handle(&mut self, event: ControlEvent) {
+        match event {
+            ControlEvent::Start => {
+                let interval = Duration::from_millis(self.interval_ms);
+                let timer =
+                    self.schedule_periodic(interval, interval, |self_c, _| {
+                        self_c.actor_ref().tell(Box::new(Check {}), self_c);
+                    });
+                self.timer = Some(timer);
+            }
+            ControlEvent::Stop => self.stop_checks(),
+            ControlEvent::Kill => self.stop_checks(),
+        }
+    }
+}
+
+impl Actor for HealthChecker {
+    fn receive_local(&mut self, _sender: ActorRef, msg: Box<Any>) {
+        let msg = match msg.downcast::<Check>() {
+            Ok(_check) => return self.run_check(),
+            Err(msg) => msg,
+        };
+        if let Ok(checked) = msg.downcast::<Checked>() {
+            self.running = false;
+            self.checked(checked.0);
+        }
+    }
+    fn receive_message(
+        &mut self,
+        sender: ActorPath,
+        _ser_id: u64,
+        _buf: &mut Buf,
+    ) {
+        error!(self.ctx.log(), "Got unexpected message from {}", sender);
+    }
+}
+
+/// Pids listed in the cgroup.procs file at `path`
+pub(crate) fn procs(path: &str) -> Vec<i32> {
+    match File::open(path) {
+        Ok(f) => BufReader::new(f)
+            .lines()
+            .filter_map(|l| l.ok().and_then(|l| l.trim().parse().ok()))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+fn exec_check(
+    args: &[String],
+    timeout: Duration,
+    reaped: &Reaped,
+) -> Result<(), String> {
+    let mut command = Command::new(&args[0]);
+    command
+        .args(&args[1..])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    let mut child = reaped
+        .spawn(&mut command)
+        .map_err(|e| format!("failed to run {}: {}", args[0], e))?;
+    let pid = child.id() as i32;
+    let start = Instant::now();
+    let result = loop {
+        let exited = match child.try_wait() {
+            Ok(Some(status)) => Some(status.code().unwrap_or(-1)),
+            Ok(None) => None,
+            // the System reaps all of its children
+            Err(_) => reaped.take(pid),
+        };
+        match exited {
+            Some(0) => break Ok(()),
+            Some(code) => {
+                break Err(format!("{} exited with {}", args[0], code))
+            }
+            None if start.elapsed() > timeout => {
+                let _ = child.kill();
+                break Err(format!("{} timed out", args[0]));
+            }
+            None => thread::sleep(Duration::from_millis(POLL_MS)),
+        }
+    };
+    reaped.forget(pid);
+    result
+}
+
+fn connect(port: u16, timeout: Duration) -> Result<TcpStream, String> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    TcpStream::connect_timeout(&addr, timeout)
+        .map_err(|e| format!("failed to connect to {}: {}", addr, e))
+}
+
+fn tcp_check(port: u16, timeout: Duration) -> Result<(), String> {
+    connect(port, timeout).map(|_| ())
+}
+
+fn http_check(port: u16, path: &str, timeout: Duration) -> Result<(), String> {
+    let mut stream = connect(port, timeout)?;
+    let io_err = |e: std::io::Error| format!("GET {} failed: {}", path, e);
+    stream.set_read_timeout(Some(timeout)).map_err(io_err)?;
+    stream.set_write_timeout(Some(timeout)).map_err(io_err)?;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+        path
+    );
+    stream.write_all(request.as_bytes()).map_err(io_err)?;
+
+    // only the status line is of interest
+    let mut status = String::new();
+    BufReader::new(stream.take(1024))
+        .read_line(&mut status)
+        .map_err(io_err)?;
+    let code = status
+        .split_whitespace()
+        .nth(1)
+        .and_then(|c| c.parse::<u16>().ok())
+        .ok_or_else(|| format!("GET {}: invalid response", path))?;
+    if code >= 200 && code < 400 {
+        Ok(())
+    } else {
+        Err(format!("GET {} returned {}", path, code))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    fn serve(response: &'static str) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            if let Ok((mut stream, _)) = listener.accept() {
+                let mut buf = [0; 512];
+                let _ = stream.read(&mut buf);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn tcp() {
+        let timeout = Duration::from_millis(500);
+        let port = serve("");
+        assert!(tcp_check(port, timeout).is_ok());
+
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        assert!(tcp_check(closed, timeout).is_err());
+    }
+
+    #[test]
+    fn http() {
+        let timeout = Duration::from_millis(500);
+        let port = serve("HTTP/1.1 204 No Content\r\n\r\n");
+        assert!(http_check(port, "/healthz", timeout).is_ok());
+
+        let port = serve("HTTP/1.1 503 Service Unavailable\r\n\r\n");
+        assert!(http_check(port, "/healthz", timeout).is_err());
+    }
+
+    #[test]
+    fn exec() {
+        let timeout = Duration::from_millis(1000);
+        let reaped = Reaped::default();
+        let ok = vec!["true".to_string()];
+        assert!(exec_check(&ok, timeout, &reaped).is_ok());
+        let fail = vec!["false".to_string()];
+        assert!(exec_check(&fail, timeout, &reaped).is_err());
+        assert!(reaped.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn reaped() {
+        let reaped = Reaped::default();
+        // orphans aren't recorded
+        reaped.insert(1, 0);
+        assert!(reaped.0.lock().unwrap().is_empty());
+
+        let mut child = reaped.spawn(&mut Command::new("true")).unwrap();
+        let pid = child.id() as i32;
+        assert_eq!(reaped.take(pid), None);
+        reaped.insert(pid, 3);
+        assert_eq!(reaped.take(pid), Some(3));
+        assert_eq!(reaped.take(pid), None);
+        child.wait().unwrap();
+    }
+}
@@ -4,45 +4,90 @@ extern crate kompact;
 extern crate slog;
 extern crate api;
 extern crate caps;
+extern crate libc;
+extern crate log;
+#[macro_use]
+extern crate serde_json;
+extern crate slog_async;
 
+pub mod config;
+mod custom;
 mod error;
+pub mod events;
+mod health;
+mod logging;
 mod monitor;
+mod oom;
+mod registry;
+mod release;
+mod sampling;
 
+use crate::config::Config;
+use crate::config::OomPolicy;
+use crate::health::{HealthChecker, Reaped};
+use crate::oom::{OomFds, OomGuard};
+use crate::release::Release;
 use caps::{CapSet, Capability};
 use kompact::default_components::DeadletterBox;
 use kompact::prelude::*;
 use oci::Spec;
 use std::fs::File;
-use std::net::SocketAddr;
-use std::net::{IpAddr, Ipv4Addr};
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
 
 use crate::error::ErrorKind::*;
 use crate::error::*;
 
 const CGROUPS_PATH: &str = "/sys/fs/cgroup";
-const SYSTEM_HOST: &str = "127.0.0.1";
-const SYSTEM_PORT: u16 = 2000;
 const DEFAULT_INTERFACE: &str = "eth0";
 const MONITOR_CGROUP: &str = "process";
+const REPORT_TIMEOUT_MS: u64 = 1000;
+const REGISTRATION_TIMEOUT_MS: u64 = 5000;
+
+/// Fds enya passes to the System
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Fds {
+    /// memfd of the metrics page, see `api::shm`
+    pub page: Option<RawFd>,
+    /// Memory cgroup dir of the process cgroup, which stays writable after
+    /// the cgroup mount is read-only
+    pub process_memory: Option<RawFd>,
+    /// Memory cgroup dir of the system cgroup
+    pub system_memory: Option<RawFd>,
+    /// eventfd notified when the process cgroup is out of memory
+    pub oom_events: Option<RawFd>,
+    /// File in the state dir the OOMs of the Process are recorded in
+    pub oom_record: Option<RawFd>,
+}
 
 pub struct System {
     linux_spec: Spec,
+    config: Config,
     cgroups_path: String,
     system: KompicsSystem,
+    fds: Fds,
 }
 
 impl System {
     #[cfg(target_os = "linux")]
-    pub fn new(spec: Spec, cpath: Option<String>) -> Result<System> {
+    pub fn new(spec: Spec, cpath: Option<String>, fds: Fds) -> Result<System> {
         let path = cpath.unwrap_or_else(|| String::from(CGROUPS_PATH));
 
         let _ = System::check_cgroups(path.clone())
             .map_err(|e| Error::with_cause(ReadFailed, e));
 
+        let config = Config::from_spec(&spec)?;
+        let system = System::system_setup(&config);
+
         Ok(System {
             linux_spec: spec,
+            config,
             cgroups_path: path,
-            system: System::system_setup(),
+            system,
+            fds,
         })
     }
 
@@ -59,15 +104,12 @@ impl System {
         net_admin.unwrap_or(false)
     }
 
-    fn system_setup() -> KompicsSystem {
-        let ip_addr = SYSTEM_HOST
-            .parse()
-            .unwrap_or_else(|_| IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)));
-
-        let socket_addr = SocketAddr::new(ip_addr, SYSTEM_PORT);
+    fn system_setup(config: &Config) -> KompicsSystem {
+        let socket_addr = config.monitor_addr();
         let mut cfg = KompicsConfig::new();
 
         cfg.label(String::from("System"));
+        cfg.logger(logging::logger());
 
         cfg.system_components(DeadletterBox::new, move || {
             let net_config = NetworkConfig::new(socket_addr);
@@ -77,13 +119,47 @@ impl System {
         KompicsSystem::new(cfg)
     }
 
-    pub fn start(self) {
+    /// Starts the monitor. Returns once the monitor is reachable, the
+    /// returned handle is used to report on the Process to its subscribers.
+    pub fn start(self) -> Result<SystemHandle> {
         info!(
             self.system.logger(),
-            "Starting System at {}:{}", SYSTEM_HOST, SYSTEM_PORT
+            "Starting System at {}",
+            self.config.monitor_socket()
         );
 
         let cpath = self.cgroups_path.clone();
+        let config = self.config.clone();
+        let procs_path = format!(
+            "{}/memory/{}/cgroup.procs",
+            self.cgroups_path, MONITOR_CGROUP
+        );
+        // the page is optional, without a sealed one the Process finds it
+        // never marked as valid
+        let page = match self.fds.page.map(api::shm::Writer::new) {
+            Some(Ok(writer)) => Some(writer),
+            Some(Err(e)) => {
+                warn!(
+                    self.system.logger(),
+                    "Not publishing metrics into the shared page: {}", e
+                );
+                unsafe {
+                    libc::close(self.fds.page.unwrap());
+                }
+                None
+            }
+            None => None,
+        };
+        let release = if self.config.release_enabled() {
+            Some(Release::new(
+                &self.config,
+                self.fds.process_memory,
+                self.fds.system_memory,
+                procs_path.clone(),
+            ))
+        } else {
+            None
+        };
         let (monitor, _m) = self.system.create_and_register(move || {
             let interface = if net::find_interface(DEFAULT_INTERFACE) {
                 Some(String::from(DEFAULT_INTERFACE))
@@ -95,21 +171,202 @@ impl System {
                 cpath,
                 MONITOR_CGROUP.to_string(),
                 interface,
-                None, // Use default timeout
+                &config,
+                page,
+                release,
             )
         });
 
-        let _ = self
+        let registered = self
             .system
-            .register_by_alias(&monitor, "monitor")
-            .await_timeout(std::time::Duration::from_millis(250))
-            .expect("Failed to register enya monitor");
+            .register_by_alias(&monitor, self.config.monitor_alias())
+            .await_timeout(Duration::from_millis(REGISTRATION_TIMEOUT_MS));
+        match registered {
+            Ok(Ok(_)) => {}
+            _ => return Err(Error::new(RegistrationFailed)),
+        }
 
         self.system.start(&monitor);
+
+        let reaped = Reaped::default();
+        let stop = Arc::new(AtomicBool::new(false));
+        if let Some(check) = self.config.health_check.clone() {
+            let config = self.config.clone();
+            let monitor_ref = monitor.actor_ref();
+            let (reaped, stop) = (reaped.clone(), stop.clone());
+            let procs_path = procs_path.clone();
+            let (checker, _h) = self.system.create_and_register(move || {
+                HealthChecker::new(
+                    &config,
+                    check,
+                    monitor_ref,
+                    reaped,
+                    stop,
+                    procs_path,
+                )
+            });
+            self.system.start(&checker);
+        }
+
+        match self.fds.oom_events {
+            Some(events) if self.config.oom_policy != OomPolicy::Kernel => {
+                let fds = OomFds {
+                    events,
+                    record: self.fds.oom_record,
+                    process_memory: self.fds.process_memory,
+                    system_memory: self.fds.system_memory,
+                };
+                let config = self.config.clone();
+                let monitor_ref = monitor.actor_ref();
+                let memory_path =
+                    format!("{}/memory/{}/", self.cgroups_path, MONITOR_CGROUP);
+                let procs_path = procs_path.clone();
+                let (guard, _g) = self.system.create_and_register(move || {
+                    OomGuard::new(
+                        &config,
+                        fds,
+                        monitor_ref,
+                        memory_path,
+                        procs_path,
+                    )
+                });
+                self.system.start(&guard);
+            }
+            _ => {}
+        }
+
+        Ok(SystemHandle {
+            system: self.system,
+            monitor: monitor.actor_ref(),
+            reaped,
+            stop,
+            procs_path,
+        })
+    }
+}
+
+pub struct SystemHandle {
+    system: KompicsSystem,
+    monitor: ActorRef,
+    reaped: Reaped,
+    stop: Arc<AtomicBool>,
+    procs_path: String,
+}
+
+impl SystemHandle {
+    /// Records the exit code of a child other than the Process, which may
+    /// have been started by one of the System components
+    pub fn child_reaped(&self, pid: i32, exit_code: i32) {
+        self.reaped.insert(pid, exit_code);
+    }
+
+    /// Whether a component asked for the Process to stay down
+    pub fn stop_requested(&self) -> bool {
+        self.stop.load(Ordering::SeqCst)
+    }
+
+    pub fn process_exited(
+        &self,
+        pid: i32,
+        exit_code: i32,
+        signal: i32,
+        restarting: bool,
+    ) {
+        let mut exited = api::ProcessExited::new();
+        exited.set_pid(pid);
+        exited.set_exit_code(exit_code);
+        exited.set_signal(signal);
+        exited.set_restarting(restarting);
+        self.exited(exited);
+    }
+
+    /// A process of the additional process group `group` exited
+    pub fn group_exited(
+        &self,
+        group: &str,
+        pid: i32,
+        exit_code: i32,
+        signal: i32,
+    ) {
+        let mut exited = api::ProcessExited::new();
+        exited.set_pid(pid);
+        exited.set_exit_code(exit_code);
+        exited.set_signal(signal);
+        exited.set_group(group.to_string());
+        self.exited(exited);
+    }
+
+    fn exited(&self, exited: api::ProcessExited) {
+        let mut event = api::ProcessEvent::new();
+        event.set_exited(exited);
+        self.monitor.tell(Box::new(event), &self.system);
+    }
+
+    pub fn process_restarted(&self, pid: i32, restarts: u32) {
+        let mut restarted = api::ProcessRestarted::new();
+        restarted.set_pid(pid);
+        restarted.set_restarts(restarts);
+        let mut event = api::ProcessEvent::new();
+        event.set_restarted(restarted);
+        self.monitor.tell(Box::new(event), &self.system);
+    }
+
+    pub fn process_stopping(
+        &self,
+        pid: i32,
+        signal: i32,
+        timeout: Option<Duration>,
+    ) {
+        let mut stopping = api::ProcessStopping::new();
+        stopping.set_pid(pid);
+        stopping.set_signal(signal);
+        if let Some(t) = timeout {
+            let ms = t.as_secs() * 1000 + u64::from(t.subsec_millis());
+            stopping.set_timeout_ms(ms);
+        }
+        let mut event = api::ProcessEvent::new();
+        event.set_stopping(stopping);
+        self.monitor.tell(Box::new(event), &self.system);
+    }
+
+    /// Sends `signal` to every process in the process cgroup
+    pub fn signal_process(&self, signal: i32) {
+        for pid in health::procs(&self.procs_path) {
+            unsafe {
+                libc::kill(pid, signal);
+            }
+        }
+    }
+
+    pub fn kill_process(&self) {
+        self.signal_process(libc::SIGKILL)
+    }
+
+    /// Collects the metrics right away, the report is also sent to the
+    /// subscribers
+    pub fn report(&self) -> Option<api::MetricReport> {
+        self.request_report(false)
+    }
+
+    /// Collects the metrics one last time and sends them to the
+    /// subscribers. No more reports are sent afterwards.
+    pub fn final_report(&self) -> Option<api::MetricReport> {
+        self.request_report(true)
+    }
+
+    fn request_report(&self, last: bool) -> Option<api::MetricReport> {
+        let (reply, rx) = channel();
+        let request = monitor::ReportRequest { reply, last };
+        self.monitor.tell(Box::new(request), &self.system);
+        rx.recv_timeout(Duration::from_millis(REPORT_TIMEOUT_MS))
+            .ok()
     }
 
     pub fn shutdown(self) {
-        self.system.shutdown().expect("Could not exit properly")
+        let log = self.system.logger().clone();
+        if let Err(e) = self.system.shutdown() {
+            warn!(log, "Could not exit properly: {:?}", e);
+        }
     }
 }
 
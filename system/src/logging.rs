@@ -0,0 +1,44 @@
+use kompact::KompicsLogger;
+use slog::{Drain, Level, Never, OwnedKVList, Record};
+use std::sync::Arc;
+
+/// Forwards Kompact's slog records to the `log` facade, so that the
+/// System ends up in the same sink as the rest of the runtime.
+struct LogDrain;
+
+impl Drain for LogDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(
+        &self,
+        record: &Record,
+        _values: &OwnedKVList,
+    ) -> std::result::Result<(), Never> {
+        let level = match record.level() {
+            Level::Critical | Level::Error => log::Level::Error,
+            Level::Warning => log::Level::Warn,
+            Level::Info => log::Level::Info,
+            Level::Debug => log::Level::Debug,
+            Level::Trace => log::Level::Trace,
+        };
+        if level <= log::max_level() {
+            log::logger().log(
+                &log::Record::builder()
+                    .args(*record.msg())
+                    .level(level)
+                    .target(record.module())
+                    .module_path(Some(record.module()))
+                    .file(Some(record.file()))
+                    .line(Some(record.line()))
+                    .build(),
+            );
+        }
+        Ok(())
+    }
+}
+
+pub fn logger() -> KompicsLogger {
+    let drain = slog_async::Async::new(LogDrain.fuse()).build().fuse();
+    slog::Logger::root_typed(Arc::new(drain), o!())
+}
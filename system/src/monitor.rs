@@ -2,33 +2,150 @@ use api::protobuf::Message;
 use bytes::Buf;
 use kompact::prelude::BufMut;
 use kompact::*;
-use std::time::Duration;
+use std::cmp;
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 
 use api::kompact_api::ProtoSer;
 use api::kompact_api::*;
+use api::shm;
+
+use crate::config::Config;
+use crate::custom::CustomMetrics;
+use crate::registry::{Registry, Target};
+use crate::release::{Release, Step};
+use crate::sampling::Sampling;
 
-use stats::cpu::Cpu;
-use stats::io::*;
 use stats::memory::*;
-use stats::network::*;
 
-const DEFAULT_TIMEOUT_MS: u64 = 2000;
+// upper bound of distinct metrics pushed by the Process
+const MAX_CUSTOM_METRICS: usize = 1024;
 
 #[derive(Clone, Copy)]
 struct Collect {}
 
+fn duration_ms(d: Duration) -> u64 {
+    d.as_secs() * 1000 + u64::from(d.subsec_millis())
+}
+
+// A subscriber of the monitor, with the reports it may still be sent
+struct Subscription {
+    path: ActorPath,
+    // None if the subscriber is sent every report
+    credits: Option<u32>,
+    // the latest report it had no credits for
+    pending: Option<Encoded>,
+    // reports replaced by a later one while waiting for credits
+    dropped: u64,
+}
+
+impl Subscription {
+    fn new(path: ActorPath, subscribe: &api::Subscribe) -> Subscription {
+        let credits = if subscribe.get_unbounded() {
+            None
+        } else {
+            Some(cmp::max(subscribe.get_credits(), 1))
+        };
+        Subscription {
+            path,
+            credits,
+            pending: None,
+            dropped: 0,
+        }
+    }
+
+    // the report to send right away, if any
+    fn offer(&mut self, report: Encoded) -> Option<Encoded> {
+        match self.credits {
+            None => Some(report),
+            Some(0) => {
+                if self.pending.replace(report).is_some() {
+                    self.dropped += 1;
+                }
+                None
+            }
+            Some(ref mut credits) => {
+                *credits -= 1;
+                Some(report)
+            }
+        }
+    }
+
+    // the pending report to send with the new credits, if any
+    fn grant(&mut self, credits: u32) -> Option<Encoded> {
+        let available = match self.credits.as_mut() {
+            Some(available) => available,
+            None => return None,
+        };
+        *available = available.saturating_add(credits);
+        if *available == 0 {
+            return None;
+        }
+        let pending = self.pending.take();
+        if pending.is_some() {
+            *available -= 1;
+        }
+        pending
+    }
+}
+
+/// Asks for an immediate collection, which is also sent back on `reply`.
+/// Collection stops afterwards if it is the `last` one.
+pub struct ReportRequest {
+    pub reply: Sender<api::MetricReport>,
+    pub last: bool,
+}
+
+// cgroup metrics of an additional process group
+struct Group {
+    name: String,
+    sources: Registry,
+}
+
+impl Group {
+    fn new(path: &str, name: String, config: &Config) -> Group {
+        let target = Target {
+            cgroups_path: path,
+            cgroup: &name,
+            interface: None,
+            cpu_window: config.cpu_window(),
+        };
+        Group {
+            sources: Registry::new(&config.monitor_sources, &target),
+            name,
+        }
+    }
+
+    fn report(&self) -> api::MetricReport {
+        let mut report = api::MetricReport::new();
+        report.set_id(self.name.clone());
+        self.sources.report(&mut report);
+        report
+    }
+}
+
 #[derive(ComponentDefinition)]
 pub struct Monitor {
     ctx: ComponentContext<Monitor>,
-    timeout_ms: u64,
+    sampling: Sampling,
     collect_timer: Option<ScheduledTimer>,
     cgroups_path: String,
-    memory: Memory,
-    cpu: Cpu,
-    network: Option<Network>,
-    io: Option<Io>,
-    subscribers: Vec<ActorPath>,
+    sources: Registry,
+    subscribers: Vec<Subscription>,
     cgroup_name: String,
+    health: Option<api::Health>,
+    groups: Vec<Group>,
+    page: Option<shm::Writer>,
+    // metrics pushed by the Process
+    custom: CustomMetrics,
+    release: Option<Release>,
+    // endpoints of the Process that are asked to release memory
+    releasers: Vec<ActorPath>,
+    oom_horizon: Option<Duration>,
+    // whether subscribers were alerted since the prediction was last above
+    // the horizon
+    oom_alerted: bool,
 }
 
 impl Monitor {
@@ -36,82 +153,280 @@ impl Monitor {
         path: String,
         cgroup_name: String,
         interface: Option<String>,
-        timeout: Option<u64>,
+        config: &Config,
+        page: Option<shm::Writer>,
+        release: Option<Release>,
     ) -> Monitor {
-        let mem_path = format!("{}/memory/{}/", path, cgroup_name);
-        let cpu_path = format!("{}/cpu/{}/", path, cgroup_name);
-        let blkio_path = format!("{}/blkio/{}/", path, cgroup_name);
+        let target = Target {
+            cgroups_path: &path,
+            cgroup: &cgroup_name,
+            interface: interface.as_ref().map(String::as_str),
+            cpu_window: config.cpu_window(),
+        };
+        let sources = Registry::new(&config.monitor_sources, &target);
 
         Monitor {
             ctx: ComponentContext::new(),
-            timeout_ms: timeout.unwrap_or(DEFAULT_TIMEOUT_MS),
+            sampling: Sampling::new(config),
             collect_timer: None,
             cgroups_path: path.clone(),
-            memory: Memory::new(mem_path),
-            cpu: Cpu::new(cpu_path),
-            network: interface.and_then(|i| Some(Network::new(i))),
-            io: Some(Io::new(blkio_path)),
+            sources,
             subscribers: Vec::new(),
             cgroup_name,
+            health: None,
+            groups: config
+                .processes
+                .iter()
+                .map(|g| Group::new(&path, g.name.clone(), config))
+                .collect(),
+            page,
+            custom: CustomMetrics::new(
+                MAX_CUSTOM_METRICS,
+                config.custom_stale(),
+            ),
+            release,
+            releasers: Vec::new(),
+            oom_horizon: config.oom_horizon(),
+            oom_alerted: false,
         }
     }
 
     fn create_report(&mut self) -> api::MetricReport {
         let mut report = api::MetricReport::new();
         report.set_id(String::from("process"));
+        report.set_interval_ms(duration_ms(self.sampling.interval()));
 
-        let mut mem = api::Memory::new();
-        mem.set_usage(self.memory.usage);
-        mem.set_limit(self.memory.limit);
-        report.set_memory(mem);
+        self.sources.report(&mut report);
 
-        let mut cpu = api::Cpu::new();
-        cpu.set_total(self.cpu.total_usage);
-        cpu.set_system(self.cpu.system_usage);
-        report.set_cpu(cpu);
+        if let Some(ref health) = self.health {
+            report.set_health(health.clone());
+        }
 
-        if let Some(net) = self.network.as_mut() {
-            let mut network = api::Network::new();
-            network.set_tx_bytes(net.tx_bytes);
-            network.set_tx_packets(net.tx_packets);
-            network.set_rx_bytes(net.rx_bytes);
-            network.set_rx_packets(net.rx_packets);
-            report.set_network(network);
+        for group in &self.groups {
+            report.mut_groups().push(group.report());
         }
 
-        if let Some(io) = self.io.as_mut() {
-            let mut io_obj = api::Io::new();
-            io_obj.set_read(io.read);
-            io_obj.set_write(io.write);
-            report.set_io(io_obj);
+        self.custom.expire(Instant::now());
+        for (key, metric) in self.custom.iter() {
+            report.mut_custom().insert(key.clone(), metric.clone());
         }
 
         report
     }
 
+    fn push_metrics(&mut self, push: api::PushMetrics) {
+        let now = Instant::now();
+        for (name, metric) in push.metrics.into_iter() {
+            if self.custom.push(&name, metric, now).is_none() {
+                warn!(self.ctx.log(), "Dropping custom metric {}", name);
+            }
+        }
+    }
+
     fn update(&mut self) {
-        let _ = self.memory.update();
-        self.cpu.update();
-        debug!(self.ctx.log(), "Memory: {}%", self.memory.procentage);
-        debug!(self.ctx.log(), "Cpu: {}%", self.cpu.avg);
+        self.sources.update();
+        if let Some(memory) = self.sources.get::<Memory>() {
+            debug!(self.ctx.log(), "Memory: {}%", memory.procentage);
+        }
+        if let Some((usage, limit)) = self.memory_usage() {
+            if let Some(interval) =
+                self.sampling.sample(Instant::now(), usage, limit)
+            {
+                self.reschedule(interval);
+            }
+        }
+
+        for group in self.groups.iter_mut() {
+            group.sources.update();
+        }
+
+        self.check_release();
+        self.check_trend();
+
+        if self.subscribers.is_empty() && self.page.is_none() {
+            return;
+        }
+        let report = self.create_report();
+
+        if let Some(page) = self.page.as_mut() {
+            page.publish(&shm::Metrics::from(&report));
+        }
+
+        if self.subscribers.is_empty() {
+            return;
+        }
+        // serialised once for all subscribers
+        let encoded = match Encoded::new(serids::METRIC_REPORT, &report) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                error!(self.ctx.log(), "Could not serialise report: {:?}", e);
+                return;
+            }
+        };
+        let mut subscribers = mem::replace(&mut self.subscribers, Vec::new());
+        for sub in subscribers.iter_mut() {
+            if let Some(report) = sub.offer(encoded.clone()) {
+                sub.path.tell(report, self);
+            }
+        }
+        self.subscribers = subscribers;
+    }
 
-        if let Some(net) = self.network.as_mut() {
-            net.update();
-            debug!(self.ctx.log(), "Network: {:?}", net);
+    fn publish_event(&self, event: &api::ProcessEvent) {
+        // events are rare and not subject to the credits
+        match Encoded::new(serids::PROCESS_EVENT, event) {
+            Ok(encoded) => {
+                for sub in self.subscribers.iter() {
+                    sub.path.tell(encoded.clone(), self);
+                }
+            }
+            Err(e) => {
+                error!(self.ctx.log(), "Could not serialise event: {:?}", e)
+            }
         }
+    }
 
-        if let Some(io) = self.io.as_mut() {
-            io.update();
-            debug!(self.ctx.log(), "IO: {:?}", io);
+    fn subscribe(&mut self, path: ActorPath, subscribe: &api::Subscribe) {
+        // subscribing again resets the credits
+        self.subscribers.retain(|sub| sub.path != path);
+        let sub = Subscription::new(path, subscribe);
+        match sub.credits {
+            Some(credits) => debug!(
+                self.ctx.log(),
+                "Adding subscriber {} with {} credits", sub.path, credits
+            ),
+            None => debug!(
+                self.ctx.log(),
+                "Adding unbounded subscriber {}", sub.path
+            ),
         }
+        self.subscribers.push(sub);
+    }
 
-        if self.subscribers.len() > 0 {
-            let report = self.create_report();
+    fn credit(&mut self, path: &ActorPath, credit: &api::Credit) {
+        let sub = match self.subscribers.iter_mut().find(|s| s.path == *path) {
+            Some(sub) => sub,
+            None => return,
+        };
+        if let Some(report) = sub.grant(credit.get_credits()) {
+            if sub.dropped > 0 {
+                debug!(
+                    self.ctx.log(),
+                    "Subscriber {} caught up, {} reports were dropped",
+                    path,
+                    sub.dropped
+                );
+                sub.dropped = 0;
+            }
+            path.tell(report, self);
+        }
+    }
+
+    // usage and limit of the process cgroup, none without memory metrics
+    fn memory_usage(&self) -> Option<(u64, u64)> {
+        let memory = self.sources.get::<Memory>()?;
+        Some((memory.usage, memory.limit))
+    }
+
+    fn check_release(&mut self) {
+        let endpoints = !self.releasers.is_empty();
+        let (usage, limit) = match self.memory_usage() {
+            Some(memory) => memory,
+            None => return,
+        };
+        let step = match self.release.as_mut() {
+            Some(release) => release.check(usage, limit, endpoints),
+            None => return,
+        };
+        match step {
+            Step::Idle => {}
+            Step::Request(request) => {
+                info!(
+                    self.ctx.log(),
+                    "Asking the Process to release {} bytes",
+                    request.get_target_bytes()
+                );
+                for releaser in self.releasers.iter() {
+                    releaser.tell(request.clone(), self);
+                }
+            }
+            Step::Escalated(escalation, Ok(())) => {
+                warn!(
+                    self.ctx.log(),
+                    "Process did not release memory, escalated with {:?}",
+                    escalation
+                );
+            }
+            Step::Escalated(escalation, Err(e)) => {
+                error!(
+                    self.ctx.log(),
+                    "Could not escalate with {:?}: {}", escalation, e
+                );
+            }
+        }
+    }
 
-            for sub in self.subscribers.iter() {
-                sub.tell(report.clone(), self);
+    fn check_trend(&mut self) {
+        let horizon = match self.oom_horizon {
+            Some(horizon) => {
+                horizon.as_secs() as f64
+                    + f64::from(horizon.subsec_millis()) / 1000.0
+            }
+            None => return,
+        };
+        let memory = match self.sources.get::<Memory>() {
+            Some(memory) => memory,
+            None => return,
+        };
+        let seconds = match memory.predicted_oom_seconds() {
+            Some(seconds) if seconds < horizon => seconds,
+            _ => {
+                self.oom_alerted = false;
+                return;
             }
+        };
+        if self.oom_alerted {
+            return;
+        }
+        self.oom_alerted = true;
+        let growth = memory.growth().unwrap_or(0.0);
+        warn!(
+            self.ctx.log(),
+            "Process is predicted to run out of memory in {:.0}s, \
+             growing {:.0} bytes/s",
+            seconds,
+            growth
+        );
+
+        let mut predicted = api::ProcessOomPredicted::new();
+        predicted.set_seconds(seconds);
+        predicted.set_growth(growth);
+        predicted.set_working_set(memory.working_set);
+        predicted.set_limit(memory.limit);
+        let mut event = api::ProcessEvent::new();
+        event.set_oom_predicted(predicted);
+        self.publish_event(&event);
+    }
+
+    fn schedule_collect(&mut self, interval: Duration) {
+        let timer = self.schedule_periodic(interval, interval, |self_c, _| {
+            self_c.actor_ref().tell(Box::new(Collect {}), self_c);
+        });
+        self.collect_timer = Some(timer);
+    }
+
+    fn reschedule(&mut self, interval: Duration) {
+        // not after the last collection
+        if self.collect_timer.is_none() {
+            return;
         }
+        debug!(
+            self.ctx.log(),
+            "Collecting every {}ms",
+            duration_ms(interval)
+        );
+        self.stop_collect();
+        self.schedule_collect(interval);
     }
 
     fn stop_collect(&mut self) {
@@ -126,13 +441,22 @@ impl Provide<ControlPort> for Monitor {
     fn handle(&mut self, event: ControlEvent) {
         match event {
             ControlEvent::Start => {
-                let timeout = Duration::from_millis(self.timeout_ms);
-                let timer =
-                    self.schedule_periodic(timeout, timeout, |self_c, _| {
-                        self_c.actor_ref().tell(Box::new(Collect {}), self_c);
-                    });
-
-                self.collect_timer = Some(timer);
+                // leaves out the sources that can't be read on this host
+                for name in self.sources.probe() {
+                    warn!(self.ctx.log(), "Source {} unavailable", name);
+                }
+                for group in self.groups.iter_mut() {
+                    for name in group.sources.probe() {
+                        warn!(
+                            self.ctx.log(),
+                            "Source {} of group {} unavailable",
+                            name,
+                            group.name
+                        );
+                    }
+                }
+                let interval = self.sampling.interval();
+                self.schedule_collect(interval);
             }
             ControlEvent::Stop => self.stop_collect(),
             ControlEvent::Kill => self.stop_collect(),
@@ -142,25 +466,100 @@ impl Provide<ControlPort> for Monitor {
 
 impl Actor for Monitor {
     fn receive_local(&mut self, _sender: ActorRef, msg: Box<Any>) {
-        if let Ok(_collect) = msg.downcast::<Collect>() {
-            self.update();
+        let msg = match msg.downcast::<Collect>() {
+            Ok(_collect) => return self.update(),
+            Err(msg) => msg,
+        };
+        let msg = match msg.downcast::<ReportRequest>() {
+            Ok(request) => {
+                if request.last {
+                    self.stop_collect();
+                }
+                self.update();
+                let _ = request.reply.send(self.create_report());
+                return;
+            }
+            Err(msg) => msg,
+        };
+        let msg = match msg.downcast::<api::Health>() {
+            Ok(health) => {
+                self.health = Some(*health);
+                return;
+            }
+            Err(msg) => msg,
+        };
+        if let Ok(event) = msg.downcast::<api::ProcessEvent>() {
+            debug!(self.ctx.log(), "Process event: {:?}", event);
+            self.publish_event(&event);
         }
     }
     fn receive_message(
         &mut self,
         sender: ActorPath,
-        _ser_id: u64,
+        ser_id: u64,
         buf: &mut Buf,
     ) {
-        let result: Result<api::Subscribe, SerError> =
-            ProtoSer::deserialise(buf);
-
-        if let Ok(res) = result {
-            debug!(self.ctx.log(), "Adding subscriber {}", sender);
-            self.subscribers.push(sender);
-        } else {
-            error!(self.ctx.log(), "Got unexpected message from {}", sender);
+        match ser_id {
+            serids::SUBSCRIBE => {
+                let result: Result<api::Subscribe, SerError> =
+                    ProtoSer::deserialise(buf);
+                if let Ok(subscribe) = result {
+                    return self.subscribe(sender, &subscribe);
+                }
+            }
+            serids::UNSUBSCRIBE => {
+                let result: Result<api::Unsubscribe, SerError> =
+                    ProtoSer::deserialise(buf);
+                if result.is_ok() {
+                    debug!(self.ctx.log(), "Removing subscriber {}", sender);
+                    self.subscribers.retain(|sub| sub.path != sender);
+                    return;
+                }
+            }
+            serids::CREDIT => {
+                let result: Result<api::Credit, SerError> =
+                    ProtoSer::deserialise(buf);
+                if let Ok(credit) = result {
+                    return self.credit(&sender, &credit);
+                }
+            }
+            serids::REGISTER_RELEASER => {
+                let result: Result<api::RegisterReleaser, SerError> =
+                    ProtoSer::deserialise(buf);
+                if result.is_ok() {
+                    debug!(self.ctx.log(), "Adding releaser {}", sender);
+                    self.releasers.push(sender);
+                    return;
+                }
+            }
+            serids::MEMORY_RELEASED => {
+                let result: Result<api::MemoryReleased, SerError> =
+                    ProtoSer::deserialise(buf);
+                if let Ok(released) = result {
+                    let known = self
+                        .release
+                        .as_mut()
+                        .map_or(false, |r| r.released(&released));
+                    if known {
+                        info!(
+                            self.ctx.log(),
+                            "Process released {} bytes",
+                            released.get_released_bytes()
+                        );
+                    }
+                    return;
+                }
+            }
+            serids::PUSH_METRICS => {
+                let result: Result<api::PushMetrics, SerError> =
+                    ProtoSer::deserialise(buf);
+                if let Ok(push) = result {
+                    return self.push_metrics(push);
+                }
+            }
+            _ => {}
         }
+        error!(self.ctx.log(), "Got unexpected message from {}", sender);
     }
 }
 
@@ -204,6 +603,9 @@ mod tests {
             if let Ok(report) = result {
                 self.reports_received += 1;
                 info!(self.ctx.log(), "MetricReport: {:?}", report);
+                let mut credit = api::Credit::new();
+                credit.set_credits(1);
+                self.target.tell(credit, self);
             } else {
                 error!(
                     self.ctx.log(),
@@ -222,6 +624,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn flow_control() {
+        use std::str::FromStr;
+        let path = ActorPath::from_str("tcp://127.0.0.1:1500/sub").unwrap();
+        let report =
+            Encoded::new(serids::METRIC_REPORT, &api::MetricReport::new())
+                .unwrap();
+
+        let mut unbounded = api::Subscribe::new();
+        unbounded.set_unbounded(true);
+        // credits don't matter then
+        unbounded.set_credits(1);
+        let mut unlimited = Subscription::new(path.clone(), &unbounded);
+        for _ in 0..10 {
+            assert!(unlimited.offer(report.clone()).is_some());
+        }
+        assert!(unlimited.grant(1).is_none());
+
+        let mut subscribe = api::Subscribe::new();
+        subscribe.set_credits(2);
+        let mut sub = Subscription::new(path, &subscribe);
+        assert!(sub.offer(report.clone()).is_some());
+        assert!(sub.offer(report.clone()).is_some());
+        // out of credits, only the latest report is kept
+        assert!(sub.offer(report.clone()).is_none());
+        assert!(sub.offer(report.clone()).is_none());
+        assert_eq!(sub.dropped, 1);
+
+        assert!(sub.grant(1).is_some());
+        assert_eq!(sub.credits, Some(0));
+        assert!(sub.grant(1).is_none());
+        assert!(sub.offer(report).is_some());
+    }
+
+    #[test]
+    fn no_credits() {
+        use std::str::FromStr;
+        let path = ActorPath::from_str("tcp://127.0.0.1:1500/sub").unwrap();
+        let report =
+            Encoded::new(serids::METRIC_REPORT, &api::MetricReport::new())
+                .unwrap();
+
+        // a subscriber that never grants credits gets the first report
+        let mut sub = Subscription::new(path, &api::Subscribe::new());
+        assert_eq!(sub.credits, Some(1));
+        assert!(sub.offer(report.clone()).is_some());
+        // and after that nothing, with only the latest report kept
+        for _ in 0..1000 {
+            assert!(sub.offer(report.clone()).is_none());
+        }
+        assert_eq!(sub.dropped, 999);
+        assert!(sub.pending.is_some());
+        assert_eq!(sub.credits, Some(0));
+    }
+
     #[test]
     fn subscription_test() {
         let ip_addr = SYSTEM_HOST
@@ -240,12 +697,18 @@ mod tests {
 
         let system = KompicsSystem::new(cfg);
 
+        let config = Config {
+            monitor_interval_ms: 250,
+            ..Config::default()
+        };
         let (monitor, _m) = system.create_and_register(move || {
             Monitor::new(
                 String::from("/sys/fs/cgroup/"),
                 "".to_string(),
                 None,
-                Some(250),
+                &config,
+                None,
+                None,
             )
         });
 
@@ -279,8 +742,9 @@ mod tests {
             .create_and_register(move || Subscriber::new(monitor_path));
 
         sub_system.start(&subscriber);
-        std::thread::sleep(std::time::Duration::from_millis(500));
+        std::thread::sleep(std::time::Duration::from_millis(1000));
         let sub = subscriber.definition().lock().unwrap();
-        assert!(sub.reports_received >= 1);
+        // more than the first one, which needs no credits
+        assert!(sub.reports_received > 1);
     }
 }
@@ -0,0 +1,300 @@
+use kompact::*;
+use std::fs;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::config::{Config, OomPolicy};
+use crate::health::procs;
+use crate::release;
+use stats::memory::Memory;
+
+// the OOM killer is disabled, so the Process stalls until the System acts
+const POLL_MS: u64 = 100;
+
+#[derive(Clone, Copy)]
+struct Poll {}
+
+/// Fds of the OOM notification and the record in the state dir
+#[derive(Clone, Copy)]
+pub struct OomFds {
+    /// eventfd registered for `memory.oom_control` of the process cgroup
+    pub events: RawFd,
+    pub record: Option<RawFd>,
+    pub process_memory: Option<RawFd>,
+    pub system_memory: Option<RawFd>,
+}
+
+/// Applies the OOM policy whenever the process cgroup is out of memory
+#[derive(ComponentDefinition)]
+pub struct OomGuard {
+    ctx: ComponentContext<OomGuard>,
+    policy: OomPolicy,
+    borrow_time: Duration,
+    fds: OomFds,
+    monitor: ActorRef,
+    memory: Memory,
+    procs_path: String,
+    // bytes lent to the process cgroup and when to take them back
+    borrowed: Option<(u64, Instant)>,
+    timer: Option<ScheduledTimer>,
+}
+
+impl OomGuard {
+    pub fn new(
+        config: &Config,
+        fds: OomFds,
+        monitor: ActorRef,
+        memory_path: String,
+        procs_path: String,
+    ) -> OomGuard {
+        OomGuard {
+            ctx: ComponentContext::new(),
+            policy: config.oom_policy,
+            borrow_time: Duration::from_millis(config.oom_borrow_ms),
+            fds,
+            monitor,
+            memory: Memory::new(memory_path),
+            procs_path,
+            borrowed: None,
+            timer: None,
+        }
+    }
+
+    fn poll(&mut self) {
+        let mut count: u64 = 0;
+        let n = unsafe {
+            libc::read(
+                self.fds.events,
+                &mut count as *mut u64 as *mut libc::c_void,
+                8,
+            )
+        };
+        if n == 8 && count > 0 {
+            self.out_of_memory();
+        }
+
+        if let Some((bytes, until)) = self.borrowed {
+            if Instant::now() >= until {
+                self.give_back(bytes);
+            }
+        }
+    }
+
+    fn out_of_memory(&mut self) {
+        let _ = self.memory.update();
+        warn!(
+            self.ctx.log(),
+            "Process is out of memory, applying {}",
+            self.policy.as_str()
+        );
+        let tasks = self.tasks();
+        let result = match self.policy {
+            OomPolicy::Kernel | OomPolicy::Notify => Ok(()),
+            OomPolicy::KillLargest => match largest(&tasks) {
+                Some(pid) => {
+                    signal(pid, libc::SIGKILL);
+                    Ok(())
+                }
+                None => Err("no tasks in the process cgroup".to_string()),
+            },
+            OomPolicy::Borrow => self.borrow(),
+            OomPolicy::Freeze => {
+                for &(pid, _) in &tasks {
+                    signal(pid, libc::SIGSTOP);
+                }
+                Ok(())
+            }
+        };
+        if let Err(ref e) = result {
+            error!(self.ctx.log(), "Could not apply OOM policy: {}", e);
+        }
+
+        let error = result.err().unwrap_or_default();
+        self.record(&tasks, &error);
+
+        let mut oom = api::ProcessOom::new();
+        oom.set_action(self.policy.as_str().to_string());
+        oom.set_usage(self.memory.usage);
+        oom.set_limit(self.memory.limit);
+        oom.set_error(error);
+        let mut event = api::ProcessEvent::new();
+        event.set_oom(oom);
+        self.monitor.tell(Box::new(event), self);
+    }
+
+    fn borrow(&mut self) -> Result<(), String> {
+        let (process, system) =
+            match (self.fds.process_memory, self.fds.system_memory) {
+                (Some(p), Some(s)) => (p, s),
+                _ => return Err(release::not_writable().to_string()),
+            };
+        let bytes = release::borrow(process, system, u64::max_value())
+            .map_err(|e| e.to_string())?;
+        let lent = self.borrowed.map_or(0, |b| b.0);
+        info!(self.ctx.log(), "Lent {} bytes to the Process", bytes);
+        self.borrowed = Some((lent + bytes, Instant::now() + self.borrow_time));
+        Ok(())
+    }
+
+    fn give_back(&mut self, bytes: u64) {
+        let (process, system) =
+            match (self.fds.process_memory, self.fds.system_memory) {
+                (Some(p), Some(s)) => (p, s),
+                _ => return,
+            };
+        match release::give_back(process, system, bytes) {
+            Ok(()) => {
+                info!(self.ctx.log(), "Took back {} bytes", bytes);
+                self.borrowed = None;
+            }
+            // retried with the next poll
+            Err(e) => debug!(self.ctx.log(), "Could not take back: {}", e),
+        }
+    }
+
+    // pids of the process cgroup with their RSS in bytes
+    fn tasks(&self) -> Vec<(i32, u64)> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        procs(&self.procs_path)
+            .into_iter()
+            .map(|pid| {
+                let rss = fs::read_to_string(format!("/proc/{}/statm", pid))
+                    .ok()
+                    .and_then(|s| {
+                        s.split_whitespace().nth(1).and_then(|r| r.parse().ok())
+                    })
+                    .unwrap_or(0u64);
+                (pid, rss * page_size)
+            })
+            .collect()
+    }
+
+    // appends a JSON line to the OOM record in the state dir
+    fn record(&self, tasks: &[(i32, u64)], error: &str) {
+        let fd = match self.fds.record {
+            Some(fd) => fd,
+            None => return,
+        };
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let line = record_line(
+            time,
+            self.policy,
+            (self.memory.usage, self.memory.limit),
+            tasks,
+            error,
+        );
+        let n = unsafe {
+            libc::write(fd, line.as_ptr() as *const libc::c_void, line.len())
+        };
+        if n < 0 {
+            warn!(self.ctx.log(), "Could not record the OOM");
+        }
+    }
+
+    fn stop_polling(&mut self) {
+        if let Some(timer) = self.timer.clone() {
+            self.cancel_timer(timer);
+            self.timer = None;
+        }
+    }
+}
+
+// the task with the largest RSS
+fn largest(tasks: &[(i32, u64)]) -> Option<i32> {
+    tasks.iter().max_by_key(|t| t.1).map(|t| t.0)
+}
+
+// a line of the OOM record, `memory` is the usage and limit
+fn record_line(
+    time: u64,
+    policy: OomPolicy,
+    memory: (u64, u64),
+    tasks: &[(i32, u64)],
+    error: &str,
+) -> String {
+    let tasks: Vec<_> = tasks
+        .iter()
+        .map(|&(pid, rss)| json!({"pid": pid, "rss": rss}))
+        .collect();
+    let record = json!({
+        "time": time,
+        "action": policy.as_str(),
+        "usage": memory.0,
+        "limit": memory.1,
+        "error": error,
+        "tasks": tasks,
+    });
+    let mut line = serde_json::to_string(&record).unwrap_or_default();
+    line.push('\n');
+    line
+}
+
+fn signal(pid: i32, signo: i32) {
+    unsafe {
+        libc::kill(pid, signo);
+    }
+}
+
+impl Provide<ControlPort> for OomGuard {
+    fn handle(&mut self, event: ControlEvent) {
+        match event {
+            ControlEvent::Start => {
+                let interval = Duration::from_millis(POLL_MS);
+                let timer =
+                    self.schedule_periodic(interval, interval, |self_c, _| {
+                        self_c.actor_ref().tell(Box::new(Poll {}), self_c);
+                    });
+                self.timer = Some(timer);
+            }
+            ControlEvent::Stop => self.stop_polling(),
+            ControlEvent::Kill => self.stop_polling(),
+        }
+    }
+}
+
+impl Actor for OomGuard {
+    fn receive_local(&mut self, _sender: ActorRef, msg: Box<Any>) {
+        if let Ok(_poll) = msg.downcast::<Poll>() {
+            self.poll();
+        }
+    }
+    fn receive_message(
+        &mut self,
+        sender: ActorPath,
+        _ser_id: u64,
+        _buf: &mut Buf,
+    ) {
+        error!(self.ctx.log(), "Got unexpected message from {}", sender);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn kill_largest() {
+        assert_eq!(largest(&[]), None);
+        assert_eq!(largest(&[(10, 100), (11, 300), (12, 200)]), Some(11));
+    }
+
+    #[test]
+    fn record() {
+        let tasks = [(10, 4096), (11, 8192)];
+        let error = "kill failed: \"denied\"\u{1b}[0m";
+        let line =
+            record_line(1, OomPolicy::KillLargest, (900, 1000), &tasks, error);
+        assert!(line.ends_with('\n'));
+        let record: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(record["time"].as_u64(), Some(1));
+        assert_eq!(record["action"].as_str(), Some("kill-largest"));
+        assert_eq!(record["usage"].as_u64(), Some(900));
+        assert_eq!(record["limit"].as_u64(), Some(1000));
+        assert_eq!(record["error"].as_str(), Some(error));
+        assert_eq!(record["tasks"][1]["pid"].as_u64(), Some(11));
+        assert_eq!(record["tasks"][1]["rss"].as_u64(), Some(8192));
+    }
+}
@@ -0,0 +1,213 @@
+//! The metric sources of a cgroup. `stats` doesn't know the api, so the
+//! part of the `MetricReport` each source fills in is defined here.
+
+use std::any::Any;
+use std::mem;
+use std::time::Duration;
+
+use stats::cpu::Cpu;
+use stats::io::Io;
+use stats::memory::Memory;
+use stats::network::Network;
+use stats::source::MetricSource;
+
+/// Names of the sources `Registry::new` creates
+pub const SOURCES: &[&str] = &["memory", "cpu", "network", "io"];
+
+pub(crate) trait Source: MetricSource {
+    /// Adds the metrics of the last update to `report`
+    fn report(&self, report: &mut api::MetricReport);
+    fn as_any(&self) -> &dyn Any;
+}
+
+impl Source for Memory {
+    fn report(&self, report: &mut api::MetricReport) {
+        let mut mem = api::Memory::new();
+        mem.set_usage(self.usage);
+        mem.set_limit(self.limit);
+        mem.set_working_set(self.working_set);
+        report.set_memory(mem);
+        if let Some(seconds) = self.predicted_oom_seconds() {
+            report.set_predicted_oom_seconds(seconds);
+        }
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Source for Cpu {
+    fn report(&self, report: &mut api::MetricReport) {
+        let mut cpu = api::Cpu::new();
+        cpu.set_total(self.total_usage);
+        cpu.set_system(self.system_usage);
+        cpu.set_percent(self.percent);
+        cpu.set_avg_1m(self.avg_1m);
+        cpu.set_avg_5m(self.avg_5m);
+        cpu.set_avg_15m(self.avg_15m);
+        cpu.set_p50(self.p50);
+        cpu.set_p95(self.p95);
+        cpu.set_max(self.max);
+        report.set_cpu(cpu);
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Source for Network {
+    fn report(&self, report: &mut api::MetricReport) {
+        let mut network = api::Network::new();
+        network.set_tx_bytes(self.tx_bytes);
+        network.set_tx_packets(self.tx_packets);
+        network.set_rx_bytes(self.rx_bytes);
+        network.set_rx_packets(self.rx_packets);
+        report.set_network(network);
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Source for Io {
+    fn report(&self, report: &mut api::MetricReport) {
+        let mut io = api::Io::new();
+        io.set_read(self.read);
+        io.set_write(self.write);
+        report.set_io(io);
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// What the sources collect the metrics of
+pub(crate) struct Target<'a> {
+    /// Mount point of the cgroup controllers
+    pub cgroups_path: &'a str,
+    pub cgroup: &'a str,
+    /// Network interface, none for cgroups that don't have their own
+    pub interface: Option<&'a str>,
+    pub cpu_window: Duration,
+}
+
+impl<'a> Target<'a> {
+    fn controller(&self, controller: &str) -> String {
+        format!("{}/{}/{}/", self.cgroups_path, controller, self.cgroup)
+    }
+}
+
+// the source `name` for `target`, none if it doesn't apply to the target
+fn source(name: &str, target: &Target) -> Option<Box<dyn Source>> {
+    let source: Box<dyn Source> = match name {
+        "memory" => Box::new(Memory::new(target.controller("memory"))),
+        "cpu" => Box::new(Cpu::with_window(
+            target.controller("cpu"),
+            target.cpu_window,
+        )),
+        "network" => Box::new(Network::new(target.interface?.to_string())),
+        "io" => Box::new(Io::new(target.controller("blkio"))),
+        _ => return None,
+    };
+    Some(source)
+}
+
+/// The enabled sources of a cgroup
+pub(crate) struct Registry {
+    sources: Vec<Box<dyn Source>>,
+}
+
+impl Registry {
+    /// Creates the sources `names` for `target`. Memory is always
+    /// collected, as releases, OOM predictions and adaptive intervals
+    /// depend on it.
+    pub fn new(names: &[String], target: &Target) -> Registry {
+        let others = names.iter().filter(|name| name.as_str() != "memory");
+        Registry {
+            sources: Some("memory")
+                .into_iter()
+                .chain(others.map(String::as_str))
+                .filter_map(|name| source(name, target))
+                .collect(),
+        }
+    }
+
+    /// Leaves out the sources that can't be read on this host and returns
+    /// their names
+    pub fn probe(&mut self) -> Vec<&'static str> {
+        let mut unavailable = Vec::new();
+        for mut source in mem::replace(&mut self.sources, Vec::new()) {
+            if source.probe() {
+                self.sources.push(source);
+            } else {
+                unavailable.push(source.name());
+            }
+        }
+        unavailable
+    }
+
+    pub fn update(&mut self) {
+        for source in self.sources.iter_mut() {
+            source.update();
+        }
+    }
+
+    pub fn report(&self, report: &mut api::MetricReport) {
+        for source in &self.sources {
+            source.report(report);
+        }
+    }
+
+    /// The source of type `T`, if it is enabled and available
+    pub fn get<T: Any>(&self) -> Option<&T> {
+        self.sources
+            .iter()
+            .filter_map(|source| source.as_any().downcast_ref::<T>())
+            .next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn registry() {
+        let target = Target {
+            cgroups_path: "/sys/fs/cgroup/enya-missing",
+            cgroup: "process",
+            interface: Some("lo"),
+            cpu_window: Duration::from_secs(60),
+        };
+        let mut registry = Registry::new(&names(&["cpu", "network"]), &target);
+        assert!(registry.get::<Memory>().is_some());
+        assert!(registry.get::<Cpu>().is_some());
+        assert!(registry.get::<Io>().is_none());
+        let mut report = api::MetricReport::new();
+        registry.report(&mut report);
+        assert!(report.has_memory() && report.has_cpu());
+        assert!(!report.has_io());
+
+        // only lo can be read
+        assert_eq!(registry.probe(), vec!["memory", "cpu"]);
+        assert!(registry.get::<Memory>().is_none());
+        registry.update();
+        let mut report = api::MetricReport::new();
+        registry.report(&mut report);
+        assert!(report.has_network());
+        assert!(!report.has_memory() && !report.has_cpu());
+
+        // no interface to collect from
+        let group = Target {
+            interface: None,
+            ..target
+        };
+        let registry = Registry::new(&names(&["network", "io"]), &group);
+        assert!(registry.get::<Network>().is_none());
+        assert!(registry.get::<Io>().is_some());
+    }
+}
@@ -0,0 +1,371 @@
+use crate::config::{Config, ReleaseEscalation};
+use crate::health;
+use std::cmp;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+pub(crate) const MEMORY_USAGE: &str = "memory.usage_in_bytes";
+pub(crate) const MEMORY_LIMIT: &str = "memory.limit_in_bytes";
+
+/// Outcome of a check of the process cgroup memory
+#[derive(Debug)]
+pub(crate) enum Step {
+    Idle,
+    /// Send the request to the endpoints of the Process
+    Request(api::ReleaseMemory),
+    /// The Process missed the deadline or can't be asked
+    Escalated(ReleaseEscalation, io::Result<()>),
+}
+
+struct Pending {
+    id: u64,
+    target_bytes: u64,
+    deadline: Instant,
+}
+
+/// Asks the Process to release memory once the process cgroup crosses the
+/// release threshold, and escalates if it doesn't in time
+pub(crate) struct Release {
+    threshold: f64,
+    target: f64,
+    deadline: Duration,
+    escalation: ReleaseEscalation,
+    // memory cgroup dirs of the process and system cgroups
+    process_memory: Option<RawFd>,
+    system_memory: Option<RawFd>,
+    procs_path: String,
+    pending: Option<Pending>,
+    next_id: u64,
+    // the usage needs some time to go down after a release
+    quiet_until: Option<Instant>,
+}
+
+impl Release {
+    pub fn new(
+        config: &Config,
+        process_memory: Option<RawFd>,
+        system_memory: Option<RawFd>,
+        procs_path: String,
+    ) -> Release {
+        Release {
+            threshold: config.release_threshold,
+            target: config.release_target,
+            deadline: config.release_deadline(),
+            escalation: config.release_escalation,
+            process_memory,
+            system_memory,
+            procs_path,
+            pending: None,
+            next_id: 1,
+            quiet_until: None,
+        }
+    }
+
+    /// Checks the latest `usage` and `limit` of the process cgroup.
+    /// `endpoints` tells whether the Process registered any endpoint.
+    pub fn check(&mut self, usage: u64, limit: u64, endpoints: bool) -> Step {
+        let now = Instant::now();
+        if let Some(pending) = self.pending.take() {
+            if now < pending.deadline {
+                self.pending = Some(pending);
+                return Step::Idle;
+            }
+            return self.escalate(pending.target_bytes, now);
+        }
+        if self.quiet_until.map_or(false, |t| now < t) {
+            return Step::Idle;
+        }
+        if limit == 0 || (usage as f64) < limit as f64 * self.threshold {
+            return Step::Idle;
+        }
+
+        let target_bytes = usage - (limit as f64 * self.target) as u64;
+        if !endpoints {
+            return self.escalate(target_bytes, now);
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending = Some(Pending {
+            id,
+            target_bytes,
+            deadline: now + self.deadline,
+        });
+
+        let mut request = api::ReleaseMemory::new();
+        request.set_id(id);
+        request.set_target_bytes(target_bytes);
+        let ms = self.deadline.as_secs() * 1000
+            + u64::from(self.deadline.subsec_millis());
+        request.set_deadline_ms(ms);
+        request.set_usage(usage);
+        request.set_limit(limit);
+        Step::Request(request)
+    }
+
+    /// Acknowledgement of the Process, false if no such request is pending
+    pub fn released(&mut self, released: &api::MemoryReleased) -> bool {
+        match self.pending {
+            Some(ref pending) if pending.id == released.get_id() => {
+                self.pending = None;
+                self.quiet_until = Some(Instant::now() + self.deadline);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn escalate(&mut self, bytes: u64, now: Instant) -> Step {
+        self.quiet_until = Some(now + self.deadline);
+        let result = match self.escalation {
+            ReleaseEscalation::None => Ok(()),
+            ReleaseEscalation::Raise => self.raise(bytes),
+            ReleaseEscalation::Reclaim => self.reclaim(bytes),
+            ReleaseEscalation::Signal(signo) => {
+                for pid in health::procs(&self.procs_path) {
+                    unsafe {
+                        libc::kill(pid, signo);
+                    }
+                }
+                Ok(())
+            }
+        };
+        Step::Escalated(self.escalation, result)
+    }
+
+    fn raise(&self, bytes: u64) -> io::Result<()> {
+        match (self.process_memory, self.system_memory) {
+            (Some(process), Some(system)) => {
+                borrow(process, system, bytes).map(|_| ())
+            }
+            _ => Err(not_writable()),
+        }
+    }
+
+    // lowering the limit below the usage makes the kernel reclaim memory
+    // of the cgroup, it gives up with EBUSY instead of invoking the OOM
+    // killer
+    fn reclaim(&self, bytes: u64) -> io::Result<()> {
+        let process = self.process_memory.ok_or_else(not_writable)?;
+        let limit = read_value(process, MEMORY_LIMIT)?;
+        let usage = read_value(process, MEMORY_USAGE)?;
+        let lowered =
+            write_value(process, MEMORY_LIMIT, usage - bytes.min(usage));
+        write_value(process, MEMORY_LIMIT, limit)?;
+        match lowered {
+            Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) => Ok(()),
+            other => other,
+        }
+    }
+}
+
+/// Moves up to `bytes` of the memory limit from the slack of the system
+/// cgroup to the process cgroup, keeping half of the slack for the System.
+/// Returns the number of bytes moved.
+pub(crate) fn borrow(
+    process: RawFd,
+    system: RawFd,
+    bytes: u64,
+) -> io::Result<u64> {
+    let system_limit = read_value(system, MEMORY_LIMIT)?;
+    let slack = system_limit.saturating_sub(read_value(system, MEMORY_USAGE)?);
+    let moved = cmp::min(bytes, slack / 2);
+    if moved == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "no slack left in the system cgroup",
+        ));
+    }
+    let process_limit = read_value(process, MEMORY_LIMIT)?;
+    // raised first, so that no bytes get lost if the second write fails
+    write_value(process, MEMORY_LIMIT, process_limit + moved)?;
+    if let Err(e) = write_value(system, MEMORY_LIMIT, system_limit - moved) {
+        let _ = write_value(process, MEMORY_LIMIT, process_limit);
+        return Err(e);
+    }
+    Ok(moved)
+}
+
+/// Moves `bytes` borrowed with `borrow` back to the system cgroup. Fails
+/// with EBUSY as long as the process cgroup can't do without them.
+pub(crate) fn give_back(
+    process: RawFd,
+    system: RawFd,
+    bytes: u64,
+) -> io::Result<()> {
+    let process_limit = read_value(process, MEMORY_LIMIT)?;
+    let system_limit = read_value(system, MEMORY_LIMIT)?;
+    write_value(process, MEMORY_LIMIT, process_limit.saturating_sub(bytes))?;
+    if let Err(e) = write_value(system, MEMORY_LIMIT, system_limit + bytes) {
+        // the Process keeps them until the next try
+        let _ = write_value(process, MEMORY_LIMIT, process_limit);
+        return Err(e);
+    }
+    Ok(())
+}
+
+pub(crate) fn not_writable() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "memory cgroups are not writable")
+}
+
+fn open_at(dirfd: RawFd, file: &str, flags: libc::c_int) -> io::Result<RawFd> {
+    let path = CString::new(file)?;
+    let fd =
+        unsafe { libc::openat(dirfd, path.as_ptr(), flags | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(fd)
+}
+
+pub(crate) fn read_value(dirfd: RawFd, file: &str) -> io::Result<u64> {
+    let fd = open_at(dirfd, file, libc::O_RDONLY)?;
+    let mut buf = [0u8; 32];
+    let n = unsafe {
+        libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+    };
+    let err = io::Error::last_os_error();
+    unsafe {
+        libc::close(fd);
+    }
+    if n < 0 {
+        return Err(err);
+    }
+    String::from_utf8_lossy(&buf[..n as usize])
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, file))
+}
+
+fn write_value(dirfd: RawFd, file: &str, value: u64) -> io::Result<()> {
+    // truncating is a no-op for cgroup files, like `echo >` does
+    let fd = open_at(dirfd, file, libc::O_WRONLY | libc::O_TRUNC)?;
+    let data = value.to_string();
+    let n = unsafe {
+        libc::write(fd, data.as_ptr() as *const libc::c_void, data.len())
+    };
+    let err = io::Error::last_os_error();
+    unsafe {
+        libc::close(fd);
+    }
+    if n < 0 {
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // a directory with the memory files of a cgroup
+    fn memory_dir(name: &str, limit: u64, usage: u64) -> (String, RawFd) {
+        let dir = std::env::temp_dir().join(format!(
+            "enya-release-{}-{}",
+            std::process::id(),
+            name
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(MEMORY_LIMIT), limit.to_string()).unwrap();
+        fs::write(dir.join(MEMORY_USAGE), usage.to_string()).unwrap();
+        let path = dir.to_str().unwrap().to_string();
+        let c_path = CString::new(path.clone()).unwrap();
+        let fd = unsafe {
+            libc::open(c_path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY)
+        };
+        assert!(fd >= 0);
+        (path, fd)
+    }
+
+    fn new_release(escalation: ReleaseEscalation, deadline_ms: u64) -> Release {
+        let config = Config {
+            release_threshold: 0.9,
+            release_target: 0.5,
+            release_deadline_ms: deadline_ms,
+            release_escalation: escalation,
+            ..Config::default()
+        };
+        Release::new(&config, None, None, String::new())
+    }
+
+    #[test]
+    fn request() {
+        let mut release = new_release(ReleaseEscalation::None, 10_000);
+        assert!(match release.check(80, 100, true) {
+            Step::Idle => true,
+            _ => false,
+        });
+
+        let request = match release.check(95, 100, true) {
+            Step::Request(request) => request,
+            step => panic!("unexpected {:?}", step),
+        };
+        assert_eq!(request.get_target_bytes(), 45);
+        // only one request at a time
+        assert!(match release.check(95, 100, true) {
+            Step::Idle => true,
+            _ => false,
+        });
+
+        let mut released = api::MemoryReleased::new();
+        released.set_id(request.get_id() + 1);
+        assert!(!release.released(&released));
+        released.set_id(request.get_id());
+        assert!(release.released(&released));
+    }
+
+    #[test]
+    fn escalation() {
+        let mut release = new_release(ReleaseEscalation::Raise, 1);
+        match release.check(95, 100, true) {
+            Step::Request(_) => {}
+            step => panic!("unexpected {:?}", step),
+        }
+        std::thread::sleep(Duration::from_millis(5));
+        match release.check(95, 100, true) {
+            Step::Escalated(ReleaseEscalation::Raise, Err(_)) => {}
+            step => panic!("unexpected {:?}", step),
+        }
+
+        // nobody to ask
+        let mut release = new_release(ReleaseEscalation::None, 10_000);
+        match release.check(95, 100, false) {
+            Step::Escalated(ReleaseEscalation::None, Ok(())) => {}
+            step => panic!("unexpected {:?}", step),
+        }
+    }
+
+    #[test]
+    fn borrow_and_give_back() {
+        let (process_dir, process) = memory_dir("process", 1000, 1000);
+        let (system_dir, system) = memory_dir("system", 1000, 200);
+        let limit = |fd| read_value(fd, MEMORY_LIMIT).unwrap();
+
+        // half of the 800 bytes of slack
+        assert_eq!(borrow(process, system, u64::max_value()).unwrap(), 400);
+        assert_eq!((limit(process), limit(system)), (1400, 600));
+        assert_eq!(borrow(process, system, 100).unwrap(), 100);
+        assert_eq!((limit(process), limit(system)), (1500, 500));
+
+        give_back(process, system, 500).unwrap();
+        assert_eq!((limit(process), limit(system)), (1000, 1000));
+
+        // nothing left to lend
+        let (full_dir, full) = memory_dir("full", 1000, 1000);
+        assert!(borrow(process, full, 100).is_err());
+        assert_eq!((limit(process), limit(full)), (1000, 1000));
+
+        for &(ref dir, fd) in &[
+            (process_dir, process),
+            (system_dir, system),
+            (full_dir, full),
+        ] {
+            unsafe {
+                libc::close(fd);
+            }
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
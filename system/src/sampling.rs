@@ -0,0 +1,139 @@
+use crate::config::Config;
+use std::time::{Duration, Instant};
+
+/// Picks the interval of the metric collections. Near the memory limit,
+/// or while the usage changes quickly, it drops to the minimum interval,
+/// and doubles back up to the configured interval once memory is stable.
+pub(crate) struct Sampling {
+    max: Duration,
+    min: Duration,
+    threshold: f64,
+    change: f64,
+    current: Duration,
+    // time and memory usage of the previous sample
+    last: Option<(Instant, u64)>,
+}
+
+impl Sampling {
+    pub fn new(config: &Config) -> Sampling {
+        let max = Duration::from_millis(config.monitor_interval_ms);
+        Sampling {
+            max,
+            min: config.monitor_min_interval().unwrap_or(max),
+            threshold: config.monitor_fast_threshold,
+            change: config.monitor_fast_change,
+            current: max,
+            last: None,
+        }
+    }
+
+    /// The current interval
+    pub fn interval(&self) -> Duration {
+        self.current
+    }
+
+    /// Adapts to the memory `usage` and `limit` at `at`. Returns the new
+    /// interval if it changed.
+    pub fn sample(
+        &mut self,
+        at: Instant,
+        usage: u64,
+        limit: u64,
+    ) -> Option<Duration> {
+        let last = self.last.replace((at, usage));
+        if self.min == self.max || limit == 0 {
+            return None;
+        }
+
+        let mut fast = usage as f64 >= limit as f64 * self.threshold;
+        if let Some((last_at, last_usage)) = last {
+            let elapsed = at.duration_since(last_at);
+            let secs = elapsed.as_secs() as f64
+                + f64::from(elapsed.subsec_millis()) / 1000.0;
+            let delta = (usage as f64 - last_usage as f64).abs();
+            // share of the limit per second
+            if secs > 0.0 && delta / limit as f64 / secs >= self.change {
+                fast = true;
+            }
+        }
+
+        let next = if fast {
+            self.min
+        } else {
+            (self.current * 2).min(self.max)
+        };
+        if next == self.current {
+            return None;
+        }
+        self.current = next;
+        Some(next)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMIT: u64 = 1000;
+
+    fn adaptive() -> Sampling {
+        let config = Config {
+            monitor_interval_ms: 8000,
+            monitor_min_interval_ms: 1000,
+            monitor_fast_threshold: 0.8,
+            monitor_fast_change: 0.01,
+            ..Config::default()
+        };
+        Sampling::new(&config)
+    }
+
+    #[test]
+    fn fixed() {
+        let mut sampling = Sampling::new(&Config::default());
+        let now = Instant::now();
+        assert_eq!(sampling.sample(now, 990, LIMIT), None);
+        assert_eq!(sampling.interval(), Duration::from_secs(2));
+    }
+
+    #[test]
+    fn threshold() {
+        let mut sampling = adaptive();
+        let start = Instant::now();
+        assert_eq!(sampling.sample(start, 500, LIMIT), None);
+        assert_eq!(sampling.interval(), Duration::from_secs(8));
+
+        let at = start + Duration::from_secs(8);
+        assert_eq!(
+            sampling.sample(at, 850, LIMIT),
+            Some(Duration::from_secs(1))
+        );
+
+        // backs off by doubling once it is stable again, the drop back
+        // to 500 is a quick change as well
+        let mut at = at;
+        let mut intervals = Vec::new();
+        for _ in 0..5 {
+            at += sampling.interval();
+            if let Some(interval) = sampling.sample(at, 500, LIMIT) {
+                intervals.push(interval.as_secs());
+            }
+        }
+        assert_eq!(intervals, vec![2, 4, 8]);
+    }
+
+    #[test]
+    fn change() {
+        let mut sampling = adaptive();
+        let start = Instant::now();
+        sampling.sample(start, 100, LIMIT);
+        // 1% of the limit over 8s is slow enough
+        let at = start + Duration::from_secs(8);
+        assert_eq!(sampling.sample(at, 110, LIMIT), None);
+        // 10% within 8s is not
+        let at = at + Duration::from_secs(8);
+        assert_eq!(
+            sampling.sample(at, 210, LIMIT),
+            Some(Duration::from_secs(1))
+        );
+    }
+}